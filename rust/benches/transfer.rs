@@ -0,0 +1,28 @@
+// Throughput of the per-block work done on every transfer
+
+use bbcpr::checksum::{create_checksum, ChecksumType};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+fn checksums(c: &mut Criterion) {
+    let data = vec![0x5au8; 1024 * 1024];
+    let mut group = c.benchmark_group("checksum");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+
+    for name in ["md5", "crc32", "adler32"] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut checksum = create_checksum(match name {
+                    "md5" => ChecksumType::MD5,
+                    "crc32" => ChecksumType::CRC32,
+                    _ => ChecksumType::Adler32,
+                });
+                checksum.update(&data);
+                checksum.name()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, checksums);
+criterion_main!(benches);
@@ -1,38 +1,99 @@
 // Authentication utilities for bbcpr
 
+use std::fmt;
 use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{compiler_fence, Ordering};
 use crate::error::{BbcprError, Result};
 
-/// Prompt for password input (hidden from terminal)
+/// A password that is wiped from memory when dropped and never printed.
+/// It can't be cloned; share it in an `Arc` so there is one copy to wipe.
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Secret(value)
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        wipe(&mut self.0);
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(***)")
+    }
+}
+
+/// Overwrite a string's bytes with zeros, then empty it
+pub fn wipe(value: &mut String) {
+    // SAFETY: all-zero bytes are valid UTF-8
+    let bytes = unsafe { value.as_mut_vec() };
+    for byte in bytes.iter_mut() {
+        // Volatile, so the compiler can't drop writes nobody reads
+        unsafe { std::ptr::write_volatile(byte, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+    bytes.clear();
+}
+
+/// Prompt for password input (hidden from terminal). The prompt goes to
+/// stderr, so it is seen even when stdout carries data.
 pub fn prompt_password(prompt: &str) -> Result<String> {
-    print!("{}", prompt);
-    io::stdout().flush().map_err(BbcprError::Io)?;
+    eprint!("{}", prompt);
+    io::stderr().flush().map_err(BbcprError::Io)?;
     
     // Try to use rpassword for hidden input
     #[cfg(feature = "rpassword")]
     {
-        rpassword::read_password().map_err(|e| BbcprError::Io(e))
+        rpassword::read_password().map_err(BbcprError::Io)
     }
     
     #[cfg(not(feature = "rpassword"))]
     {
         // Fallback to visible input with warning
         eprintln!("Warning: Password will be visible");
-        let mut input = String::new();
+        // Sized up front so growing it leaves no copies behind
+        let mut input = String::with_capacity(256);
         io::stdin().read_line(&mut input).map_err(BbcprError::Io)?;
-        Ok(input.trim().to_string())
+        let password = input.trim().to_string();
+        wipe(&mut input);
+        Ok(password)
     }
 }
 
-/// Get SSH password from various sources
+/// Environment variable holding the SSH password for unattended runs
+pub const PASSWORD_ENV: &str = "BBCPR_SSH_PASS";
+
+/// Read a password from the first line of `path`
+pub fn read_password_file(path: &Path) -> Result<String> {
+    let mut contents = std::fs::read_to_string(path)
+        .map_err(|e| BbcprError::Config(format!("Failed to read password file {:?}: {}", path, e)))?;
+    let password = contents.lines().next().unwrap_or_default().to_string();
+    wipe(&mut contents);
+    Ok(password)
+}
+
+/// Get SSH password from various sources: `--ssh-pass-file`, then
+/// `BBCPR_SSH_PASS`, then an interactive prompt with `--password`
 pub fn get_ssh_password(
     password_prompt: bool,
-    password_value: Option<String>,
+    password_file: Option<&Path>,
     host: &str,
     user: Option<&str>
 ) -> Result<Option<String>> {
-    if let Some(password) = password_value {
-        // Password provided via command line (not recommended for security)
+    if let Some(path) = password_file {
+        Ok(Some(read_password_file(path)?))
+    } else if let Some(password) = std::env::var_os(PASSWORD_ENV).and_then(|value| value.into_string().ok()) {
         Ok(Some(password))
     } else if password_prompt {
         // Interactive password prompt
@@ -63,9 +124,31 @@ mod tests {
     }
 
     #[test]
-    fn test_get_ssh_password_provided() {
-        let result = get_ssh_password(false, Some("test123".to_string()), "example.com", Some("user"));
-        assert!(result.is_ok());
+    fn test_get_ssh_password_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pass");
+        std::fs::write(&path, "test123\n").unwrap();
+
+        let result = get_ssh_password(false, Some(&path), "example.com", Some("user"));
         assert_eq!(result.unwrap(), Some("test123".to_string()));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_wipe_zeroes_buffer() {
+        let mut password = String::from("hunter2");
+        let (data, len) = (password.as_ptr(), password.len());
+        wipe(&mut password);
+
+        assert!(password.is_empty());
+        // The allocation is still owned by `password`
+        let bytes = unsafe { std::slice::from_raw_parts(data, len) };
+        assert!(bytes.iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn test_secret_is_not_printed() {
+        let secret = Secret::from("hunter2".to_string());
+        assert_eq!(secret.expose(), "hunter2");
+        assert!(!format!("{:?}", secret).contains("hunter2"));
+    }
+}
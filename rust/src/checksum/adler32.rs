@@ -4,12 +4,39 @@ pub struct Adler32Checksum {
     hasher: adler::Adler32,
 }
 
+const MOD_ADLER: u32 = 65521;
+
 impl Adler32Checksum {
     pub fn new() -> Self {
         Self {
             hasher: adler::Adler32::new(),
         }
     }
+
+    /// Current checksum, without consuming the hasher
+    pub fn value(&self) -> u32 {
+        self.hasher.checksum()
+    }
+
+    /// Slide a window of `window` bytes one byte forward: drop `outgoing`
+    /// from the front and append `incoming` at the back
+    pub fn roll(&mut self, outgoing: u8, incoming: u8, window: usize) {
+        let m = MOD_ADLER as u64;
+        let sum = self.hasher.checksum();
+        let (a, b) = ((sum & 0xffff) as u64, (sum >> 16) as u64);
+        let (outgoing, incoming) = (outgoing as u64, incoming as u64);
+
+        let a = (a + m - outgoing + incoming) % m;
+        let b = (b + m - (window as u64 % m) * outgoing % m + a + m - 1) % m;
+
+        self.hasher = adler::Adler32::from_checksum(((b << 16) | a) as u32);
+    }
+}
+
+impl Default for Adler32Checksum {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Checksum for Adler32Checksum {
@@ -18,10 +45,31 @@ impl Checksum for Adler32Checksum {
     }
 
     fn finalize(self) -> Vec<u8> {
-        self.hasher.finish().to_le_bytes().to_vec()
+        self.hasher.checksum().to_le_bytes().to_vec()
     }
 
     fn name(&self) -> &'static str {
         "Adler32"
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roll_matches_fresh_checksum() {
+        let data: Vec<u8> = (0..=255u8).cycle().take(4096).map(|b| b.wrapping_mul(31)).collect();
+        let window = 1000;
+
+        let mut rolling = Adler32Checksum::new();
+        rolling.update(&data[..window]);
+        for start in 1..data.len() - window {
+            rolling.roll(data[start - 1], data[start + window - 1], window);
+
+            let mut fresh = Adler32Checksum::new();
+            fresh.update(&data[start..start + window]);
+            assert_eq!(rolling.value(), fresh.value(), "window at {}", start);
+        }
+    }
+}
@@ -12,6 +12,12 @@ impl CRC32Checksum {
     }
 }
 
+impl Default for CRC32Checksum {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Checksum for CRC32Checksum {
     fn update(&mut self, data: &[u8]) {
         self.hasher.update(data);
@@ -1,26 +1,30 @@
 use crate::checksum::Checksum;
 
 pub struct MD5Checksum {
-    hasher: md5::Md5,
+    hasher: md5::Context,
 }
 
 impl MD5Checksum {
     pub fn new() -> Self {
         Self {
-            hasher: md5::Md5::new(),
+            hasher: md5::Context::new(),
         }
     }
 }
 
+impl Default for MD5Checksum {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Checksum for MD5Checksum {
     fn update(&mut self, data: &[u8]) {
-        use md5::Digest;
-        self.hasher.update(data);
+        self.hasher.consume(data);
     }
 
     fn finalize(self) -> Vec<u8> {
-        use md5::Digest;
-        self.hasher.finalize().to_vec()
+        self.hasher.compute().0.to_vec()
     }
 
     fn name(&self) -> &'static str {
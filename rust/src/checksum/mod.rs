@@ -1,7 +1,5 @@
 // Checksum algorithms implementation
 
-use anyhow::Result;
-
 pub trait Checksum: Send + Sync {
     fn update(&mut self, data: &[u8]);
     fn finalize(self) -> Vec<u8>;
@@ -7,7 +7,6 @@ use std::path::PathBuf;
 #[command(
     name = "bbcpr",
     about = "Secure and fast copy utility - Rust implementation",
-    version,
     author = "Andrew Mello <andrew@88plug.com>",
     long_about = "bbcpr (Berkeley Byte Copy Rust) is a modern Rust implementation of bbcp, providing \
                   high-performance parallel file transfers with support for SSH, checksums, advanced resume functionality, \
@@ -15,15 +14,20 @@ use std::path::PathBuf;
                   files into multiple parallel streams for maximum speed."
 )]
 pub struct Args {
-    /// Source file(s) or directory
-    #[arg(value_name = "SOURCE")]
+    /// Sources then the destination. A source of "-" reads stdin and "null:SIZE"
+    /// generates SIZE bytes; a destination of "-" writes stdout and "null:" discards the data
+    #[arg(value_name = "PATH")]
+    pub paths: Vec<String>,
+
+    /// Source file(s) or directory, split off `paths`
+    #[arg(skip)]
     pub source: Vec<String>,
 
-    /// Destination file or directory
-    #[arg(value_name = "DEST")]
+    /// Destination file or directory, the last of `paths`
+    #[arg(skip)]
     pub destination: String,
 
-    /// Append mode to restart a previously failed copy
+    /// Append mode to restart a previously failed copy, keeping transfer state in DIR
     #[arg(short = 'a', long = "append", value_name = "DIR")]
     pub append_dir: Option<PathBuf>,
 
@@ -55,7 +59,7 @@ pub struct Args {
     #[arg(short = 'E', long = "checksum", value_name = "ALGO")]
     pub checksum_algo: Option<String>,
 
-    /// Force copy by unlinking target first
+    /// Force copy, replacing an existing target
     #[arg(short = 'f', long = "force")]
     pub force: bool,
 
@@ -63,35 +67,67 @@ pub struct Args {
     #[arg(short = 'F', long = "no-space-check")]
     pub no_space_check: bool,
 
-    /// Print help information
-    #[arg(short = 'h', long = "help")]
-    pub help: bool,
-
     /// SSH identity file
     #[arg(short = 'i', long = "identity", value_name = "FILE")]
     pub identity_file: Option<PathBuf>,
 
-    /// Prompt for SSH password authentication
+    /// Prompt for SSH password authentication (logs in with the native backend)
     #[arg(long = "password")]
     pub ssh_password: bool,
 
-    /// SSH password (use --password for interactive prompt)
-    #[arg(long = "ssh-pass", value_name = "PASSWORD", hide = true)]
-    pub ssh_password_value: Option<String>,
+    /// Read the SSH password from the first line of FILE (or set BBCPR_SSH_PASS)
+    #[arg(long = "ssh-pass-file", value_name = "FILE")]
+    pub ssh_password_file: Option<PathBuf>,
+
+    /// SSH implementation: openssh (the system ssh client), native (libssh2, no ssh binary needed) or auto
+    #[arg(long = "ssh-backend", value_name = "BACKEND", default_value = "auto")]
+    pub ssh_backend: String,
+
+    /// Authentication methods the native backend tries, in order
+    #[arg(long = "ssh-auth", value_name = "METHODS", default_value = "agent,publickey,keyboard-interactive,password")]
+    pub ssh_auth: String,
+
+    /// Unknown SSH host keys: strict (refuse), accept-new (trust and record) or off (no checks)
+    #[arg(long = "host-key-policy", value_name = "POLICY", default_value = "accept-new")]
+    pub host_key_policy: String,
+
+    /// known_hosts file to check and update instead of ~/.ssh/known_hosts
+    #[arg(long = "known-hosts", value_name = "FILE")]
+    pub known_hosts: Option<PathBuf>,
+
+    /// ssh client config to read instead of ~/.ssh/config
+    #[arg(long = "ssh-config", value_name = "FILE")]
+    pub ssh_config: Option<PathBuf>,
+
+    /// Reach SSH hosts through these jump hosts, comma-separated [user@]host[:port]; overrides ProxyJump
+    #[arg(short = 'J', long = "jump", value_name = "HOSTS")]
+    pub jump: Option<String>,
 
-    /// File containing list of files to copy
+    /// File containing list of files to copy ("-" for stdin), one SOURCE or SOURCE DEST per line
     #[arg(short = 'I', long = "file-list", value_name = "FILE")]
     pub file_list: Option<PathBuf>,
 
-    /// Keep destination file on failure
+    /// File list entries are NUL-delimited (as written by find -print0)
+    #[arg(short = '0', long = "null", requires = "file_list")]
+    pub null_delimited: bool,
+
+    /// Keep the partial destination file on failure
     #[arg(short = 'k', long = "keep")]
     pub keep_partial: bool,
 
+    /// Suffix of the temporary `.<name>.<SUFFIX>` file written before the final rename
+    #[arg(long = "partial-suffix", value_name = "SUFFIX", default_value = "bbcpr-partial")]
+    pub partial_suffix: String,
+
+    /// Write directly to the destination name instead of a temporary file
+    #[arg(long = "inplace")]
+    pub inplace: bool,
+
     /// Log file for stderr
     #[arg(short = 'l', long = "log", value_name = "FILE")]
     pub log_file: Option<PathBuf>,
 
-    /// Target file mode
+    /// Octal mode for created files and directories: FMODE[%DMODE], e.g. 644 or 0640%0750
     #[arg(short = 'm', long = "mode", value_name = "MODE")]
     pub file_mode: Option<String>,
 
@@ -107,14 +143,38 @@ pub struct Args {
     #[arg(short = 'o', long = "ordered")]
     pub ordered: bool,
 
-    /// Omit existing files at target
-    #[arg(short = 'O', long = "omit-existing")]
+    /// Treat the source (i), destination (o) or both (io) as shell commands to read from or write to
+    #[arg(short = 'N', long = "pipe", value_name = "MODE")]
+    pub pipe: Option<String>,
+
+    /// Omit existing files at target (same as --sync existing)
+    #[arg(short = 'O', long = "omit-existing", conflicts_with = "sync")]
     pub omit_existing: bool,
 
+    /// Only copy files whose target is missing or out of date: existing, size-mtime, checksum or always
+    #[arg(long = "sync", value_name = "MODE")]
+    pub sync: Option<String>,
+
+    /// Update existing targets by sending only changed blocks (rolling-checksum delta)
+    #[arg(long = "delta")]
+    pub delta: bool,
+
+    /// Copy only the data extents of sparse files and leave their holes as holes
+    #[arg(long = "sparse")]
+    pub sparse: bool,
+
+    /// Also leave runs of zero blocks in the data as holes on the target
+    #[arg(long = "zero-holes")]
+    pub zero_holes: bool,
+
     /// Preserve source attributes
     #[arg(short = 'p', long = "preserve")]
     pub preserve: bool,
 
+    /// Preserve extended attributes (user.*, trusted.* when root) and POSIX ACLs
+    #[arg(short = 'X', long = "xattrs")]
+    pub xattrs: bool,
+
     /// Progress message interval (seconds)
     #[arg(short = 'P', long = "progress", value_name = "SEC")]
     pub progress_interval: Option<u32>,
@@ -123,6 +183,58 @@ pub struct Args {
     #[arg(short = 'r', long = "recursive")]
     pub recursive: bool,
 
+    /// Number of files copied at once in recursive transfers
+    #[arg(long = "concurrent-files", value_name = "N", default_value = "4")]
+    pub concurrent_files: usize,
+
+    /// Batch files up to this many bytes into shared streams in recursive transfers (0 disables)
+    #[arg(long = "batch-threshold", value_name = "BYTES", default_value = "65536")]
+    pub batch_threshold: u64,
+
+    /// Symbolic links in recursive transfers: copy (as links), follow or skip
+    #[arg(long = "symlinks", value_name = "MODE", default_value = "copy")]
+    pub symlinks: String,
+
+    /// Follow symbolic links (same as --symlinks follow)
+    #[arg(short = 'L', long = "dereference", conflicts_with = "symlinks")]
+    pub dereference: bool,
+
+    /// Recreate hard links on the target instead of copying the data again
+    #[arg(short = 'H', long = "hard-links")]
+    pub hard_links: bool,
+
+    /// FIFOs and device nodes in recursive transfers: skip or recreate (devices need root)
+    #[arg(long = "specials", value_name = "MODE", default_value = "skip")]
+    pub specials: String,
+
+    /// Copy entries matching PATTERN even if an exclude matches them (repeatable)
+    #[arg(long = "include", value_name = "PATTERN")]
+    pub include: Vec<String>,
+
+    /// Skip entries matching PATTERN; a trailing '/' matches directories only (repeatable)
+    #[arg(long = "exclude", value_name = "PATTERN")]
+    pub exclude: Vec<String>,
+
+    /// Read exclude patterns from FILE, one per line
+    #[arg(long = "exclude-from", value_name = "FILE")]
+    pub exclude_from: Vec<PathBuf>,
+
+    /// Honour ignore files with this name (e.g. .gitignore) in every directory
+    #[arg(long = "ignore-file", value_name = "NAME")]
+    pub ignore_file: Vec<String>,
+
+    /// Skip files smaller than SIZE (e.g. 4K, 10M)
+    #[arg(long = "min-size", value_name = "SIZE")]
+    pub min_size: Option<String>,
+
+    /// Skip files larger than SIZE (e.g. 100M, 2G)
+    #[arg(long = "max-size", value_name = "SIZE")]
+    pub max_size: Option<String>,
+
+    /// Only copy files modified within AGE (e.g. 12h, 7d) or after @EPOCH
+    #[arg(long = "newer-than", value_name = "AGE")]
+    pub newer_than: Option<String>,
+
     /// Time limit for copy (seconds)
     #[arg(short = 't', long = "time-limit", value_name = "SEC")]
     pub time_limit: Option<u32>,
@@ -147,6 +259,38 @@ pub struct Args {
     #[arg(short = 'Z', long = "port-range", value_name = "PORT1:PORT2")]
     pub port_range: Option<String>,
 
+    /// Remote-to-remote transfers: data flows directly between the hosts (direct), is relayed through this one (relay), or is tunnelled over SSH (tunnel)
+    #[arg(long = "third-party", value_name = "MODE", default_value = "direct")]
+    pub third_party: String,
+
+    /// Streams tunnelled over SSH share one session per host as channels, or get a session each
+    #[arg(long = "tunnel", value_name = "MODE", default_value = "channels")]
+    pub tunnel: String,
+
+    /// Name or path of bbcpr on remote hosts
+    #[arg(long = "remote-program", value_name = "PATH", default_value = "bbcpr")]
+    pub remote_program: String,
+
+    /// Run as a remote agent of a third-party transfer: send, receive, listen or query
+    #[arg(long = "agent", value_name = "ROLE", hide = true)]
+    pub agent: Option<String>,
+
+    /// Address of the listening agent a sending agent connects to
+    #[arg(long = "agent-peer", value_name = "HOST:PORT", hide = true, requires = "agent")]
+    pub agent_peer: Option<String>,
+
+    /// Part of the file a sending agent writes to stdout, for a tunnelled stream
+    #[arg(long = "agent-part", value_name = "INDEX/COUNT", hide = true, requires = "agent")]
+    pub agent_part: Option<String>,
+
+    /// Secret that opens every stream between a sending and a listening agent
+    #[arg(long = "agent-token", value_name = "TOKEN", hide = true, requires = "agent")]
+    pub agent_token: Option<String>,
+
+    /// Listen on the loopback address only, for streams tunnelled over SSH
+    #[arg(long = "agent-loopback", hide = true, requires = "agent")]
+    pub agent_loopback: bool,
+
     /// Use IPv4 only
     #[arg(short = '4', long = "ipv4")]
     pub ipv4_only: bool,
@@ -171,6 +315,10 @@ pub struct Args {
     #[arg(long = "keep-state")]
     pub keep_state: bool,
 
+    /// Keep transfer state next to the destination file instead of the state directory
+    #[arg(long = "sidecar-state")]
+    pub sidecar_state: bool,
+
     /// Print license and exit
     #[arg(long = "license")]
     pub license: bool,
@@ -178,24 +326,17 @@ pub struct Args {
     /// Print version and exit
     #[arg(short = '#', long = "version")]
     pub version: bool,
-
-    /// Resume interrupted transfers automatically
-    #[arg(short = 'R', long = "resume")]
-    pub resume: bool,
-
-    /// List pending transfers that can be resumed
-    #[arg(long = "list-transfers")]
-    pub list_transfers: bool,
-
-    /// Cancel a specific transfer by ID
-    #[arg(long = "cancel-transfer", value_name = "ID")]
-    pub cancel_transfer: Option<String>,
-
-    /// Clean up old transfer state files (days)
-    #[arg(long = "cleanup-transfers", value_name = "DAYS")]
-    pub cleanup_transfers: Option<u32>,
-
-    /// Keep transfer state files after completion
-    #[arg(long = "keep-state")]
-    pub keep_state: bool,
-}
\ No newline at end of file
+}
+
+impl Args {
+    /// Parse the command line; the last path is the destination. clap
+    /// can't give a variable number of sources before a required one.
+    pub fn from_command_line() -> Self {
+        let mut args = Self::parse();
+        if let Some(destination) = args.paths.pop() {
+            args.destination = destination;
+            args.source = std::mem::take(&mut args.paths);
+        }
+        args
+    }
+}
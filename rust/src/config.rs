@@ -0,0 +1,38 @@
+// Configuration file support
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Settings read from the bbcpr configuration file (TOML)
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Directory for transfer state files
+    pub state_dir: Option<PathBuf>,
+    /// Keep transfer state next to the destination file
+    pub sidecar_state: bool,
+}
+
+impl Config {
+    /// Load the file given with `-C/--config`, or `<config dir>/bbcpr/config.toml`
+    /// when it exists
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match Self::default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config file {:?}", path))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse config file {:?}", path))
+    }
+
+    fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("bbcpr").join("config.toml"))
+    }
+}
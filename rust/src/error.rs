@@ -29,6 +29,23 @@ pub enum BbcprError {
     #[error("Protocol error: {0}")]
     Protocol(String),
     
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+    
+    #[error("Not enough space on target {path}: {required} bytes needed, {available} bytes available (use -F to skip this check)")]
+    InsufficientSpace {
+        path: String,
+        required: u64,
+        available: u64,
+    },
+    
+    #[error("Transfer {transfer_id} is already running as pid {pid} on {hostname}")]
+    TransferLocked {
+        transfer_id: String,
+        pid: u32,
+        hostname: String,
+    },
+    
     #[error("Authentication failed")]
     AuthenticationFailed,
     
@@ -24,5 +24,8 @@ pub const DEFAULT_STREAMS: u32 = 4;
 /// Default buffer size (128KB)
 pub const DEFAULT_BUFFER_SIZE: usize = 128 * 1024;
 
+/// Largest buffer, and so data chunk, a transfer may use (64MB)
+pub const MAX_BUFFER_SIZE: usize = 64 * 1024 * 1024;
+
 /// Default window size (128KB)
 pub const DEFAULT_WINDOW_SIZE: usize = 128 * 1024;
\ No newline at end of file
@@ -14,21 +14,41 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::mpsc;
 use tracing::{info, Level};
 use tracing_subscriber::FmtSubscriber;
 
 mod cli;
-mod transfer;
+mod config;
 
+use bbcpr::auth::{self, Secret};
+use bbcpr::network::endpoint::{self, Endpoint};
+use bbcpr::network::ssh::{AuthMethod, HostKeyPolicy, SshBackend, SshOptions};
+use bbcpr::network::ssh_config::{JumpHost, SshConfig};
 use crate::cli::Args;
-use crate::transfer::engine::TransferEngine;
+use crate::config::Config;
+use bbcpr::transfer::TransferOptions;
+use bbcpr::transfer::engine::{TransferEngine, TransferMessage};
+use bbcpr::transfer::filelist::{self, FileListEntry, FileListTransfer};
+use bbcpr::transfer::filter::{self, Filter};
+use bbcpr::transfer::agent::{self, Agent, AgentRole};
+use bbcpr::transfer::mode::TargetMode;
+use bbcpr::transfer::pipe::{self, PipeMode, Sink, Source};
+use bbcpr::transfer::progress::ProgressReporter;
+use bbcpr::transfer::state;
+use bbcpr::transfer::sync::SyncPolicy;
+use bbcpr::transfer::remote::RemoteTransfer;
+use bbcpr::transfer::third_party::{RelayMode, ThirdPartyTransfer, TunnelMode};
+use bbcpr::transfer::tree::TreeTransfer;
+use bbcpr::transfer::walker::{SpecialPolicy, SymlinkPolicy};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Parse command line arguments
-    let args = Args::parse();
+    let args = Args::from_command_line();
 
     // Initialize logging
     let log_level = match args.verbose {
@@ -38,8 +58,10 @@ async fn main() -> Result<()> {
         _ => Level::TRACE,
     };
 
+    // Logs go to stderr; stdout may carry data (-) or an agent's reports
     let subscriber = FmtSubscriber::builder()
         .with_max_level(log_level)
+        .with_writer(std::io::stderr)
         .with_target(false)
         .finish();
     
@@ -59,6 +81,24 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
+    // Agents of a third-party transfer only move data
+    if let Some(ref role) = args.agent {
+        return run_agent(role.parse()?, &args).await;
+    }
+
+    // Resolve where transfer state is kept: -a DIR, then BBCPR_STATE_DIR,
+    // then the config file, then ~/.bbcpr/transfers
+    let config = Config::load(args.config_file.as_deref())?;
+    if let Some(dir) = args.append_dir.clone() {
+        state::set_state_directory(dir);
+    } else if std::env::var_os("BBCPR_STATE_DIR").is_none() {
+        if let Some(dir) = config.state_dir.clone() {
+            state::set_state_directory(dir);
+        }
+    }
+    let resume = args.resume || args.append_dir.is_some();
+    let sidecar_state = args.sidecar_state || config.sidecar_state;
+
     // Handle transfer management commands
     if args.list_transfers {
         let transfers = TransferEngine::list_pending_transfers().await?;
@@ -102,7 +142,7 @@ async fn main() -> Result<()> {
     info!("Starting bbcpr v{}", env!("CARGO_PKG_VERSION"));
 
     // Parse source and destination
-    if args.source.is_empty() {
+    if args.source.is_empty() && args.file_list.is_none() {
         anyhow::bail!("No source files specified");
     }
     
@@ -110,45 +150,529 @@ async fn main() -> Result<()> {
         anyhow::bail!("No destination specified");
     }
 
-    // Show configuration
-    println!("bbcpr v{}", env!("CARGO_PKG_VERSION"));
-    println!("Transfer configuration:");
-    println!("  Sources: {:?}", args.source);
-    println!("  Destination: {}", args.destination);
-    println!("  Streams: {}", args.streams);
-    println!("  Compress: {:?}", args.compress_level);
-    println!("  Verbose: {}", args.verbose);
+    // Reject bad patterns and sizes before anything is printed
+    let filter = build_filter(&args)?;
+    let target_mode = args.file_mode.as_deref()
+        .map(str::parse::<TargetMode>)
+        .transpose()?
+        .unwrap_or_default();
+    let symlinks = if args.dereference {
+        SymlinkPolicy::Follow
+    } else {
+        args.symlinks.parse::<SymlinkPolicy>()?
+    };
+    let specials = args.specials.parse::<SpecialPolicy>()?;
+    let sync_policy = if args.omit_existing {
+        Some(SyncPolicy::Existing)
+    } else {
+        args.sync.as_deref().map(str::parse::<SyncPolicy>).transpose()?
+    };
+    let buffer_size = args.buffer_size.as_deref()
+        .map(filter::parse_size)
+        .transpose()?
+        .map_or(bbcpr::DEFAULT_BUFFER_SIZE, |size| size as usize);
+    let window_size = args.window_size.as_deref()
+        .map(filter::parse_size)
+        .transpose()?
+        .map_or(bbcpr::DEFAULT_WINDOW_SIZE, |size| size as usize);
+    if buffer_size == 0 || window_size == 0 {
+        anyhow::bail!("Buffer and window sizes must be at least one byte");
+    }
+    if buffer_size > bbcpr::MAX_BUFFER_SIZE {
+        anyhow::bail!("Buffer size may be at most {} bytes", bbcpr::MAX_BUFFER_SIZE);
+    }
+
+    let options = TransferOptions {
+        streams: args.streams.max(1),
+        buffer_size,
+        window_size,
+        compress: args.compress_level,
+        checksum: args.error_check,
+        preserve: args.preserve,
+        force: args.force,
+        resume,
+        cleanup_on_success: !args.keep_state,
+        sidecar_state,
+        partial_suffix: (!args.inplace).then(|| args.partial_suffix.clone()),
+        keep_partial: args.keep_partial,
+        space_check: !args.no_space_check,
+        concurrent_files: args.concurrent_files,
+        batch_threshold: args.batch_threshold,
+        filter: filter.map(Arc::new),
+        sync: sync_policy,
+        delta: args.delta,
+        sparse: args.sparse,
+        zero_holes: args.zero_holes,
+        xattrs: args.xattrs,
+        target_mode,
+        symlinks,
+        hard_links: args.hard_links,
+        specials,
+    };
+
+    // Pipes, programs and null: stream one source into one target
+    let pipe_mode = args.pipe.as_deref()
+        .map(str::parse::<PipeMode>)
+        .transpose()?
+        .unwrap_or_default();
+    let sink = Sink::parse(&args.destination, pipe_mode)?;
+    let pipe_source = match args.source.as_slice() {
+        [source] => Some(Source::parse(source, pipe_mode)?).filter(|source| source.is_pipe() || sink.is_pipe()),
+        _ if pipe_mode.input || sink.is_pipe() => {
+            anyhow::bail!("Pipe transfers take exactly one source");
+        }
+        _ => None,
+    };
+    if pipe_source.is_some() && args.recursive {
+        anyhow::bail!("Pipe transfers can't be recursive");
+    }
+    if let Some(ref source) = pipe_source {
+        pipe::check_ends(source, &sink)?;
+    }
+
+    // Everything else is a local path, a path over SSH or a bbcpr:// URL
+    let endpoints = match pipe_source {
+        Some(_) => None,
+        None => {
+            let sources = args.source.iter()
+                .map(|source| Endpoint::parse(source))
+                .collect::<Result<Vec<_>, _>>()?;
+            Some((sources, Endpoint::parse(&args.destination)?))
+        }
+    };
+
+    // Entries of -I FILE are copied along with any sources on the command line
+    let file_list = match (&args.file_list, &endpoints) {
+        (Some(path), Some(_)) => Some(filelist::read_file_list(path, args.null_delimited)?),
+        (Some(_), None) => anyhow::bail!("A file list can't be combined with pipe transfers"),
+        (None, _) => None,
+    };
+
+    // How SSH hosts are reached; --password asks once, for the first of them
+    let first_ssh_host = endpoints.iter()
+        .flat_map(|(sources, destination)| sources.iter().chain(std::iter::once(destination)))
+        .find_map(|endpoint| match endpoint {
+            Endpoint::Ssh { user, host, .. } => Some((host.as_str(), user.as_deref())),
+            _ => None,
+        });
+    let password = match first_ssh_host {
+        Some((host, user)) => auth::get_ssh_password(args.ssh_password, args.ssh_password_file.as_deref(), host, user)?
+            .map(|password| Arc::new(Secret::from(password))),
+        None => None,
+    };
+    let ssh_options = SshOptions {
+        backend: args.ssh_backend.parse()?,
+        identity_file: args.identity_file.clone(),
+        password,
+        auth_methods: AuthMethod::parse_list(&args.ssh_auth)?,
+        host_key_policy: args.host_key_policy.parse()?,
+        known_hosts: args.known_hosts.clone(),
+        config: Arc::new(SshConfig::load(args.ssh_config.as_deref())?),
+        config_file: args.ssh_config.clone(),
+        jump_hosts: args.jump.as_deref().map(JumpHost::parse_list).transpose()?.unwrap_or_default(),
+    };
+
+    // Remote to remote, with this host only controlling the transfer
+    let relay_mode = args.third_party.parse::<RelayMode>()?;
+    let tunnel_mode = args.tunnel.parse::<TunnelMode>()?;
+    let third_party = match endpoints.as_ref().map(|(sources, destination)| (sources.as_slice(), destination)) {
+        Some(([source @ Endpoint::Ssh { .. }], destination @ Endpoint::Ssh { .. })) => Some({
+            if let Some(flag) = agent_conflict(&args) {
+                anyhow::bail!("{} is not supported with remote-to-remote transfers", flag);
+            }
+            ThirdPartyTransfer::new(source.clone(), destination.clone())?
+                .with_mode(relay_mode)
+                .with_tunnel(tunnel_mode)
+                .with_streams(args.streams as usize)
+                .with_ssh_options(ssh_options.clone())
+                .with_remote_program(args.remote_program.clone())
+                .with_port_range(args.port_range.clone())
+                .with_force(args.force)
+                .with_partial_suffix((!args.inplace).then(|| args.partial_suffix.clone()))
+                .with_space_check(!args.no_space_check)
+                .with_preserve(args.preserve)
+                .with_file_mode(target_mode.file)
+                .with_xattrs(args.xattrs)
+        }),
+        _ => None,
+    };
+    // Otherwise at most one side may be remote, with an agent started on it
+    // to send or receive a single file
+    let remote = match &endpoints {
+        Some((sources, destination)) if third_party.is_none() => {
+            if sources.iter().any(Endpoint::is_remote) || destination.is_remote() {
+                if let Some(flag) = agent_conflict(&args) {
+                    anyhow::bail!("{} is not supported when copying to or from a remote host", flag);
+                }
+            }
+            match (sources.as_slice(), destination.is_remote()) {
+                ([source], true) if !source.is_remote() => Some((source.clone(), destination.clone())),
+                ([source], false) if source.is_remote() => Some((source.clone(), destination.clone())),
+                (sources, false) if !sources.iter().any(Endpoint::is_remote) => None,
+                (sources, true) if !sources.iter().any(Endpoint::is_remote) => {
+                    anyhow::bail!("Only a single file can be copied to a remote host at a time");
+                }
+                _ => anyhow::bail!("Only one side of a transfer can be remote, unless both are reached over SSH"),
+            }
+        }
+        _ => None,
+    };
+    let remote_transfer = match &remote {
+        Some((source, destination)) => Some(
+            RemoteTransfer::new(source.clone(), destination.clone())?
+                .with_ssh_options(ssh_options.clone())
+                .with_remote_program(args.remote_program.clone())
+                .with_chunk_size(buffer_size)
+                .with_force(args.force)
+                .with_partial_suffix((!args.inplace).then(|| args.partial_suffix.clone()))
+                .with_space_check(!args.no_space_check)
+                .with_preserve(args.preserve)
+                .with_mode(target_mode.file)
+                .with_xattrs(args.xattrs),
+        ),
+        None => None,
+    };
+
+    // Show configuration on stderr, as stdout may be a pipe sink
+    eprintln!("bbcpr v{}", env!("CARGO_PKG_VERSION"));
+    eprintln!("Transfer configuration:");
+    eprintln!("  Sources: {:?}", args.source);
+    if let Some(ref file_list) = args.file_list {
+        eprintln!("  File list: {}{}", file_list.display(),
+                 if args.null_delimited { " (NUL-delimited)" } else { "" });
+    }
+    eprintln!("  Destination: {}", args.destination);
+    if third_party.is_some() {
+        match relay_mode {
+            RelayMode::Direct => eprintln!("  Third-party transfer: data flows directly between the hosts, or over SSH if the data port is blocked"),
+            RelayMode::Relay => eprintln!("  Third-party transfer: data relayed through this host"),
+            RelayMode::Tunnel => eprintln!("  Third-party transfer: data tunnelled over SSH ({})", tunnel_mode.as_str()),
+        }
+    } else if let Some((source, destination)) = &remote {
+        let host = source.host().or(destination.host()).unwrap_or_default();
+        eprintln!("  Remote: {} over SSH, through a bbcpr agent", host);
+    }
+    if first_ssh_host.is_some() {
+        match ssh_options.backend()? {
+            SshBackend::Native => eprintln!("  SSH backend: native (libssh2)"),
+            _ => eprintln!("  SSH backend: openssh"),
+        }
+        match ssh_options.host_key_policy {
+            HostKeyPolicy::Strict => eprintln!("  Host keys: only hosts already known are trusted"),
+            HostKeyPolicy::AcceptNew => {}
+            HostKeyPolicy::Off => eprintln!("  Host keys: not checked"),
+        }
+        if let Some(ref file) = ssh_options.known_hosts {
+            eprintln!("  Known hosts: {}", file.display());
+        }
+        if !ssh_options.jump_hosts.is_empty() {
+            let hops: Vec<String> = ssh_options.jump_hosts.iter().map(|jump| jump.to_string()).collect();
+            eprintln!("  Jump hosts: {}", hops.join(" -> "));
+        }
+    }
+    if let Some(ref source) = pipe_source {
+        eprintln!("  Streaming {} to {} with ordered delivery", source, sink);
+    } else if args.ordered {
+        eprintln!("  Ordered delivery: enabled");
+    }
+    eprintln!("  Streams: {}", args.streams);
+    eprintln!("  Compress: {:?}", args.compress_level);
+    eprintln!("  Verbose: {}", args.verbose);
     
     if args.error_check {
-        println!("  Checksum verification: enabled");
+        eprintln!("  Checksum verification: enabled");
     }
     
     if args.preserve {
-        println!("  Preserve attributes: enabled");
+        eprintln!("  Preserve attributes: enabled");
     }
 
-    if args.resume {
-        println!("  Resume mode: enabled");
+    if let Some(mode) = target_mode.file {
+        eprintln!("  File mode: {:04o}", mode);
+    }
+    if let Some(mode) = target_mode.directory {
+        eprintln!("  Directory mode: {:04o}", mode);
+    }
+
+    if args.xattrs {
+        eprintln!("  Preserve extended attributes and ACLs: enabled");
+    }
+
+    if args.recursive {
+        eprintln!("  Recursive: enabled ({} files at once)", args.concurrent_files);
+        if args.batch_threshold > 0 {
+            eprintln!("  Small-file batching: files up to {} bytes", args.batch_threshold);
+        }
+        match symlinks {
+            SymlinkPolicy::Copy => eprintln!("  Symlinks: copied as links"),
+            SymlinkPolicy::Follow => eprintln!("  Symlinks: followed"),
+            SymlinkPolicy::Skip => eprintln!("  Symlinks: skipped"),
+        }
+        if args.hard_links {
+            eprintln!("  Hard links: recreated");
+        }
+        if specials == SpecialPolicy::Recreate {
+            eprintln!("  Special files: recreated");
+        }
+    }
+
+    if options.filter.is_some() {
+        for pattern in &args.include {
+            eprintln!("  Include: {}", pattern);
+        }
+        for pattern in &args.exclude {
+            eprintln!("  Exclude: {}", pattern);
+        }
+        for file in &args.exclude_from {
+            eprintln!("  Exclude patterns from: {}", file.display());
+        }
+        for name in &args.ignore_file {
+            eprintln!("  Ignore files: {}", name);
+        }
+        if let Some(ref size) = args.min_size {
+            eprintln!("  Minimum file size: {}", size);
+        }
+        if let Some(ref size) = args.max_size {
+            eprintln!("  Maximum file size: {}", size);
+        }
+        if let Some(ref age) = args.newer_than {
+            eprintln!("  Modified within: {}", age);
+        }
+    }
+
+    match sync_policy {
+        Some(SyncPolicy::Existing) => eprintln!("  Omit existing targets: enabled"),
+        Some(SyncPolicy::SizeAndTime) => eprintln!("  Sync: skip targets with matching size and modification time"),
+        Some(SyncPolicy::Checksum) => eprintln!("  Sync: skip targets with matching checksum"),
+        Some(SyncPolicy::Always) => eprintln!("  Sync: always replace targets"),
+        None => {}
+    }
+
+    if args.delta {
+        eprintln!("  Delta transfer of changed targets: enabled");
+    }
+
+    if args.sparse {
+        eprintln!("  Sparse files: holes kept as holes");
+    }
+
+    if args.zero_holes {
+        eprintln!("  Zero blocks: written as holes");
+    }
+
+    if resume {
+        eprintln!("  Resume mode: enabled");
+    }
+
+    if args.inplace {
+        eprintln!("  Writing destination in place");
+    } else {
+        eprintln!("  Partial file suffix: .{}", args.partial_suffix);
+    }
+
+    if args.keep_partial {
+        eprintln!("  Keep partial file on failure: enabled");
+    }
+
+    if args.force {
+        eprintln!("  Replace existing target: enabled");
+    }
+
+    if args.no_space_check {
+        eprintln!("  Target space check: disabled");
+    }
+
+    if sidecar_state {
+        eprintln!("  Transfer state: next to destination");
+    } else {
+        eprintln!("  Transfer state: {}", state::get_state_directory()?.display());
     }
 
     if args.keep_state {
-        println!("  Keep transfer state: enabled");
+        eprintln!("  Keep transfer state: enabled");
     }
 
-    println!("\n🚀 Resume functionality implemented!");
-    println!("\nNew features available:");
-    println!("✅ Automatic transfer resumption with -R/--resume");
-    println!("✅ Transfer state persistence");
-    println!("✅ List pending transfers with --list-transfers");
-    println!("✅ Cancel transfers with --cancel-transfer <ID>");
-    println!("✅ Cleanup old state files with --cleanup-transfers <DAYS>");
-    println!("✅ Keep state files with --keep-state");
-    
-    println!("\nExample usage:");
-    println!("  bbcpr -R largefile.zip user@server:/backup/    # Resume if interrupted");
-    println!("  bbcpr --list-transfers                         # Show pending transfers");
-    println!("  bbcpr --cancel-transfer abc123                 # Cancel specific transfer");
-    println!("  bbcpr --cleanup-transfers 7                    # Clean up week-old states");
+    // Progress is reported as data moves; the bar is drawn with -P
+    let total_bytes = endpoints.iter()
+        .flat_map(|(sources, _)| sources.iter().filter_map(|source| match source {
+            Endpoint::Local(path) => Some(path.clone()),
+            _ => None,
+        }))
+        .chain(file_list.iter().flatten().map(|entry| entry.source.clone()))
+        .map(|path| std::fs::metadata(path).ok().filter(|m| m.is_file()).map(|m| m.len()))
+        .sum::<Option<u64>>()
+        .unwrap_or(0);
+    let (progress_tx, progress_rx) = mpsc::channel(100);
+    let reporter = tokio::spawn(report_progress(progress_rx, total_bytes, args.progress_interval));
+
+    let result = match (&pipe_source, &third_party, &remote_transfer, &endpoints) {
+        (Some(source), _, _, _) => {
+            pipe::transfer(source, &sink, &options, &progress_tx)
+                .await
+                .map(drop)
+        }
+        (None, Some(transfer), _, _) => transfer.run(&progress_tx).await.map(drop),
+        (None, None, Some(transfer), _) => transfer.run(&progress_tx).await.map(drop),
+        (None, None, None, Some((sources, destination))) => async {
+            copy_paths(sources, destination, args.recursive, &options, &progress_tx).await?;
+            match file_list {
+                Some(entries) => copy_file_list(entries, destination, args.recursive, &options, &progress_tx).await,
+                None => Ok(()),
+            }
+        }.await,
+        (None, None, None, None) => unreachable!("endpoints are parsed for every transfer that is not a pipe"),
+    };
+
+    let outcome = match &result {
+        Ok(()) => TransferMessage::Complete,
+        Err(e) => TransferMessage::Error(format!("{:#}", e)),
+    };
+    let _ = progress_tx.send(outcome).await;
+    drop(progress_tx);
+    reporter.await.context("Progress reporter panicked")?;
+
+    result
+}
+
+/// Copy each local source to the local destination: into it when it is an
+/// existing directory, otherwise onto it. Directories are only copied with -r.
+async fn copy_paths(
+    sources: &[Endpoint],
+    destination: &Endpoint,
+    recursive: bool,
+    options: &TransferOptions,
+    progress_tx: &mpsc::Sender<TransferMessage>,
+) -> Result<()> {
+    let destination = PathBuf::from(destination.path());
+    if sources.len() > 1 && !destination.is_dir() {
+        anyhow::bail!("Destination {:?} must be an existing directory when copying several sources", destination);
+    }
+
+    for source in sources {
+        let source = PathBuf::from(source.path());
+        let metadata = tokio::fs::metadata(&source).await
+            .with_context(|| format!("Failed to read {:?}", source))?;
+
+        if metadata.is_dir() {
+            if !recursive {
+                anyhow::bail!("{:?} is a directory (use -r to copy it)", source);
+            }
+            TreeTransfer::new(source, destination.clone(), options.clone())
+                .copy(progress_tx)
+                .await?;
+        } else {
+            let target = match source.file_name() {
+                Some(name) if destination.is_dir() => destination.join(name),
+                _ => destination.clone(),
+            };
+            TransferEngine::new(source, target, options.clone())
+                .copy(progress_tx)
+                .await?;
+        }
+    }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Copy the entries of a -I file list. Every entry is tried before
+/// failing, so one bad path doesn't hold up the rest.
+async fn copy_file_list(
+    entries: Vec<FileListEntry>,
+    destination: &Endpoint,
+    recursive: bool,
+    options: &TransferOptions,
+    progress_tx: &mpsc::Sender<TransferMessage>,
+) -> Result<()> {
+    let total = entries.len();
+    let report = FileListTransfer::new(entries, PathBuf::from(destination.path()), options.clone())
+        .with_recursive(recursive)
+        .copy(progress_tx)
+        .await?;
+
+    if !report.failures.is_empty() {
+        anyhow::bail!("{} of {} file list entries failed to copy", report.failures.len(), total);
+    }
+    Ok(())
+}
+
+/// Draw a progress bar every `interval` seconds with -P; otherwise just
+/// drain the messages so senders never wait
+async fn report_progress(mut rx: mpsc::Receiver<TransferMessage>, total_bytes: u64, interval: Option<u32>) {
+    match interval {
+        Some(seconds) => ProgressReporter::new(total_bytes, seconds.into()).run(rx).await,
+        None => while rx.recv().await.is_some() {},
+    }
+}
+
+/// Build the recursive transfer filter from the command line, if any filter option was given
+fn build_filter(args: &Args) -> Result<Option<Filter>> {
+    if args.include.is_empty() && args.exclude.is_empty() && args.exclude_from.is_empty()
+        && args.ignore_file.is_empty() && args.min_size.is_none() && args.max_size.is_none()
+        && args.newer_than.is_none()
+    {
+        return Ok(None);
+    }
+
+    let mut filter = Filter::new()
+        .min_size(args.min_size.as_deref().map(filter::parse_size).transpose()?)
+        .max_size(args.max_size.as_deref().map(filter::parse_size).transpose()?)
+        .newer_than(args.newer_than.as_deref().map(filter::parse_newer_than).transpose()?);
+
+    for pattern in &args.include {
+        filter = filter.include(pattern)?;
+    }
+    for pattern in &args.exclude {
+        filter = filter.exclude(pattern)?;
+    }
+    for file in &args.exclude_from {
+        filter = filter.exclude_from(file)?;
+    }
+    for name in &args.ignore_file {
+        filter = filter.ignore_file(name);
+    }
+
+    Ok(Some(filter))
+}
+
+/// The first option given that a transfer through remote agents can't honour
+fn agent_conflict(args: &Args) -> Option<&'static str> {
+    [
+        (args.file_list.is_some(), "-I/--file-list"),
+        (args.recursive, "-r/--recursive"),
+        (args.omit_existing, "-O/--omit-existing"),
+        (args.sync.is_some(), "--sync"),
+        (args.delta, "--delta"),
+        (args.sparse || args.zero_holes, "--sparse"),
+        (args.keep_partial, "-k/--keep"),
+        (args.resume || args.append_dir.is_some(), "-R/--resume"),
+    ]
+    .into_iter()
+    .find_map(|(given, flag)| given.then_some(flag))
+}
+
+/// Run one end of a third-party transfer, as started by the controller
+async fn run_agent(role: AgentRole, args: &Args) -> Result<()> {
+    let path = match role {
+        AgentRole::Send => args.source.first()
+            .ok_or_else(|| anyhow::anyhow!("Sending agent needs a source"))?,
+        AgentRole::Receive | AgentRole::Listen | AgentRole::Query => &args.destination,
+    };
+    let partial_suffix = (!args.inplace).then(|| args.partial_suffix.clone());
+    let target_mode = args.file_mode.as_deref().map(str::parse::<TargetMode>).transpose()?;
+
+    // The controller passes remote paths through unexpanded
+    Agent::new(role, endpoint::expand_home(path))
+        .with_peer(args.agent_peer.clone())
+        .with_streams(args.streams as usize)
+        .with_part(args.agent_part.as_deref().map(agent::parse_part).transpose()?)
+        .with_port_range(args.port_range.as_deref().map(agent::parse_port_range).transpose()?)
+        .with_token(args.agent_token.clone())
+        .with_loopback(args.agent_loopback)
+        .with_force(args.force)
+        .with_partial_suffix(partial_suffix)
+        .with_preserve(args.preserve)
+        .with_mode(target_mode.and_then(|mode| mode.file))
+        .with_xattrs(args.xattrs)
+        .run()
+        .await
+}
@@ -0,0 +1,342 @@
+// Source and destination specs
+//
+// A command-line path is one of:
+//
+//   path, ./a:b, C:\dir          local
+//   [user@]host:path             SSH, as in scp
+//   [user@]host:port:path        SSH on another port, as in bbcp
+//   [user@][v6addr]:path         SSH to an IPv6 address
+//   ssh://[user@]host[:port]/path
+//   bbcpr://host[:port]/path     a listening bbcpr over plain TCP
+//
+// Remote paths are kept as written, `~` included; the remote end expands
+// `~` itself (see `expand_home`).
+
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::error::{BbcprError, Result};
+
+/// Port bbcpr listens on when a bbcpr:// URL gives none
+pub const DEFAULT_TCP_PORT: u16 = 5031;
+
+/// Where a transfer reads from or writes to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    Local(PathBuf),
+    /// A path reached over SSH; without a port the SSH default applies
+    Ssh {
+        user: Option<String>,
+        host: String,
+        port: Option<u16>,
+        path: String,
+    },
+    /// A path served by bbcpr listening on `host:port`
+    Tcp {
+        host: String,
+        port: u16,
+        path: String,
+    },
+}
+
+impl Endpoint {
+    pub fn parse(spec: &str) -> Result<Self> {
+        if spec.is_empty() {
+            return Err(BbcprError::Config("Empty source or destination".to_string()));
+        }
+        if let Some(rest) = spec.strip_prefix("ssh://") {
+            return parse_url(spec, rest, false);
+        }
+        if let Some(rest) = spec.strip_prefix("bbcpr://") {
+            return parse_url(spec, rest, true);
+        }
+        if is_local(spec) {
+            return Ok(Endpoint::Local(PathBuf::from(spec)));
+        }
+
+        let (user, rest) = split_user(spec);
+        let (host, rest) = split_host(spec, rest)?;
+        let Some(rest) = rest.strip_prefix(':') else {
+            return Err(invalid(spec, "expected ':' after the host"));
+        };
+
+        // host:port:path, with a bare number before the second colon
+        let (port, path) = match rest.split_once(':') {
+            Some((port, path)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => {
+                (Some(parse_port(spec, port)?), path)
+            }
+            _ => (None, rest),
+        };
+
+        Ok(Endpoint::Ssh {
+            user,
+            host,
+            port,
+            path: remote_path(path),
+        })
+    }
+
+    pub fn is_remote(&self) -> bool {
+        !matches!(self, Endpoint::Local(_))
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        match self {
+            Endpoint::Local(_) => None,
+            Endpoint::Ssh { host, .. } | Endpoint::Tcp { host, .. } => Some(host),
+        }
+    }
+
+    /// The path as the side holding it sees it
+    pub fn path(&self) -> String {
+        match self {
+            Endpoint::Local(path) => path.to_string_lossy().into_owned(),
+            Endpoint::Ssh { path, .. } | Endpoint::Tcp { path, .. } => path.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Local(path) => write!(f, "{}", path.display()),
+            Endpoint::Ssh { user, host, port, path } => {
+                if let Some(user) = user {
+                    write!(f, "{}@", user)?;
+                }
+                write!(f, "{}:", bracketed(host))?;
+                if let Some(port) = port {
+                    write!(f, "{}:", port)?;
+                }
+                write!(f, "{}", path)
+            }
+            Endpoint::Tcp { host, port, path } => {
+                let path = path.strip_prefix('/').unwrap_or(path);
+                write!(f, "bbcpr://{}:{}/{}", bracketed(host), port, path)
+            }
+        }
+    }
+}
+
+/// Expand a leading `~` or `~user` against this host's home directories.
+/// Called on the remote end, where the path actually lives.
+pub fn expand_home(path: &str) -> PathBuf {
+    let Some(rest) = path.strip_prefix('~') else {
+        return PathBuf::from(path);
+    };
+    let (name, tail) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash + 1..]),
+        None => (rest, ""),
+    };
+
+    let home = if name.is_empty() {
+        dirs::home_dir()
+    } else {
+        user_home(name)
+    };
+    match home {
+        Some(home) if tail.is_empty() => home,
+        Some(home) => home.join(tail),
+        None => PathBuf::from(path),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn user_home(name: &str) -> Option<PathBuf> {
+    nix::unistd::User::from_name(name).ok().flatten().map(|user| user.dir)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn user_home(_name: &str) -> Option<PathBuf> {
+    None
+}
+
+/// Specs that never name a host: no colon, a slash before the first colon,
+/// or a drive letter such as C:\ or C:/
+fn is_local(spec: &str) -> bool {
+    let Some(colon) = spec.find(':') else {
+        return true;
+    };
+    if spec.starts_with('[') {
+        return false;
+    }
+
+    let before = &spec[..colon];
+    let bytes = spec.as_bytes();
+    let drive = colon == 1
+        && bytes[0].is_ascii_alphabetic()
+        && matches!(bytes.get(2), Some(b'\\') | Some(b'/'));
+    drive || before.contains('/') || before.contains('\\')
+}
+
+/// Take `user@` off the front; the user ends at the first '@' that comes
+/// before any ':', '[' or '/'
+fn split_user(spec: &str) -> (Option<String>, &str) {
+    let login_end = spec.find([':', '[', '/']).unwrap_or(spec.len());
+    match spec[..login_end].find('@') {
+        Some(at) => (Some(spec[..at].to_string()), &spec[at + 1..]),
+        None => (None, spec),
+    }
+}
+
+/// Take the host off the front, unwrapping an [IPv6] address
+fn split_host<'a>(spec: &str, rest: &'a str) -> Result<(String, &'a str)> {
+    let (host, rest) = match rest.strip_prefix('[') {
+        Some(inner) => {
+            let end = inner.find(']').ok_or_else(|| invalid(spec, "unclosed '['"))?;
+            (&inner[..end], &inner[end + 1..])
+        }
+        None => {
+            let end = rest.find([':', '/']).unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        }
+    };
+    if host.is_empty() {
+        return Err(invalid(spec, "missing host"));
+    }
+    Ok((host.to_string(), rest))
+}
+
+/// `ssh://` and `bbcpr://` URLs; the path is everything from the first '/'
+fn parse_url(spec: &str, rest: &str, tcp: bool) -> Result<Endpoint> {
+    let (user, rest) = split_user(rest);
+    let (host, rest) = split_host(spec, rest)?;
+    let (port, path) = match rest.strip_prefix(':') {
+        Some(rest) => {
+            let end = rest.find('/').unwrap_or(rest.len());
+            (Some(parse_port(spec, &rest[..end])?), &rest[end..])
+        }
+        None => (None, rest),
+    };
+    if !path.is_empty() && !path.starts_with('/') {
+        return Err(invalid(spec, "expected '/' before the path"));
+    }
+    // As in scp URLs, /~/dir is relative to the remote home
+    let path = match path.strip_prefix("/~") {
+        Some(home) if home.is_empty() || home.starts_with('/') => format!("~{}", home),
+        _ => path.to_string(),
+    };
+
+    if tcp {
+        if user.is_some() {
+            return Err(invalid(spec, "bbcpr:// takes no user; use ssh:// to log in"));
+        }
+        return Ok(Endpoint::Tcp {
+            host,
+            port: port.unwrap_or(DEFAULT_TCP_PORT),
+            path: remote_path(&path),
+        });
+    }
+    Ok(Endpoint::Ssh {
+        user,
+        host,
+        port,
+        path: remote_path(&path),
+    })
+}
+
+fn parse_port(spec: &str, port: &str) -> Result<u16> {
+    match port.parse::<u16>() {
+        Ok(port) if port != 0 => Ok(port),
+        _ => Err(invalid(spec, &format!("invalid port {:?}", port))),
+    }
+}
+
+/// An empty remote path is the login directory, as in scp
+fn remote_path(path: &str) -> String {
+    if path.is_empty() {
+        ".".to_string()
+    } else {
+        path.to_string()
+    }
+}
+
+fn bracketed(host: &str) -> String {
+    if host.contains(':') {
+        format!("[{}]", host)
+    } else {
+        host.to_string()
+    }
+}
+
+fn invalid(spec: &str, reason: &str) -> BbcprError {
+    BbcprError::Config(format!("Invalid path {:?}: {}", spec, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ssh(user: Option<&str>, host: &str, port: Option<u16>, path: &str) -> Endpoint {
+        Endpoint::Ssh {
+            user: user.map(str::to_string),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_local() {
+        for spec in ["file", "/data/file", "./a:b", "dir/x:y", "C:\\data\\f", "d:/data", "-"] {
+            assert_eq!(Endpoint::parse(spec).unwrap(), Endpoint::Local(PathBuf::from(spec)), "{}", spec);
+        }
+        assert!(Endpoint::parse("").is_err());
+    }
+
+    #[test]
+    fn test_parse_ssh() {
+        assert_eq!(Endpoint::parse("alice@node1:/data/f").unwrap(), ssh(Some("alice"), "node1", None, "/data/f"));
+        assert_eq!(Endpoint::parse("node2:g").unwrap(), ssh(None, "node2", None, "g"));
+        assert_eq!(Endpoint::parse("node2:").unwrap(), ssh(None, "node2", None, "."));
+        assert_eq!(Endpoint::parse("c:file").unwrap(), ssh(None, "c", None, "file"));
+        assert_eq!(Endpoint::parse("bob@node3:2222:~/f").unwrap(), ssh(Some("bob"), "node3", Some(2222), "~/f"));
+        assert_eq!(Endpoint::parse("node3:x:y").unwrap(), ssh(None, "node3", None, "x:y"));
+        assert!(Endpoint::parse("node3:99999:/f").is_err());
+    }
+
+    #[test]
+    fn test_parse_ipv6() {
+        assert_eq!(Endpoint::parse("[::1]:/tmp/f").unwrap(), ssh(None, "::1", None, "/tmp/f"));
+        assert_eq!(
+            Endpoint::parse("carol@[fe80::1%eth0]:2200:f").unwrap(),
+            ssh(Some("carol"), "fe80::1%eth0", Some(2200), "f"),
+        );
+        assert!(Endpoint::parse("[::1/tmp").is_err());
+        assert!(Endpoint::parse("[::1]/tmp").is_err());
+    }
+
+    #[test]
+    fn test_parse_urls() {
+        assert_eq!(Endpoint::parse("ssh://dave@host:2022/srv/f").unwrap(), ssh(Some("dave"), "host", Some(2022), "/srv/f"));
+        assert_eq!(Endpoint::parse("ssh://host/~/f").unwrap(), ssh(None, "host", None, "~/f"));
+        assert_eq!(Endpoint::parse("bbcpr://[2001:db8::2]:6000/x").unwrap(), Endpoint::Tcp {
+            host: "2001:db8::2".to_string(),
+            port: 6000,
+            path: "/x".to_string(),
+        });
+        assert_eq!(Endpoint::parse("bbcpr://host").unwrap(), Endpoint::Tcp {
+            host: "host".to_string(),
+            port: DEFAULT_TCP_PORT,
+            path: ".".to_string(),
+        });
+        assert!(Endpoint::parse("bbcpr://user@host/x").is_err());
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        for spec in ["alice@node1:/data/f", "[::1]:2222:~/f", "bbcpr://[::1]:6000/x", "local/file"] {
+            assert_eq!(Endpoint::parse(spec).unwrap().to_string(), spec);
+        }
+    }
+
+    #[test]
+    fn test_expand_home() {
+        assert_eq!(expand_home("/abs/~x"), PathBuf::from("/abs/~x"));
+        if let Some(home) = dirs::home_dir() {
+            assert_eq!(expand_home("~"), home);
+            assert_eq!(expand_home("~/f"), home.join("f"));
+        }
+        assert_eq!(expand_home("~no-such-user-here/f"), PathBuf::from("~no-such-user-here/f"));
+    }
+}
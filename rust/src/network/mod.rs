@@ -1,15 +1,50 @@
 // Network communication layer
 
+pub mod endpoint;
 pub mod ssh;
+pub mod ssh_config;
+pub mod ssh_native;
 pub mod tcp;
 pub mod protocol;
 
 use async_trait::async_trait;
 
+use crate::error::Result;
+use crate::network::ssh::{RemoteChild, RemoteSocket};
+
 #[async_trait]
 pub trait Connection: Send + Sync {
     async fn connect(&mut self) -> crate::error::Result<()>;
     async fn send(&mut self, data: &[u8]) -> crate::error::Result<usize>;
     async fn receive(&mut self, buf: &mut [u8]) -> crate::error::Result<usize>;
     async fn close(&mut self) -> crate::error::Result<()>;
-}
\ No newline at end of file
+}
+
+/// A connection that can run commands on the remote host
+#[async_trait]
+pub trait RemoteShell: Connection {
+    /// The host's real name or address, with ssh config aliases resolved,
+    /// as another host would connect to it
+    fn host(&self) -> &str;
+    async fn spawn(&self, program: &str, args: &[String]) -> Result<RemoteChild>;
+    /// Connect to `port` on the remote host itself, through the SSH
+    /// connection, for data ports a firewall keeps us from reaching
+    async fn forward(&self, port: u16) -> Result<RemoteSocket>;
+}
+
+#[async_trait]
+impl<C: Connection + ?Sized> Connection for Box<C> {
+    async fn connect(&mut self) -> Result<()> {
+        (**self).connect().await
+    }
+    async fn send(&mut self, data: &[u8]) -> Result<usize> {
+        (**self).send(data).await
+    }
+    async fn receive(&mut self, buf: &mut [u8]) -> Result<usize> {
+        (**self).receive(buf).await
+    }
+    async fn close(&mut self) -> Result<()> {
+        (**self).close().await
+    }
+}
+
@@ -1,7 +1,14 @@
 // bbcp protocol implementation
 
-use crate::error::Result;
+use crate::error::{BbcprError, Result};
+use crate::network::Connection;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest message payload a reader accepts: a full data chunk plus room
+/// for record headers. Anything longer is refused before it is allocated.
+pub const MAX_FRAME_SIZE: usize = crate::MAX_BUFFER_SIZE + 64 * 1024;
 
 #[derive(Debug, Clone)]
 pub struct ProtocolMessage {
@@ -9,7 +16,7 @@ pub struct ProtocolMessage {
     pub data: Bytes,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MessageType {
     Handshake = 0x01,
     FileInfo = 0x02,
@@ -17,6 +24,16 @@ pub enum MessageType {
     Checksum = 0x04,
     Complete = 0x05,
     Error = 0x06,
+    SpaceQuery = 0x07,
+    SpaceReply = 0x08,
+    Hole = 0x0A,
+    Progress = 0x0B,
+    Part = 0x0C,
+    InfoQuery = 0x0D,
+    /// Block signatures of the receiver's existing file, for a delta transfer
+    Signature = 0x0E,
+    /// One instruction for rebuilding a range of the file by delta
+    DeltaOp = 0x0F,
 }
 
 impl ProtocolMessage {
@@ -51,6 +68,14 @@ impl ProtocolMessage {
             0x04 => MessageType::Checksum,
             0x05 => MessageType::Complete,
             0x06 => MessageType::Error,
+            0x07 => MessageType::SpaceQuery,
+            0x08 => MessageType::SpaceReply,
+            0x0A => MessageType::Hole,
+            0x0B => MessageType::Progress,
+            0x0C => MessageType::Part,
+            0x0D => MessageType::InfoQuery,
+            0x0E => MessageType::Signature,
+            0x0F => MessageType::DeltaOp,
             _ => return Err(crate::error::BbcprError::Protocol(format!("Unknown message type: {}", msg_type))),
         };
         
@@ -61,4 +86,180 @@ impl ProtocolMessage {
             data: payload,
         })
     }
-}
\ No newline at end of file
+}
+
+/// Kind of entry described by a `FileInfo` record
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+/// Payload of a `FileInfo` message, describing the entry whose data (if
+/// any) follows in `DataChunk` messages
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileInfo {
+    /// Path relative to the transfer root, using `/` separators
+    pub path: String,
+    pub kind: EntryKind,
+    pub size: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: i64,
+    pub mtime_nsec: u32,
+    pub atime: i64,
+    pub atime_nsec: u32,
+    /// Extended attributes and ACLs, when the sender was asked for them
+    pub xattrs: Option<Vec<(String, Vec<u8>)>>,
+}
+
+impl FileInfo {
+    pub fn to_message(&self) -> Result<ProtocolMessage> {
+        let data = bincode::serialize(self)
+            .map_err(|e| BbcprError::Protocol(format!("Failed to encode file info: {}", e)))?;
+        Ok(ProtocolMessage::new(MessageType::FileInfo, Bytes::from(data)))
+    }
+
+    pub fn from_message(message: &ProtocolMessage) -> Result<Self> {
+        bincode::deserialize(&message.data)
+            .map_err(|e| BbcprError::Protocol(format!("Invalid file info: {}", e)))
+    }
+}
+
+/// A `Hole` record: the next `len` bytes of the current entry are zeros
+/// and carry no data
+pub fn hole_message(len: u64) -> ProtocolMessage {
+    ProtocolMessage::new(MessageType::Hole, Bytes::copy_from_slice(&len.to_be_bytes()))
+}
+
+/// Length of the hole described by a `Hole` record
+pub fn hole_length(message: &ProtocolMessage) -> Result<u64> {
+    let bytes: [u8; 8] = message.data.as_ref().try_into()
+        .map_err(|_| BbcprError::Protocol(format!("Invalid hole record of {} bytes", message.data.len())))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// A `Progress` record: a receiver reporting how much it has written
+pub fn progress_message(bytes: u64, total: u64) -> ProtocolMessage {
+    let mut data = BytesMut::with_capacity(16);
+    data.put_u64(bytes);
+    data.put_u64(total);
+    ProtocolMessage::new(MessageType::Progress, data.freeze())
+}
+
+/// Bytes written and total size carried by a `Progress` record
+pub fn progress_values(message: &ProtocolMessage) -> Result<(u64, u64)> {
+    if message.data.len() != 16 {
+        return Err(BbcprError::Protocol(format!("Invalid progress record of {} bytes", message.data.len())));
+    }
+    let mut data = message.data.clone();
+    Ok((data.get_u64(), data.get_u64()))
+}
+
+/// A `Part` record: the stream carries `len` bytes of the file from
+/// `offset`, as one of several parallel streams
+pub fn part_message(offset: u64, len: u64) -> ProtocolMessage {
+    let mut data = BytesMut::with_capacity(16);
+    data.put_u64(offset);
+    data.put_u64(len);
+    ProtocolMessage::new(MessageType::Part, data.freeze())
+}
+
+/// Offset and length carried by a `Part` record
+pub fn part_values(message: &ProtocolMessage) -> Result<(u64, u64)> {
+    if message.data.len() != 16 {
+        return Err(BbcprError::Protocol(format!("Invalid part record of {} bytes", message.data.len())));
+    }
+    let mut data = message.data.clone();
+    Ok((data.get_u64(), data.get_u64()))
+}
+
+/// Write a whole message to the connection
+pub async fn send_message<C: Connection + ?Sized>(connection: &mut C, message: &ProtocolMessage) -> Result<()> {
+    let encoded = message.encode();
+    let mut written = 0;
+    while written < encoded.len() {
+        let n = connection.send(&encoded[written..]).await?;
+        if n == 0 {
+            return Err(BbcprError::Network("Connection closed while sending".to_string()));
+        }
+        written += n;
+    }
+    Ok(())
+}
+
+/// Read exactly one message from the connection
+pub async fn receive_message<C: Connection + ?Sized>(connection: &mut C) -> Result<ProtocolMessage> {
+    let mut header = [0u8; 8];
+    receive_exact(connection, &mut header).await?;
+
+    let data_len = frame_length(&header)?;
+    let mut frame = BytesMut::zeroed(8 + data_len);
+    frame[..8].copy_from_slice(&header);
+    receive_exact(connection, &mut frame[8..]).await?;
+
+    ProtocolMessage::decode(frame.freeze())
+}
+
+/// Payload length from a message header, checked against `MAX_FRAME_SIZE`
+fn frame_length(header: &[u8; 8]) -> Result<usize> {
+    let data_len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    if data_len > MAX_FRAME_SIZE {
+        return Err(BbcprError::Protocol(format!(
+            "Message of {} bytes exceeds the {} byte limit", data_len, MAX_FRAME_SIZE
+        )));
+    }
+    Ok(data_len)
+}
+
+async fn receive_exact<C: Connection + ?Sized>(connection: &mut C, buf: &mut [u8]) -> Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = connection.receive(&mut buf[filled..]).await?;
+        if n == 0 {
+            return Err(BbcprError::Network("Connection closed while receiving".to_string()));
+        }
+        filled += n;
+    }
+    Ok(())
+}
+
+/// Write a whole message to an async writer
+pub async fn write_message<W: AsyncWrite + Unpin + ?Sized>(writer: &mut W, message: &ProtocolMessage) -> Result<()> {
+    writer.write_all(&message.encode()).await?;
+    Ok(())
+}
+
+/// Read one message from an async reader, or `None` at a clean end of stream
+pub async fn read_message<R: AsyncRead + Unpin + ?Sized>(reader: &mut R) -> Result<Option<ProtocolMessage>> {
+    let mut header = [0u8; 8];
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(BbcprError::Io(e)),
+    }
+
+    let data_len = frame_length(&header)?;
+    let mut frame = BytesMut::zeroed(8 + data_len);
+    frame[..8].copy_from_slice(&header);
+    reader.read_exact(&mut frame[8..]).await?;
+
+    ProtocolMessage::decode(frame.freeze()).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_oversized_frame_is_refused() {
+        let mut frame = BytesMut::new();
+        frame.put_u32(MessageType::DataChunk as u32);
+        frame.put_u32(u32::MAX);
+        let mut reader = &frame[..];
+
+        let err = read_message(&mut reader).await.unwrap_err();
+        assert!(matches!(err, BbcprError::Protocol(_)), "{:?}", err);
+    }
+}
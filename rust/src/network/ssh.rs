@@ -1,37 +1,432 @@
 use async_trait::async_trait;
-use openssh::{Session, SessionBuilder, Stdio};
-use std::path::Path;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tracing::{debug, info};
+use openssh::{ForwardType, KnownHosts, Session, SessionBuilder, Stdio};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::task::JoinHandle;
+use tracing::info;
 
+use crate::auth::Secret;
 use crate::error::{BbcprError, Result};
-use crate::network::Connection;
+use crate::network::ssh_config::{JumpHost, SshConfig};
+use crate::network::ssh_native::{self, Hop, NativeSshConnection};
+use crate::network::{Connection, RemoteShell};
+
+/// Which SSH implementation connects to remote hosts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SshBackend {
+    /// The system ssh client when there is one, libssh2 otherwise
+    #[default]
+    Auto,
+    /// The system ssh client, sharing one ControlMaster connection
+    Openssh,
+    /// libssh2 in-process; needs no ssh binary
+    Native,
+}
+
+impl SshBackend {
+    /// Settle `Auto` by looking for an ssh client on PATH
+    pub fn resolve(self) -> SshBackend {
+        match self {
+            SshBackend::Auto if ssh_client_installed() => SshBackend::Openssh,
+            SshBackend::Auto => SshBackend::Native,
+            backend => backend,
+        }
+    }
+}
+
+impl FromStr for SshBackend {
+    type Err = BbcprError;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "auto" => Ok(SshBackend::Auto),
+            "openssh" => Ok(SshBackend::Openssh),
+            "native" | "libssh2" => Ok(SshBackend::Native),
+            other => Err(BbcprError::Config(format!(
+                "Invalid SSH backend {:?}: expected auto, openssh or native", other
+            ))),
+        }
+    }
+}
+
+fn ssh_client_installed() -> bool {
+    std::env::var_os("PATH")
+        .map(|path| std::env::split_paths(&path).any(|dir| dir.join("ssh").is_file()))
+        .unwrap_or(false)
+}
+
+/// An authentication method the native backend may try
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMethod {
+    /// Keys held by ssh-agent
+    Agent,
+    /// The identity file, or the default keys in ~/.ssh
+    PublicKey,
+    /// Server prompts answered with the password, as PAM setups ask
+    KeyboardInteractive,
+    Password,
+}
+
+impl AuthMethod {
+    /// Every method, in the order they are tried by default
+    pub const ALL: [AuthMethod; 4] = [
+        AuthMethod::Agent,
+        AuthMethod::PublicKey,
+        AuthMethod::KeyboardInteractive,
+        AuthMethod::Password,
+    ];
+
+    /// The server's name for the method, as listed by `auth_methods`
+    pub fn server_name(self) -> &'static str {
+        match self {
+            AuthMethod::Agent | AuthMethod::PublicKey => "publickey",
+            AuthMethod::KeyboardInteractive => "keyboard-interactive",
+            AuthMethod::Password => "password",
+        }
+    }
+
+    /// Parse a comma-separated list such as "agent,publickey"
+    pub fn parse_list(list: &str) -> Result<Vec<AuthMethod>> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+}
+
+impl FromStr for AuthMethod {
+    type Err = BbcprError;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "agent" => Ok(AuthMethod::Agent),
+            "publickey" | "key" => Ok(AuthMethod::PublicKey),
+            "keyboard-interactive" => Ok(AuthMethod::KeyboardInteractive),
+            "password" => Ok(AuthMethod::Password),
+            other => Err(BbcprError::Config(format!(
+                "Invalid SSH auth method {:?}: expected agent, publickey, keyboard-interactive or password", other
+            ))),
+        }
+    }
+}
+
+/// What to do with a host key that isn't in known_hosts yet
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HostKeyPolicy {
+    /// Refuse unknown hosts, as StrictHostKeyChecking=yes
+    Strict,
+    /// Trust and record unknown hosts; refuse changed keys
+    #[default]
+    AcceptNew,
+    /// Check nothing and record nothing, for throwaway test hosts
+    Off,
+}
+
+impl HostKeyPolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HostKeyPolicy::Strict => "strict",
+            HostKeyPolicy::AcceptNew => "accept-new",
+            HostKeyPolicy::Off => "off",
+        }
+    }
+}
+
+impl FromStr for HostKeyPolicy {
+    type Err = BbcprError;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "strict" | "yes" => Ok(HostKeyPolicy::Strict),
+            "accept-new" => Ok(HostKeyPolicy::AcceptNew),
+            "off" | "no" => Ok(HostKeyPolicy::Off),
+            other => Err(BbcprError::Config(format!(
+                "Invalid host key policy {:?}: expected strict, accept-new or off", other
+            ))),
+        }
+    }
+}
+
+/// How to reach remote hosts over SSH
+#[derive(Clone)]
+pub struct SshOptions {
+    pub backend: SshBackend,
+    pub identity_file: Option<PathBuf>,
+    /// Only the native backend can log in with a password
+    pub password: Option<Arc<Secret>>,
+    /// Methods the native backend tries, in order
+    pub auth_methods: Vec<AuthMethod>,
+    pub host_key_policy: HostKeyPolicy,
+    /// known_hosts file to check and update instead of ~/.ssh/known_hosts
+    pub known_hosts: Option<PathBuf>,
+    /// Host settings from the ssh client config
+    pub config: Arc<SshConfig>,
+    /// Config file given instead of ~/.ssh/config, passed on to ssh
+    pub config_file: Option<PathBuf>,
+    /// Jump hosts from the command line; these replace any ProxyJump
+    pub jump_hosts: Vec<JumpHost>,
+}
+
+impl Default for SshOptions {
+    fn default() -> Self {
+        Self {
+            backend: SshBackend::default(),
+            identity_file: None,
+            password: None,
+            auth_methods: AuthMethod::ALL.to_vec(),
+            host_key_policy: HostKeyPolicy::default(),
+            known_hosts: None,
+            config: Arc::default(),
+            config_file: None,
+            jump_hosts: Vec::new(),
+        }
+    }
+}
+
+impl SshOptions {
+    /// The backend that will be used. The openssh crate runs ssh in batch
+    /// mode, which never asks for a password, so passwords need libssh2.
+    pub fn backend(&self) -> Result<SshBackend> {
+        match (self.backend, &self.password) {
+            (SshBackend::Auto, Some(_)) => Ok(SshBackend::Native),
+            (SshBackend::Openssh, Some(_)) => Err(BbcprError::Config(
+                "The openssh backend can't log in with a password; use --ssh-backend native or a key".to_string(),
+            )),
+            (backend, _) => Ok(backend.resolve()),
+        }
+    }
+
+    /// An unconnected shell on `host` using the chosen backend. Settings
+    /// given here win over those from the ssh config.
+    pub fn shell(&self, host: &str, user: Option<&str>, port: Option<u16>) -> Result<Box<dyn RemoteShell>> {
+        let host_config = self.config.lookup(host)?;
+        let user = user.map(str::to_string).or(host_config.user);
+        let port = port.or(host_config.port).unwrap_or(22);
+        let identity = self.identity_file.as_ref().map(|path| path.to_string_lossy().into_owned());
+
+        Ok(match self.backend()? {
+            SshBackend::Native => {
+                // libssh2 can't accept the agent channels the server opens back
+                if host_config.forward_agent {
+                    return Err(BbcprError::Config(format!(
+                        "ForwardAgent is set for {} but the native SSH backend can't forward the agent; \
+                         use --ssh-backend openssh or turn ForwardAgent off",
+                        host
+                    )));
+                }
+                let jumps = if self.jump_hosts.is_empty() { &host_config.proxy_jump } else { &self.jump_hosts };
+                let hops = jumps.iter().map(|jump| self.hop(jump)).collect::<Result<Vec<_>>>()?;
+                let host = host_config.host_name.unwrap_or_else(|| host.to_string());
+                Box::new(
+                    NativeSshConnection::new(host, user, port, identity)
+                        .with_identity_files(host_config.identity_files)
+                        .with_jump_hosts(hops)
+                        .with_password(self.password.clone())
+                        .with_auth_methods(self.auth_methods.clone())
+                        .with_host_key_policy(self.host_key_policy)
+                        .with_known_hosts(self.known_hosts.clone()),
+                )
+            }
+            // ssh reads its own config, so it gets the alias and only what
+            // was given on our command line
+            _ => Box::new(
+                SshConnection::new(host.to_string(), user, port, identity)
+                    .with_host_name(host_config.host_name)
+                    .with_config_file(self.config_file.clone())
+                    .with_jump_hosts(self.jump_hosts.iter().map(JumpHost::to_string).collect())
+                    .with_host_key_policy(self.host_key_policy)
+                    .with_known_hosts(self.known_hosts.clone()),
+            ),
+        })
+    }
+
+    /// A jump host with its own config applied, for the native backend
+    fn hop(&self, jump: &JumpHost) -> Result<Hop> {
+        let config = self.config.lookup(&jump.host)?;
+        Ok(Hop {
+            host: config.host_name.unwrap_or_else(|| jump.host.clone()),
+            user: jump.user.clone().or(config.user),
+            port: jump.port.or(config.port).unwrap_or(22),
+            identity_files: self.identity_file.iter().cloned().chain(config.identity_files).collect(),
+        })
+    }
+}
+
+/// One of the pipes of a remote command
+pub type RemoteStdin = Box<dyn AsyncWrite + Send + Unpin>;
+pub type RemoteStdout = Box<dyn AsyncRead + Send + Unpin>;
+
+/// A TCP connection made from a remote host and carried over SSH
+pub struct RemoteSocket {
+    pub reader: RemoteStdout,
+    pub writer: RemoteStdin,
+}
+
+/// A command running on a remote host, whichever backend started it
+pub struct RemoteChild {
+    stdin: Option<RemoteStdin>,
+    stdout: Option<RemoteStdout>,
+    exit: RemoteExit,
+}
+
+enum RemoteExit {
+    Openssh(Box<openssh::Child<Arc<Session>>>),
+    /// The task pumping a libssh2 channel, which yields the exit code
+    Native(JoinHandle<Result<i32>>),
+}
+
+impl RemoteChild {
+    pub(crate) fn native(stdin: RemoteStdin, stdout: RemoteStdout, pump: JoinHandle<Result<i32>>) -> Self {
+        Self {
+            stdin: Some(stdin),
+            stdout: Some(stdout),
+            exit: RemoteExit::Native(pump),
+        }
+    }
+
+    pub fn stdin(&mut self) -> &mut Option<RemoteStdin> {
+        &mut self.stdin
+    }
+
+    pub fn stdout(&mut self) -> &mut Option<RemoteStdout> {
+        &mut self.stdout
+    }
+
+    /// Wait for the command to exit, closing its stdin first
+    pub async fn wait(mut self) -> Result<RemoteStatus> {
+        drop(self.stdin.take());
+        match self.exit {
+            RemoteExit::Openssh(child) => {
+                let status = child.wait().await
+                    .map_err(|e| BbcprError::Ssh(format!("Failed to wait for remote command: {}", e)))?;
+                Ok(RemoteStatus(status.code()))
+            }
+            RemoteExit::Native(pump) => {
+                let code = pump.await
+                    .map_err(|e| BbcprError::Ssh(format!("Remote command task failed: {}", e)))??;
+                Ok(RemoteStatus(Some(code)))
+            }
+        }
+    }
+}
+
+/// How a remote command ended; no code means it was killed by a signal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RemoteStatus(Option<i32>);
+
+impl RemoteStatus {
+    pub fn success(&self) -> bool {
+        self.0 == Some(0)
+    }
+}
+
+impl fmt::Display for RemoteStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(code) => write!(f, "exit status {}", code),
+            None => write!(f, "killed by a signal"),
+        }
+    }
+}
+
+/// Quote `arg` for a POSIX remote shell
+pub fn shell_quote(arg: &str) -> String {
+    let plain = !arg.is_empty() && arg.bytes().all(|b| {
+        b.is_ascii_alphanumeric() || b"-_./:=@,+%".contains(&b)
+    });
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
 
 pub struct SshConnection {
     host: String,
+    /// `HostName` from the ssh config, when `host` is an alias
+    host_name: Option<String>,
     user: Option<String>,
     port: u16,
     identity_file: Option<String>,
-    password: Option<String>,
-    session: Option<Session>,
+    host_key_policy: HostKeyPolicy,
+    known_hosts: Option<PathBuf>,
+    config_file: Option<PathBuf>,
+    jump_hosts: Vec<String>,
+    session: Option<Arc<Session>>,
 }
 
 impl SshConnection {
     pub fn new(host: String, user: Option<String>, port: u16, identity_file: Option<String>) -> Self {
         Self {
             host,
+            host_name: None,
             user,
             port,
             identity_file,
-            password: None,
+            host_key_policy: HostKeyPolicy::default(),
+            known_hosts: None,
+            config_file: None,
+            jump_hosts: Vec::new(),
             session: None,
         }
     }
 
-    pub fn with_password(mut self, password: Option<String>) -> Self {
-        self.password = password;
+    pub fn with_config_file(mut self, config_file: Option<PathBuf>) -> Self {
+        self.config_file = config_file;
         self
     }
+
+    /// The real name of an aliased host, which other hosts connect to
+    pub fn with_host_name(mut self, host_name: Option<String>) -> Self {
+        self.host_name = host_name;
+        self
+    }
+
+    /// Jump hosts for ssh -J, as `[user@]host[:port]`
+    pub fn with_jump_hosts(mut self, jump_hosts: Vec<String>) -> Self {
+        self.jump_hosts = jump_hosts;
+        self
+    }
+
+    pub fn with_host_key_policy(mut self, policy: HostKeyPolicy) -> Self {
+        self.host_key_policy = policy;
+        self
+    }
+
+    pub fn with_known_hosts(mut self, known_hosts: Option<PathBuf>) -> Self {
+        self.known_hosts = known_hosts;
+        self
+    }
+
+    /// Explain a failed connection, naming the offending key when ssh
+    /// rejected the host key
+    async fn connect_error(&self, error: openssh::Error) -> BbcprError {
+        let mut message = error.to_string();
+        let mut source = std::error::Error::source(&error);
+        while let Some(cause) = source {
+            message = format!("{}: {}", message, cause);
+            source = cause.source();
+        }
+
+        let rejected = ["Host key verification failed", "IDENTIFICATION HAS CHANGED", "host key is known"]
+            .iter()
+            .any(|sign| message.contains(sign));
+        if !rejected {
+            return BbcprError::Ssh(format!("Failed to connect: {}", message));
+        }
+        match ssh_native::scan_host_key(&self.host, self.port).await {
+            Ok(key) => BbcprError::Ssh(format!(
+                "Host key verification for {} failed (host key policy {}): it offers {}",
+                self.host, self.host_key_policy.as_str(), key
+            )),
+            Err(_) => BbcprError::Ssh(format!("Host key verification for {} failed: {}", self.host, message)),
+        }
+    }
 }
 
 #[async_trait]
@@ -42,7 +437,7 @@ impl Connection for SshConnection {
         let mut builder = SessionBuilder::default();
         
         if let Some(ref user) = self.user {
-            builder.user(user);
+            builder.user(user.clone());
         }
         
         builder.port(self.port);
@@ -51,42 +446,35 @@ impl Connection for SshConnection {
         if let Some(ref identity) = self.identity_file {
             builder.keyfile(identity);
         }
-        
-        // If password is provided, configure password authentication
-        if let Some(ref password) = self.password {
-            // For openssh crate, password auth is handled via SSH_ASKPASS
-            // We'll set an environment variable that the SSH client can use
-            std::env::set_var("SSH_ASKPASS_REQUIRE", "force");
-            std::env::set_var("DISPLAY", "none");
-            
-            // Create a temporary script that outputs the password
-            let temp_dir = std::env::temp_dir();
-            let askpass_script = temp_dir.join("bbcpr_askpass.sh");
-            
-            use std::fs::File;
-            use std::io::Write;
-            use std::os::unix::fs::PermissionsExt;
-            
-            let mut file = File::create(&askpass_script)
-                .map_err(|e| BbcprError::Io(e))?;
-            
-            writeln!(file, "#!/bin/bash\necho '{}'", password)
-                .map_err(|e| BbcprError::Io(e))?;
-            
-            // Make script executable
-            let mut perms = file.metadata().map_err(|e| BbcprError::Io(e))?.permissions();
-            perms.set_mode(0o755);
-            file.set_permissions(perms).map_err(|e| BbcprError::Io(e))?;
-            
-            std::env::set_var("SSH_ASKPASS", askpass_script.to_string_lossy().to_string());
+
+        builder.known_hosts_check(match self.host_key_policy {
+            HostKeyPolicy::Strict => KnownHosts::Strict,
+            HostKeyPolicy::AcceptNew => KnownHosts::Add,
+            HostKeyPolicy::Off => KnownHosts::Accept,
+        });
+        match (&self.known_hosts, self.host_key_policy) {
+            (Some(file), _) => {
+                builder.user_known_hosts_file(file);
+            }
+            // Accept would otherwise record the key
+            (None, HostKeyPolicy::Off) => {
+                builder.user_known_hosts_file("/dev/null");
+            }
+            (None, _) => {}
         }
+        if let Some(ref file) = self.config_file {
+            builder.config_file(file);
+        }
+        if !self.jump_hosts.is_empty() {
+            builder.jump_hosts(&self.jump_hosts);
+        }
+
+        let session = match builder.connect(&self.host).await {
+            Ok(session) => session,
+            Err(e) => return Err(self.connect_error(e).await),
+        };
         
-        let session = builder
-            .connect(&self.host)
-            .await
-            .map_err(|e| BbcprError::Ssh(format!("Failed to connect: {}", e)))?;
-        
-        self.session = Some(session);
+        self.session = Some(Arc::new(session));
         info!("SSH connection established");
         Ok(())
     }
@@ -108,13 +496,13 @@ impl Connection for SshConnection {
             .ok_or_else(|| BbcprError::Ssh("Failed to get stdin".to_string()))?;
         
         let bytes_written = stdin.write(data).await
-            .map_err(|e| BbcprError::Io(e))?;
+            .map_err(BbcprError::Io)?;
         
         Ok(bytes_written)
     }
     
-    async fn receive(&mut self, buf: &mut [u8]) -> Result<usize> {
-        let session = self.session.as_ref()
+    async fn receive(&mut self, _buf: &mut [u8]) -> Result<usize> {
+        self.session.as_ref()
             .ok_or_else(|| BbcprError::Network("Not connected".to_string()))?;
         
         // This is a simplified version - real implementation would maintain
@@ -129,4 +517,119 @@ impl Connection for SshConnection {
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[async_trait]
+impl RemoteShell for SshConnection {
+    fn host(&self) -> &str {
+        self.host_name.as_deref().unwrap_or(&self.host)
+    }
+
+    /// Stdin and stdout are piped back to us; stderr is passed through
+    async fn spawn(&self, program: &str, args: &[String]) -> Result<RemoteChild> {
+        let session = self.session.as_ref()
+            .ok_or_else(|| BbcprError::Network("Not connected".to_string()))?;
+
+        let mut child = session.clone()
+            .arc_command(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .await
+            .map_err(|e| BbcprError::Ssh(format!("Failed to run {} on {}: {}", program, self.host, e)))?;
+
+        let stdin = child.stdin().take().map(|stdin| Box::new(stdin) as RemoteStdin);
+        let stdout = child.stdout().take().map(|stdout| Box::new(stdout) as RemoteStdout);
+        Ok(RemoteChild {
+            stdin,
+            stdout,
+            exit: RemoteExit::Openssh(Box::new(child)),
+        })
+    }
+
+    /// Each call adds a local forward to the master connection, so the
+    /// connections share one session as separate channels
+    async fn forward(&self, port: u16) -> Result<RemoteSocket> {
+        let session = self.session.as_ref()
+            .ok_or_else(|| BbcprError::Network("Not connected".to_string()))?;
+
+        // A free local port for ssh to listen on
+        let local = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?.local_addr()?;
+        session.request_port_forward(ForwardType::Local, local, (Ipv4Addr::LOCALHOST, port)).await
+            .map_err(|e| BbcprError::Ssh(format!("Failed to forward to port {} on {}: {}", port, self.host, e)))?;
+        let stream = tokio::net::TcpStream::connect(local).await
+            .map_err(|e| BbcprError::Network(format!("Failed to connect to forwarded port {}: {}", local, e)))?;
+
+        let (reader, writer) = stream.into_split();
+        Ok(RemoteSocket { reader: Box::new(reader), writer: Box::new(writer) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_backend_and_auth() {
+        assert_eq!("native".parse::<SshBackend>().unwrap(), SshBackend::Native);
+        assert_eq!(SshBackend::Openssh.resolve(), SshBackend::Openssh);
+        assert!("putty".parse::<SshBackend>().is_err());
+
+        assert_eq!(
+            AuthMethod::parse_list("agent, password").unwrap(),
+            vec![AuthMethod::Agent, AuthMethod::Password],
+        );
+        assert!(AuthMethod::parse_list("agent,hostbased").is_err());
+
+        assert_eq!("accept-new".parse::<HostKeyPolicy>().unwrap(), HostKeyPolicy::AcceptNew);
+        assert_eq!("strict".parse::<HostKeyPolicy>().unwrap(), HostKeyPolicy::Strict);
+        assert!("ask".parse::<HostKeyPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_password_needs_native_backend() {
+        let mut options = SshOptions {
+            password: Some(Arc::new(Secret::from("hunter2".to_string()))),
+            ..SshOptions::default()
+        };
+        assert_eq!(options.backend().unwrap(), SshBackend::Native);
+
+        options.backend = SshBackend::Openssh;
+        assert!(options.backend().is_err());
+        assert!(options.shell("node1", None, None).is_err());
+    }
+
+    #[test]
+    fn test_native_backend_refuses_agent_forwarding() {
+        let options = SshOptions {
+            backend: SshBackend::Native,
+            config: Arc::new(SshConfig::parse("Host node1\n    ForwardAgent yes\n").unwrap()),
+            ..SshOptions::default()
+        };
+        assert!(options.shell("node1", None, None).is_err());
+        assert!(options.shell("node2", None, None).is_ok());
+    }
+
+    #[test]
+    fn test_shell_reports_resolved_host() {
+        for backend in [SshBackend::Openssh, SshBackend::Native] {
+            let options = SshOptions {
+                backend,
+                config: Arc::new(SshConfig::parse("Host store\n    HostName 10.0.0.7\n").unwrap()),
+                ..SshOptions::default()
+            };
+            assert_eq!(options.shell("store", None, None).unwrap().host(), "10.0.0.7");
+            assert_eq!(options.shell("node2", None, None).unwrap().host(), "node2");
+        }
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("/data/f-1.img"), "/data/f-1.img");
+        assert_eq!(shell_quote("my file"), "'my file'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+}
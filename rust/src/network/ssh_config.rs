@@ -0,0 +1,420 @@
+// OpenSSH client configuration (~/.ssh/config)
+//
+// Only what bbcpr needs to reach a host is read: HostName, User, Port,
+// IdentityFile, ProxyJump and ForwardAgent. As in ssh, the first value
+// found for a keyword wins, `Host` patterns may use `*`, `?` and `!`, and
+// `Match` blocks are skipped because their criteria aren't evaluated.
+
+use std::path::{Path, PathBuf};
+
+use crate::error::{BbcprError, Result};
+
+/// Include nesting deeper than this is treated as a loop
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// A host to hop through on the way to the target
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JumpHost {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl JumpHost {
+    /// Parse `[user@]host[:port]`, `[user@][v6addr][:port]` or `ssh://...`
+    pub fn parse(spec: &str) -> Result<Self> {
+        let invalid = || BbcprError::Config(format!("Invalid jump host {:?}", spec));
+        let rest = spec.strip_prefix("ssh://").unwrap_or(spec);
+
+        let (user, rest) = match rest.rsplit_once('@') {
+            Some((user, rest)) if !user.is_empty() => (Some(user.to_string()), rest),
+            Some(_) => return Err(invalid()),
+            None => (None, rest),
+        };
+        let (host, port) = match rest.strip_prefix('[') {
+            Some(inner) => {
+                let (host, after) = inner.split_once(']').ok_or_else(invalid)?;
+                match after {
+                    "" => (host, None),
+                    _ => (host, Some(after.strip_prefix(':').ok_or_else(invalid)?)),
+                }
+            }
+            None => match rest.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (rest, None),
+            },
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let port = port
+            .map(|port| port.parse::<u16>().ok().filter(|&port| port != 0).ok_or_else(invalid))
+            .transpose()?;
+
+        Ok(JumpHost {
+            user,
+            host: host.to_string(),
+            port,
+        })
+    }
+
+    /// Parse a comma-separated chain; "none" means no jump hosts
+    pub fn parse_list(list: &str) -> Result<Vec<JumpHost>> {
+        if list.trim().eq_ignore_ascii_case("none") {
+            return Ok(Vec::new());
+        }
+        list.split(',').map(str::trim).filter(|spec| !spec.is_empty()).map(JumpHost::parse).collect()
+    }
+}
+
+impl std::fmt::Display for JumpHost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(ref user) = self.user {
+            write!(f, "{}@", user)?;
+        }
+        match (self.host.contains(':'), self.port) {
+            (true, Some(port)) => write!(f, "[{}]:{}", self.host, port),
+            (false, Some(port)) => write!(f, "{}:{}", self.host, port),
+            (_, None) => write!(f, "{}", self.host),
+        }
+    }
+}
+
+/// What the config says about one host
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostConfig {
+    /// The real name or address, when the host is an alias
+    pub host_name: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub identity_files: Vec<PathBuf>,
+    pub proxy_jump: Vec<JumpHost>,
+    pub forward_agent: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Block {
+    /// `Host` patterns; `None` applies to every host
+    patterns: Option<Vec<String>>,
+    /// Set for `Match` blocks, which apply to no host
+    skip: bool,
+    options: Vec<(String, String)>,
+}
+
+/// A parsed ssh client config
+#[derive(Debug, Clone, Default)]
+pub struct SshConfig {
+    blocks: Vec<Block>,
+}
+
+impl SshConfig {
+    /// Read `path`, or ~/.ssh/config if there is one when no path is given
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let mut config = SshConfig::default();
+        match path {
+            Some(path) => config.read(path, 0)?,
+            None => {
+                if let Some(path) = dirs::home_dir().map(|home| home.join(".ssh").join("config")) {
+                    if path.exists() {
+                        config.read(&path, 0)?;
+                    }
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut config = SshConfig::default();
+        config.parse_into(text, 0, None, false)?;
+        Ok(config)
+    }
+
+    fn read(&mut self, path: &Path, depth: usize) -> Result<()> {
+        self.read_under(path, depth, None, false)
+    }
+
+    fn read_under(&mut self, path: &Path, depth: usize, patterns: Option<Vec<String>>, skip: bool) -> Result<()> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| BbcprError::Config(format!("Failed to read SSH config {}: {}", path.display(), e)))?;
+        self.parse_into(&text, depth, patterns, skip)
+    }
+
+    /// Parse `text` whose leading lines fall under `patterns`, as an
+    /// included file inherits the Host block of its Include line
+    fn parse_into(&mut self, text: &str, depth: usize, patterns: Option<Vec<String>>, skip: bool) -> Result<()> {
+        if depth > MAX_INCLUDE_DEPTH {
+            return Err(BbcprError::Config("SSH config Include nesting is too deep".to_string()));
+        }
+
+        self.blocks.push(Block { patterns, skip, options: Vec::new() });
+        for line in text.lines() {
+            let Some((keyword, value)) = split_line(line) else {
+                continue;
+            };
+            match keyword.as_str() {
+                "host" => self.blocks.push(Block {
+                    patterns: Some(value.split_whitespace().map(str::to_string).collect()),
+                    skip: false,
+                    options: Vec::new(),
+                }),
+                "match" => self.blocks.push(Block { patterns: None, skip: true, options: Vec::new() }),
+                "include" => {
+                    let (patterns, skip) = self.blocks.last()
+                        .map(|block| (block.patterns.clone(), block.skip))
+                        .unwrap_or_default();
+                    for pattern in value.split_whitespace() {
+                        for file in include_files(pattern) {
+                            self.read_under(&file, depth + 1, patterns.clone(), skip)?;
+                        }
+                    }
+                    // Lines after the Include still belong to its block
+                    self.blocks.push(Block { patterns, skip, options: Vec::new() });
+                }
+                _ => {
+                    if let Some(block) = self.blocks.last_mut() {
+                        block.options.push((keyword, value));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Collect the settings for `host`, the name given on the command line
+    pub fn lookup(&self, host: &str) -> Result<HostConfig> {
+        let mut config = HostConfig::default();
+        let mut seen: Vec<&str> = Vec::new();
+
+        for block in &self.blocks {
+            if block.skip {
+                continue;
+            }
+            if let Some(ref patterns) = block.patterns {
+                if !host_matches(host, patterns) {
+                    continue;
+                }
+            }
+            for (keyword, value) in &block.options {
+                // IdentityFile accumulates; everything else keeps its first value
+                if keyword != "identityfile" && seen.contains(&keyword.as_str()) {
+                    continue;
+                }
+                seen.push(keyword);
+
+                match keyword.as_str() {
+                    "hostname" => config.host_name = Some(expand_tokens(value, host, None, None)),
+                    "user" => config.user = Some(value.clone()),
+                    "port" => {
+                        let port = value.parse::<u16>()
+                            .map_err(|_| BbcprError::Config(format!("Invalid Port {:?} for {}", value, host)))?;
+                        config.port = Some(port);
+                    }
+                    "identityfile" if !value.eq_ignore_ascii_case("none") => {
+                        config.identity_files.push(PathBuf::from(value));
+                    }
+                    "proxyjump" => config.proxy_jump = JumpHost::parse_list(value)?,
+                    "forwardagent" => config.forward_agent = value.eq_ignore_ascii_case("yes"),
+                    _ => {}
+                }
+            }
+        }
+
+        // Tokens in IdentityFile may refer to the final user and port
+        let name = config.host_name.clone().unwrap_or_else(|| host.to_string());
+        config.identity_files = config.identity_files.iter()
+            .map(|file| {
+                let file = expand_tokens(&file.to_string_lossy(), &name, config.user.as_deref(), config.port);
+                expand_tilde(&file)
+            })
+            .collect();
+        Ok(config)
+    }
+}
+
+/// Split `Keyword value` or `Keyword=value`; `None` for blanks and comments
+fn split_line(line: &str) -> Option<(String, String)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let end = line.find(|c: char| c.is_whitespace() || c == '=').unwrap_or(line.len());
+    let keyword = line[..end].to_ascii_lowercase();
+    let value = line[end..].trim_start();
+    let value = value.strip_prefix('=').unwrap_or(value).trim();
+    let value = value.strip_prefix('"').and_then(|value| value.strip_suffix('"')).unwrap_or(value);
+    Some((keyword, value.to_string()))
+}
+
+/// Whether `host` matches a `Host` line: any pattern matches and no negated one does
+fn host_matches(host: &str, patterns: &[String]) -> bool {
+    let mut matched = false;
+    for pattern in patterns {
+        match pattern.strip_prefix('!') {
+            Some(negated) if wildcard_match(negated, host) => return false,
+            Some(_) => {}
+            None => matched |= wildcard_match(pattern, host),
+        }
+    }
+    matched
+}
+
+/// `*` matches any run of characters and `?` any one
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<char>, Vec<char>) = (pattern.chars().collect(), text.chars().collect());
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p].eq_ignore_ascii_case(&text[t])) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Expand %h, %p, %r and %% as ssh does
+fn expand_tokens(value: &str, host: &str, user: Option<&str>, port: Option<u16>) -> String {
+    let mut expanded = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('h') => expanded.push_str(host),
+            Some('p') => expanded.push_str(&port.unwrap_or(22).to_string()),
+            Some('r') => expanded.push_str(user.unwrap_or_default()),
+            Some('%') => expanded.push('%'),
+            Some(other) => {
+                expanded.push('%');
+                expanded.push(other);
+            }
+            None => expanded.push('%'),
+        }
+    }
+    expanded
+}
+
+fn expand_tilde(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Files named by an Include pattern; relative ones live in ~/.ssh, and
+/// wildcards are allowed in the file name
+fn include_files(pattern: &str) -> Vec<PathBuf> {
+    let path = expand_tilde(pattern);
+    let path = match (path.is_relative(), dirs::home_dir()) {
+        (true, Some(home)) => home.join(".ssh").join(path),
+        _ => path,
+    };
+
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    if !name.contains(['*', '?']) {
+        return if path.exists() { vec![path] } else { Vec::new() };
+    }
+    let Some(dir) = path.parent() else {
+        return Vec::new();
+    };
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|file| {
+            file.is_file()
+                && file.file_name().map(|found| wildcard_match(&name, &found.to_string_lossy())).unwrap_or(false)
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "
+# bastion first
+Host bastion
+    HostName bastion.example.org
+    User ops
+    Port 2222
+
+Host node* !node9
+    ProxyJump bastion
+    User alice
+    IdentityFile ~/.ssh/cluster_%r
+
+Host *
+    User nobody
+    ForwardAgent yes
+    IdentityFile=/keys/default
+";
+
+    #[test]
+    fn test_lookup() {
+        let config = SshConfig::parse(CONFIG).unwrap();
+
+        let bastion = config.lookup("bastion").unwrap();
+        assert_eq!(bastion.host_name.as_deref(), Some("bastion.example.org"));
+        assert_eq!(bastion.user.as_deref(), Some("ops"));
+        assert_eq!(bastion.port, Some(2222));
+        assert!(bastion.proxy_jump.is_empty());
+        assert!(bastion.forward_agent);
+
+        let node = config.lookup("node3").unwrap();
+        assert_eq!(node.host_name, None);
+        assert_eq!(node.user.as_deref(), Some("alice"));
+        assert_eq!(node.proxy_jump, vec![JumpHost { user: None, host: "bastion".to_string(), port: None }]);
+        assert_eq!(node.identity_files.len(), 2);
+        assert!(node.identity_files[0].ends_with(".ssh/cluster_alice"));
+        assert_eq!(node.identity_files[1], PathBuf::from("/keys/default"));
+
+        let excluded = config.lookup("node9").unwrap();
+        assert_eq!(excluded.user.as_deref(), Some("nobody"));
+        assert!(excluded.proxy_jump.is_empty());
+    }
+
+    #[test]
+    fn test_match_blocks_are_skipped() {
+        let config = SshConfig::parse("Match user root\n  Port 99\nHost *\n  Port 22\n").unwrap();
+        assert_eq!(config.lookup("any").unwrap().port, Some(22));
+    }
+
+    #[test]
+    fn test_parse_jump_hosts() {
+        assert_eq!(
+            JumpHost::parse_list("ops@gw1:2222, [fd00::1]:22,gw3").unwrap(),
+            vec![
+                JumpHost { user: Some("ops".to_string()), host: "gw1".to_string(), port: Some(2222) },
+                JumpHost { user: None, host: "fd00::1".to_string(), port: Some(22) },
+                JumpHost { user: None, host: "gw3".to_string(), port: None },
+            ],
+        );
+        assert_eq!(JumpHost::parse("ssh://ops@gw1").unwrap().to_string(), "ops@gw1");
+        assert!(JumpHost::parse_list("none").unwrap().is_empty());
+        assert!(JumpHost::parse("gw1:port").is_err());
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("*.example.org", "a.b.example.org"));
+        assert!(wildcard_match("node?", "NODE1"));
+        assert!(!wildcard_match("node?", "node10"));
+        assert!(wildcard_match("*", ""));
+    }
+}
@@ -0,0 +1,710 @@
+// libssh2 SSH backend
+//
+// The whole client runs in-process on the ssh2 crate, so bbcpr works on
+// hosts with no ssh binary. libssh2 calls block: connecting and
+// authenticating run on the blocking pool, after which the session is made
+// non-blocking and each remote command is pumped by an async task.
+
+use async_trait::async_trait;
+use ssh2::{CheckResult, Channel, ErrorCode, HashType, HostKeyType, KeyboardInteractivePrompt, KnownHostFileKind, Prompt, Session};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tracing::{debug, info, warn};
+
+use crate::auth::Secret;
+use crate::error::{BbcprError, Result};
+use crate::network::ssh::{shell_quote, AuthMethod, HostKeyPolicy, RemoteChild, RemoteSocket};
+use crate::network::{Connection, RemoteShell};
+
+/// libssh2's "would block", returned by non-blocking sessions
+const LIBSSH2_ERROR_EAGAIN: i32 = -37;
+
+/// How long an idle pump waits before polling the channel again
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+/// Buffer between a pump and the local end of a remote command's pipes
+const PIPE_BUFFER: usize = 256 * 1024;
+
+pub struct NativeSshConnection {
+    host: String,
+    user: Option<String>,
+    port: u16,
+    identity_files: Vec<PathBuf>,
+    password: Option<Arc<Secret>>,
+    auth_methods: Vec<AuthMethod>,
+    host_key_policy: HostKeyPolicy,
+    known_hosts: Option<PathBuf>,
+    jump_hosts: Vec<Hop>,
+    session: Option<Session>,
+}
+
+/// A jump host on the way to the target, with its settings resolved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hop {
+    pub host: String,
+    pub user: Option<String>,
+    pub port: u16,
+    pub identity_files: Vec<PathBuf>,
+}
+
+impl NativeSshConnection {
+    pub fn new(host: String, user: Option<String>, port: u16, identity_file: Option<String>) -> Self {
+        Self {
+            host,
+            user,
+            port,
+            identity_files: identity_file.into_iter().map(PathBuf::from).collect(),
+            password: None,
+            auth_methods: AuthMethod::ALL.to_vec(),
+            host_key_policy: HostKeyPolicy::default(),
+            known_hosts: None,
+            jump_hosts: Vec::new(),
+            session: None,
+        }
+    }
+
+    pub fn with_password(mut self, password: Option<Arc<Secret>>) -> Self {
+        self.password = password;
+        self
+    }
+
+    /// Methods to try, in order; ones the server doesn't offer are skipped
+    pub fn with_auth_methods(mut self, methods: Vec<AuthMethod>) -> Self {
+        self.auth_methods = methods;
+        self
+    }
+
+    pub fn with_host_key_policy(mut self, policy: HostKeyPolicy) -> Self {
+        self.host_key_policy = policy;
+        self
+    }
+
+    /// known_hosts file to use instead of ~/.ssh/known_hosts
+    pub fn with_known_hosts(mut self, known_hosts: Option<PathBuf>) -> Self {
+        self.known_hosts = known_hosts;
+        self
+    }
+
+    /// Further keys to try after the identity file, as from IdentityFile
+    pub fn with_identity_files(mut self, files: Vec<PathBuf>) -> Self {
+        self.identity_files.extend(files);
+        self
+    }
+
+    /// Reach the host through these jump hosts, first one first
+    pub fn with_jump_hosts(mut self, hops: Vec<Hop>) -> Self {
+        self.jump_hosts = hops;
+        self
+    }
+}
+
+#[async_trait]
+impl Connection for NativeSshConnection {
+    async fn connect(&mut self) -> Result<()> {
+        info!("Connecting to {}:{} with libssh2", self.host, self.port);
+
+        // Only needed to log in; wiped once the logins and every other
+        // connection sharing it are dropped
+        let password = self.password.take();
+        let target = Hop {
+            host: self.host.clone(),
+            user: self.user.clone(),
+            port: self.port,
+            identity_files: self.identity_files.clone(),
+        };
+        let logins = self.jump_hosts.iter().chain(std::iter::once(&target))
+            .map(|hop| {
+                Ok(Login {
+                    host: hop.host.clone(),
+                    port: hop.port,
+                    user: hop.user.clone().map_or_else(local_user, Ok)?,
+                    identity_files: hop.identity_files.clone(),
+                    password: password.clone(),
+                    auth_methods: self.auth_methods.clone(),
+                    host_key_policy: self.host_key_policy,
+                    known_hosts: self.known_hosts.clone(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        drop(password);
+
+        // Each hop's session carries a tunnel to the next
+        let session = tokio::task::spawn_blocking(move || {
+            let mut via: Option<Session> = None;
+            for login in logins {
+                if via.is_some() {
+                    debug!("Tunnelling to {}:{}", login.host, login.port);
+                }
+                via = Some(login.open(via.as_ref())?);
+            }
+            via.ok_or_else(|| BbcprError::Ssh("No host to log in to".to_string()))
+        })
+        .await
+        .map_err(|e| BbcprError::Ssh(format!("SSH login task failed: {}", e)))??;
+
+        session.set_blocking(false);
+        self.session = Some(session);
+        info!("SSH connection established");
+        Ok(())
+    }
+
+    async fn send(&mut self, _data: &[u8]) -> Result<usize> {
+        Err(BbcprError::Unsupported("SSH data goes through remote commands; use spawn".to_string()))
+    }
+
+    async fn receive(&mut self, _buf: &mut [u8]) -> Result<usize> {
+        Err(BbcprError::Unsupported("SSH data goes through remote commands; use spawn".to_string()))
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        if let Some(session) = self.session.take() {
+            session.set_blocking(true);
+            let _ = session.disconnect(None, "bbcpr done", None);
+            info!("SSH connection closed");
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl RemoteShell for NativeSshConnection {
+    fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Remote stderr is copied to ours, as the openssh backend passes it through
+    async fn spawn(&self, program: &str, args: &[String]) -> Result<RemoteChild> {
+        let session = self.session.as_ref()
+            .ok_or_else(|| BbcprError::Network("Not connected".to_string()))?;
+
+        let command = std::iter::once(program)
+            .chain(args.iter().map(String::as_str))
+            .map(shell_quote)
+            .collect::<Vec<_>>()
+            .join(" ");
+        debug!("Running {} on {}", command, self.host);
+
+        let failed = |e: ssh2::Error| BbcprError::Ssh(format!("Failed to run {} on {}: {}", program, self.host, e));
+        let mut channel = again(|| session.channel_session()).await.map_err(failed)?;
+        again(|| channel.exec(&command)).await.map_err(failed)?;
+
+        let (stdin, pump_input) = tokio::io::duplex(PIPE_BUFFER);
+        let (pump_output, stdout) = tokio::io::duplex(PIPE_BUFFER);
+        let pump = tokio::spawn(pump(channel, pump_input, pump_output));
+        Ok(RemoteChild::native(Box::new(stdin), Box::new(stdout), pump))
+    }
+
+    /// A direct-tcpip channel on this session, pumped like a command's pipes
+    async fn forward(&self, port: u16) -> Result<RemoteSocket> {
+        let session = self.session.as_ref()
+            .ok_or_else(|| BbcprError::Network("Not connected".to_string()))?;
+
+        let channel = again(|| session.channel_direct_tcpip("127.0.0.1", port, None)).await
+            .map_err(|e| BbcprError::Ssh(format!("Failed to forward to port {} on {}: {}", port, self.host, e)))?;
+
+        let (writer, pump_input) = tokio::io::duplex(PIPE_BUFFER);
+        let (pump_output, reader) = tokio::io::duplex(PIPE_BUFFER);
+        let host = self.host.clone();
+        tokio::spawn(async move {
+            if let Err(e) = pump(channel, pump_input, pump_output).await {
+                debug!("Forwarded connection to port {} on {} failed: {}", port, host, e);
+            }
+        });
+        Ok(RemoteSocket { reader: Box::new(reader), writer: Box::new(writer) })
+    }
+}
+
+/// Everything a blocking login needs
+struct Login {
+    host: String,
+    port: u16,
+    user: String,
+    identity_files: Vec<PathBuf>,
+    password: Option<Arc<Secret>>,
+    auth_methods: Vec<AuthMethod>,
+    host_key_policy: HostKeyPolicy,
+    known_hosts: Option<PathBuf>,
+}
+
+impl Login {
+    /// Log in over TCP, or through a tunnel from the `via` session
+    fn open(self, via: Option<&Session>) -> Result<Session> {
+        let mut session = Session::new()
+            .map_err(|e| BbcprError::Ssh(format!("Failed to start libssh2: {}", e)))?;
+        match via {
+            Some(jump) => session.set_tcp_stream(tunnel(jump, &self.host, self.port)?),
+            None => {
+                let tcp = TcpStream::connect((self.host.as_str(), self.port))
+                    .map_err(|e| BbcprError::Network(format!("Failed to connect to {}:{}: {}", self.host, self.port, e)))?;
+                let _ = tcp.set_nodelay(true);
+                session.set_tcp_stream(tcp);
+            }
+        }
+        session.handshake()
+            .map_err(|e| BbcprError::Ssh(format!("SSH handshake with {} failed: {}", self.host, e)))?;
+
+        self.check_host_key(&session)?;
+        self.authenticate(&session)?;
+        Ok(session)
+    }
+
+    /// Check the host key against known_hosts under the chosen policy
+    fn check_host_key(&self, session: &Session) -> Result<()> {
+        if self.host_key_policy == HostKeyPolicy::Off {
+            debug!("Not checking the host key of {}", self.host);
+            return Ok(());
+        }
+
+        let (key, key_type) = session.host_key()
+            .ok_or_else(|| BbcprError::Ssh(format!("{} sent no host key", self.host)))?;
+        let offered = HostKey::of(session)?;
+        let known_hosts_file = self.known_hosts.clone()
+            .or_else(|| dirs::home_dir().map(|home| home.join(".ssh").join("known_hosts")));
+
+        let mut known_hosts = session.known_hosts()
+            .map_err(|e| BbcprError::Ssh(format!("Failed to read known hosts: {}", e)))?;
+        if let Some(ref file) = known_hosts_file {
+            if file.exists() {
+                known_hosts.read_file(file, KnownHostFileKind::OpenSSH)
+                    .map_err(|e| BbcprError::Ssh(format!("Failed to read {}: {}", file.display(), e)))?;
+            }
+        }
+        let file_name = known_hosts_file.as_deref()
+            .map(|file| file.display().to_string())
+            .unwrap_or_else(|| "known_hosts".to_string());
+
+        match host_key_action(self.host_key_policy, known_hosts.check_port(&self.host, self.port, key)) {
+            HostKeyAction::Accept => Ok(()),
+            HostKeyAction::RejectChanged => Err(BbcprError::Ssh(format!(
+                "Host key for {} has changed: it now offers {}. If the host was reinstalled, remove its entry from {}",
+                self.host, offered, file_name,
+            ))),
+            HostKeyAction::Fail => Err(BbcprError::Ssh(format!("Failed to check the host key of {}", self.host))),
+            HostKeyAction::RejectUnknown => Err(BbcprError::Ssh(format!(
+                "{} is not in {}: it offers {}. Add it, or use --host-key-policy accept-new",
+                self.host, file_name, offered,
+            ))),
+            HostKeyAction::Remember => {
+                warn!("Permanently added {} ({}) to the list of known hosts", self.host, offered);
+                match known_hosts_file {
+                    Some(file) => self.remember_host_key(session, &file, key, key_type),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Append the host's key to `file`, leaving the existing entries untouched
+    fn remember_host_key(&self, session: &Session, file: &Path, key: &[u8], key_type: HostKeyType) -> Result<()> {
+        let name = match self.port {
+            22 => self.host.clone(),
+            port => format!("[{}]:{}", self.host, port),
+        };
+        let failed = |e: ssh2::Error| BbcprError::Ssh(format!("Failed to record the host key of {}: {}", self.host, e));
+
+        let mut entry = session.known_hosts().map_err(failed)?;
+        entry.add(&name, key, "", key_type.into()).map_err(failed)?;
+        let hosts = entry.hosts().map_err(failed)?;
+        let host = hosts.first()
+            .ok_or_else(|| BbcprError::Ssh(format!("Failed to record the host key of {}", self.host)))?;
+        let line = entry.write_string(host, KnownHostFileKind::OpenSSH).map_err(failed)?;
+
+        if let Some(dir) = file.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut known_hosts = std::fs::OpenOptions::new().create(true).append(true).open(file)?;
+        writeln!(known_hosts, "{}", line.trim_end())?;
+        Ok(())
+    }
+
+    fn authenticate(&self, session: &Session) -> Result<()> {
+        // Asking for the methods may already log us in if the server allows "none"
+        let offered = session.auth_methods(&self.user)
+            .map_err(|e| BbcprError::Ssh(format!("Failed to list auth methods of {}: {}", self.host, e)))?
+            .to_string();
+        if session.authenticated() {
+            return Ok(());
+        }
+
+        for method in auth_plan(&self.auth_methods, &offered, self.password.is_some()) {
+            let attempt = match (method, &self.password) {
+                (AuthMethod::Agent, _) => session.userauth_agent(&self.user),
+                (AuthMethod::PublicKey, _) => self.try_keys(session),
+                (AuthMethod::KeyboardInteractive, Some(password)) => {
+                    session.userauth_keyboard_interactive(&self.user, &mut PasswordPrompt(password.expose()))
+                }
+                (AuthMethod::Password, Some(password)) => session.userauth_password(&self.user, password.expose()),
+                (AuthMethod::KeyboardInteractive | AuthMethod::Password, None) => continue,
+            };
+            match attempt {
+                Ok(()) if session.authenticated() => {
+                    debug!("Authenticated to {} as {} with {:?}", self.host, self.user, method);
+                    return Ok(());
+                }
+                Ok(()) => {}
+                Err(e) => debug!("{:?} authentication to {} failed: {}", method, self.host, e),
+            }
+        }
+
+        Err(BbcprError::Ssh(format!(
+            "Authentication as {} on {} failed (server offers {})",
+            self.user, self.host, offered
+        )))
+    }
+
+    /// The identity files, or else each default key in ~/.ssh
+    fn try_keys(&self, session: &Session) -> std::result::Result<(), ssh2::Error> {
+        let keys = match self.identity_files.as_slice() {
+            [] => dirs::home_dir()
+                .map(|home| {
+                    ["id_ed25519", "id_ecdsa", "id_rsa"].iter()
+                        .map(|name| home.join(".ssh").join(name))
+                        .filter(|key| key.exists())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            files => files.to_vec(),
+        };
+
+        let mut result = Err(ssh2::Error::new(ErrorCode::Session(-18), "no key file found"));
+        for key in keys {
+            result = session.userauth_pubkey_file(&self.user, None, &key, None);
+            if session.authenticated() {
+                break;
+            }
+        }
+        result
+    }
+}
+
+/// The configured `methods` worth trying, in order: those the server
+/// `offered` (a comma-separated list), leaving out the password methods
+/// when there is no password
+fn auth_plan(methods: &[AuthMethod], offered: &str, have_password: bool) -> Vec<AuthMethod> {
+    methods.iter()
+        .copied()
+        .filter(|method| offered.split(',').any(|name| name == method.server_name()))
+        .filter(|method| have_password || !matches!(method, AuthMethod::KeyboardInteractive | AuthMethod::Password))
+        .collect()
+}
+
+/// What to do with the key a host offers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HostKeyAction {
+    Accept,
+    /// Accept it and add it to known_hosts
+    Remember,
+    /// The host is known with another key
+    RejectChanged,
+    /// The host is not known and the policy is strict
+    RejectUnknown,
+    /// known_hosts could not be checked
+    Fail,
+}
+
+/// Decide on a host key from the known_hosts `check` under `policy`
+fn host_key_action(policy: HostKeyPolicy, check: CheckResult) -> HostKeyAction {
+    match (policy, check) {
+        (HostKeyPolicy::Off, _) | (_, CheckResult::Match) => HostKeyAction::Accept,
+        (_, CheckResult::Mismatch) => HostKeyAction::RejectChanged,
+        (_, CheckResult::Failure) => HostKeyAction::Fail,
+        (HostKeyPolicy::Strict, CheckResult::NotFound) => HostKeyAction::RejectUnknown,
+        (HostKeyPolicy::AcceptNew, CheckResult::NotFound) => HostKeyAction::Remember,
+    }
+}
+
+/// A host key as ssh shows it: type and SHA256 fingerprint
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostKey {
+    pub key_type: &'static str,
+    pub fingerprint: String,
+}
+
+impl HostKey {
+    fn of(session: &Session) -> Result<Self> {
+        let key_type = match session.host_key() {
+            Some((_, HostKeyType::Rsa)) => "RSA",
+            Some((_, HostKeyType::Dss)) => "DSA",
+            Some((_, HostKeyType::Ecdsa256 | HostKeyType::Ecdsa384 | HostKeyType::Ecdsa521)) => "ECDSA",
+            Some((_, HostKeyType::Ed25519)) => "ED25519",
+            _ => "unknown",
+        };
+        let hash = session.host_key_hash(HashType::Sha256)
+            .ok_or_else(|| BbcprError::Ssh("Failed to hash the host key".to_string()))?;
+        Ok(HostKey {
+            key_type,
+            fingerprint: format!("SHA256:{}", base64_unpadded(hash)),
+        })
+    }
+}
+
+impl fmt::Display for HostKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} key {}", self.key_type, self.fingerprint)
+    }
+}
+
+/// Fetch the key `host` offers, without logging in
+pub async fn scan_host_key(host: &str, port: u16) -> Result<HostKey> {
+    let host = host.to_string();
+    tokio::task::spawn_blocking(move || {
+        let tcp = TcpStream::connect((host.as_str(), port))
+            .map_err(|e| BbcprError::Network(format!("Failed to connect to {}:{}: {}", host, port, e)))?;
+        let mut session = Session::new()
+            .map_err(|e| BbcprError::Ssh(format!("Failed to start libssh2: {}", e)))?;
+        session.set_tcp_stream(tcp);
+        session.handshake()
+            .map_err(|e| BbcprError::Ssh(format!("SSH handshake with {} failed: {}", host, e)))?;
+        HostKey::of(&session)
+    })
+    .await
+    .map_err(|e| BbcprError::Ssh(format!("Host key scan failed: {}", e)))?
+}
+
+/// Open a direct-tcpip channel from `jump` to `host:port` and return a
+/// socket a new session can run over, as ssh -J does
+fn tunnel(jump: &Session, host: &str, port: u16) -> Result<TcpStream> {
+    let channel = jump.channel_direct_tcpip(host, port, None)
+        .map_err(|e| BbcprError::Ssh(format!("Jump host could not reach {}:{}: {}", host, port, e)))?;
+    // A loopback pair rather than a socketpair, which Windows lacks
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let ours = TcpStream::connect(listener.local_addr()?)?;
+    let (theirs, _) = listener.accept()?;
+    let _ = ours.set_nodelay(true);
+    let jump = jump.clone();
+    std::thread::spawn(move || relay(jump, channel, theirs));
+    Ok(ours)
+}
+
+/// Copy between a tunnel channel and its socket until either side closes.
+/// Owns the jump session from here on, so it can switch it to non-blocking.
+fn relay(jump: Session, mut channel: Channel, mut socket: TcpStream) {
+    jump.set_blocking(false);
+    if socket.set_nonblocking(true).is_err() {
+        return;
+    }
+    let mut buffer = vec![0u8; 32 * 1024];
+    let mut to_channel = Vec::new();
+    let mut to_socket = Vec::new();
+
+    loop {
+        let mut idle = true;
+
+        if to_channel.is_empty() {
+            match socket.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => {
+                    to_channel.extend_from_slice(&buffer[..n]);
+                    idle = false;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(_) => break,
+            }
+        }
+        if !to_channel.is_empty() {
+            match channel.write(&to_channel) {
+                Ok(n) => {
+                    to_channel.drain(..n);
+                    idle = false;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(_) => break,
+            }
+        }
+
+        if to_socket.is_empty() {
+            match channel.read(&mut buffer) {
+                Ok(0) if channel.eof() => break,
+                Ok(n) => {
+                    to_socket.extend_from_slice(&buffer[..n]);
+                    idle = idle && n == 0;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(_) => break,
+            }
+        }
+        if !to_socket.is_empty() {
+            match socket.write(&to_socket) {
+                Ok(n) => {
+                    to_socket.drain(..n);
+                    idle = false;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(_) => break,
+            }
+        }
+
+        if idle {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+    debug!("Jump host tunnel closed");
+}
+
+/// Base64 without padding, as OpenSSH prints fingerprints
+fn base64_unpadded(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &byte)| bits | (byte as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    encoded
+}
+
+/// Answers every keyboard-interactive prompt with the password. ssh2 takes
+/// the answers by value, so these copies are beyond our reach to wipe.
+struct PasswordPrompt<'a>(&'a str);
+
+impl KeyboardInteractivePrompt for PasswordPrompt<'_> {
+    fn prompt<'b>(&mut self, _username: &str, _instructions: &str, prompts: &[Prompt<'b>]) -> Vec<String> {
+        prompts.iter().map(|_| self.0.to_string()).collect()
+    }
+}
+
+fn local_user() -> Result<String> {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("LOGNAME"))
+        .map_err(|_| BbcprError::Config("Could not determine the local user name; use user@host".to_string()))
+}
+
+/// Retry a non-blocking libssh2 call until it stops asking to be retried
+async fn again<T>(mut call: impl FnMut() -> std::result::Result<T, ssh2::Error>) -> std::result::Result<T, ssh2::Error> {
+    loop {
+        match call() {
+            Err(e) if e.code() == ErrorCode::Session(LIBSSH2_ERROR_EAGAIN) => tokio::time::sleep(POLL_INTERVAL).await,
+            result => return result,
+        }
+    }
+}
+
+/// Move data between a channel and the local ends of the command's pipes
+/// until the command exits, then return its exit status
+async fn pump(mut channel: Channel, mut input: DuplexStream, mut output: DuplexStream) -> Result<i32> {
+    let mut stderr = channel.stderr();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut pending = Vec::new();
+    let mut input_open = true;
+
+    loop {
+        let mut idle = true;
+
+        // Remote stdout and stderr
+        loop {
+            match channel.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => {
+                    idle = false;
+                    // A closed local end only means nobody wants the rest
+                    let _ = output.write_all(&buffer[..n]).await;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(BbcprError::Io(e)),
+            }
+        }
+        while let Ok(n) = stderr.read(&mut buffer) {
+            if n == 0 {
+                break;
+            }
+            idle = false;
+            let _ = std::io::stderr().write_all(&buffer[..n]);
+        }
+        if channel.eof() {
+            break;
+        }
+
+        // Local stdin
+        if !pending.is_empty() {
+            match channel.write(&pending) {
+                Ok(n) => {
+                    pending.drain(..n);
+                    idle = false;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(BbcprError::Io(e)),
+            }
+        } else if input_open {
+            // Reading doubles as the idle wait
+            match tokio::time::timeout(POLL_INTERVAL, input.read(&mut buffer)).await {
+                Ok(Ok(0)) | Ok(Err(_)) => {
+                    input_open = false;
+                    again(|| channel.send_eof()).await
+                        .map_err(|e| BbcprError::Ssh(format!("Failed to close remote stdin: {}", e)))?;
+                }
+                Ok(Ok(n)) => pending.extend_from_slice(&buffer[..n]),
+                Err(_) => {}
+            }
+            continue;
+        }
+
+        if idle {
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    let _ = output.shutdown().await;
+    drop(output);
+    again(|| channel.wait_close()).await
+        .map_err(|e| BbcprError::Ssh(format!("Failed to close channel: {}", e)))?;
+    channel.exit_status()
+        .map_err(|e| BbcprError::Ssh(format!("Failed to read exit status: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64_unpadded() {
+        assert_eq!(base64_unpadded(b""), "");
+        assert_eq!(base64_unpadded(b"f"), "Zg");
+        assert_eq!(base64_unpadded(b"fo"), "Zm8");
+        assert_eq!(base64_unpadded(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64_unpadded(&[0xfb, 0xff]), "+/8");
+    }
+
+    #[test]
+    fn test_auth_plan_keeps_configured_order() {
+        let methods = [AuthMethod::Password, AuthMethod::Agent, AuthMethod::PublicKey];
+
+        // Agent keys are offered as publickey
+        assert_eq!(
+            auth_plan(&methods, "publickey,password", true),
+            vec![AuthMethod::Password, AuthMethod::Agent, AuthMethod::PublicKey]
+        );
+        assert_eq!(
+            auth_plan(&methods, "publickey,password", false),
+            vec![AuthMethod::Agent, AuthMethod::PublicKey]
+        );
+        // The server's order doesn't matter
+        assert_eq!(
+            auth_plan(&AuthMethod::ALL, "password,keyboard-interactive", true),
+            vec![AuthMethod::KeyboardInteractive, AuthMethod::Password]
+        );
+        assert!(auth_plan(&methods, "hostbased", true).is_empty());
+    }
+
+    #[test]
+    fn test_host_key_action_per_policy() {
+        use HostKeyAction::*;
+
+        let cases = [
+            (HostKeyPolicy::Strict, [Accept, RejectChanged, RejectUnknown]),
+            (HostKeyPolicy::AcceptNew, [Accept, RejectChanged, Remember]),
+            (HostKeyPolicy::Off, [Accept, Accept, Accept]),
+        ];
+        for (policy, [matched, mismatched, not_found]) in cases {
+            assert_eq!(host_key_action(policy, CheckResult::Match), matched, "{:?}", policy);
+            assert_eq!(host_key_action(policy, CheckResult::Mismatch), mismatched, "{:?}", policy);
+            assert_eq!(host_key_action(policy, CheckResult::NotFound), not_found, "{:?}", policy);
+        }
+        assert_eq!(host_key_action(HostKeyPolicy::Strict, CheckResult::Failure), Fail);
+    }
+}
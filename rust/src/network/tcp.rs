@@ -2,7 +2,7 @@ use async_trait::async_trait;
 use std::net::SocketAddr;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tracing::{debug, info};
+use tracing::info;
 
 use crate::error::{BbcprError, Result};
 use crate::network::Connection;
@@ -22,9 +22,9 @@ impl TcpConnection {
         }
     }
     
-    fn configure_socket(stream: &TcpStream) -> Result<()> {
+    fn configure_socket(stream: &TcpStream, window_size: usize) -> Result<()> {
         use socket2::Socket;
-        use std::os::unix::io::AsRawFd;
+        use std::os::unix::io::{AsRawFd, FromRawFd};
         
         let sock = unsafe {
             Socket::from_raw_fd(stream.as_raw_fd())
@@ -34,11 +34,11 @@ impl TcpConnection {
         sock.set_nodelay(true)
             .map_err(|e| BbcprError::Network(format!("Failed to set TCP_NODELAY: {}", e)))?;
         
-        // Set send/receive buffer sizes
-        sock.set_send_buffer_size(1024 * 1024)
+        // Size the socket buffers to the requested window (-w)
+        sock.set_send_buffer_size(window_size)
             .map_err(|e| BbcprError::Network(format!("Failed to set send buffer: {}", e)))?;
         
-        sock.set_recv_buffer_size(1024 * 1024)
+        sock.set_recv_buffer_size(window_size)
             .map_err(|e| BbcprError::Network(format!("Failed to set recv buffer: {}", e)))?;
         
         // Don't drop the socket, we're just borrowing it
@@ -58,7 +58,7 @@ impl Connection for TcpConnection {
         
         #[cfg(unix)]
         {
-            Self::configure_socket(&stream)?;
+            Self::configure_socket(&stream, self.window_size)?;
         }
         
         self.stream = Some(stream);
@@ -71,10 +71,10 @@ impl Connection for TcpConnection {
             .ok_or_else(|| BbcprError::Network("Not connected".to_string()))?;
         
         let bytes_written = stream.write(data).await
-            .map_err(|e| BbcprError::Io(e))?;
+            .map_err(BbcprError::Io)?;
         
         stream.flush().await
-            .map_err(|e| BbcprError::Io(e))?;
+            .map_err(BbcprError::Io)?;
         
         Ok(bytes_written)
     }
@@ -84,7 +84,7 @@ impl Connection for TcpConnection {
             .ok_or_else(|| BbcprError::Network("Not connected".to_string()))?;
         
         let bytes_read = stream.read(buf).await
-            .map_err(|e| BbcprError::Io(e))?;
+            .map_err(BbcprError::Io)?;
         
         Ok(bytes_read)
     }
@@ -92,7 +92,7 @@ impl Connection for TcpConnection {
     async fn close(&mut self) -> Result<()> {
         if let Some(mut stream) = self.stream.take() {
             stream.shutdown().await
-                .map_err(|e| BbcprError::Io(e))?;
+                .map_err(BbcprError::Io)?;
             info!("TCP connection closed");
         }
         Ok(())
@@ -1,5 +1,8 @@
 use crate::error::Result;
 use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+#[cfg(not(unix))]
+use std::time::SystemTime;
 
 pub trait FileSystem {
     fn get_file_size(path: &Path) -> Result<u64>;
@@ -9,38 +12,367 @@ pub trait FileSystem {
     fn get_block_size(path: &Path) -> Result<u64>;
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FileMetadata {
     pub size: u64,
     pub modified: u64,
+    pub modified_nsec: u32,
+    pub accessed: u64,
+    pub accessed_nsec: u32,
     pub mode: u32,
     pub uid: u32,
     pub gid: u32,
+    /// Extended attributes as name/value pairs; only filled in on request,
+    /// see `read_xattrs`
+    pub xattrs: Vec<(String, Vec<u8>)>,
+}
+
+impl FileMetadata {
+    pub fn from_std(metadata: &std::fs::Metadata) -> Self {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            FileMetadata {
+                size: metadata.len(),
+                modified: metadata.mtime().max(0) as u64,
+                modified_nsec: metadata.mtime_nsec() as u32,
+                accessed: metadata.atime().max(0) as u64,
+                accessed_nsec: metadata.atime_nsec() as u32,
+                mode: metadata.mode(),
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+                xattrs: Vec::new(),
+            }
+        }
+
+        #[cfg(not(unix))]
+        {
+            let since_epoch = |time: std::io::Result<SystemTime>| {
+                time.ok()
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .unwrap_or_default()
+            };
+            let modified = since_epoch(metadata.modified());
+            let accessed = since_epoch(metadata.accessed());
+            FileMetadata {
+                size: metadata.len(),
+                modified: modified.as_secs(),
+                modified_nsec: modified.subsec_nanos(),
+                accessed: accessed.as_secs(),
+                accessed_nsec: accessed.subsec_nanos(),
+                mode: if metadata.permissions().readonly() { 0o444 } else { 0o644 }, // Default permissions on Windows
+                uid: 0,
+                gid: 0,
+                xattrs: Vec::new(),
+            }
+        }
+    }
 }
 
 pub fn get_metadata(path: &Path) -> Result<FileMetadata> {
     let metadata = std::fs::metadata(path)?;
-    
+    Ok(FileMetadata::from_std(&metadata))
+}
+
+/// Apply preserved attributes to `path` once its data is complete.
+///
+/// Ownership is only changed when running privileged. Timestamps are set
+/// before the mode, so a read-only source mode can't get in the way.
+pub fn apply_metadata(path: &Path, metadata: &FileMetadata) -> Result<()> {
+    #[cfg(unix)]
+    if is_privileged() {
+        std::os::unix::fs::chown(path, Some(metadata.uid), Some(metadata.gid))?;
+    }
+
+    let times = std::fs::FileTimes::new()
+        .set_accessed(UNIX_EPOCH + Duration::new(metadata.accessed, metadata.accessed_nsec))
+        .set_modified(UNIX_EPOCH + Duration::new(metadata.modified, metadata.modified_nsec));
+    std::fs::File::open(path)?.set_times(times)?;
+
+    set_mode(path, metadata.mode)
+}
+
+/// Set the permission bits of `path` exactly, regardless of the umask.
+/// Elsewhere than Unix only the write bits count, as the read-only flag.
+pub fn set_mode(path: &Path, mode: u32) -> Result<()> {
     #[cfg(unix)]
     {
-        use std::os::unix::fs::MetadataExt;
-        Ok(FileMetadata {
-            size: metadata.len(),
-            modified: metadata.mtime() as u64,
-            mode: metadata.mode(),
-            uid: metadata.uid(),
-            gid: metadata.gid(),
-        })
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o7777))?;
     }
-    
+
+    #[cfg(not(unix))]
+    {
+        let mut permissions = std::fs::metadata(path)?.permissions();
+        permissions.set_readonly(mode & 0o222 == 0);
+        std::fs::set_permissions(path, permissions)?;
+    }
+
+    Ok(())
+}
+
+/// Copy the attributes of local `source` onto `target`: mode, timestamps
+/// and ownership with `preserve`, extended attributes and ACLs with
+/// `xattrs`. An explicit `mode` (-m) overrides the preserved mode bits.
+pub fn copy_attributes(
+    source: &Path,
+    target: &Path,
+    preserve: bool,
+    mode: Option<u32>,
+    xattrs: bool,
+) -> Result<()> {
+    if preserve {
+        apply_metadata(target, &get_metadata(source)?)?;
+    }
+    if let Some(mode) = mode {
+        set_mode(target, mode)?;
+    }
+    // After the mode, which would otherwise rewrite the ACL mask
+    if xattrs {
+        apply_xattrs(target, &read_xattrs(source)?)?;
+    }
+    Ok(())
+}
+
+/// Whether an extended attribute is transferred: user attributes, POSIX
+/// ACLs, and trusted attributes when running privileged
+fn xattr_transferable(name: &str) -> bool {
+    name.starts_with("user.")
+        || name == "system.posix_acl_access"
+        || name == "system.posix_acl_default"
+        || (name.starts_with("trusted.") && is_privileged())
+}
+
+/// Read the transferable extended attributes of `path`. Filesystems
+/// without xattr support have none.
+pub fn read_xattrs(path: &Path) -> Result<Vec<(String, Vec<u8>)>> {
+    #[cfg(target_os = "linux")]
+    {
+        use crate::platform::linux;
+
+        let names = match linux::list_xattrs(path) {
+            Ok(names) => names,
+            Err(crate::error::BbcprError::Io(e)) if e.raw_os_error() == Some(nix::libc::ENOTSUP) => {
+                return Ok(Vec::new());
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut xattrs = Vec::new();
+        for name in names.into_iter().filter(|name| xattr_transferable(name)) {
+            if let Some(value) = linux::get_xattr(path, &name)? {
+                xattrs.push((name, value));
+            }
+        }
+        Ok(xattrs)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (path, xattr_transferable);
+        Ok(Vec::new())
+    }
+}
+
+/// Make the transferable extended attributes of `path` match `xattrs`.
+///
+/// Attributes the target filesystem rejects are logged as warnings rather
+/// than failing the transfer; only failing to list them is an error.
+pub fn apply_xattrs(path: &Path, xattrs: &[(String, Vec<u8>)]) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use crate::platform::linux;
+
+        // Drop attributes the source no longer has
+        let existing = linux::list_xattrs(path).unwrap_or_default();
+        for name in existing.iter().filter(|name| xattr_transferable(name)) {
+            if !xattrs.iter().any(|(wanted, _)| wanted == name) {
+                if let Err(e) = linux::remove_xattr(path, name) {
+                    tracing::warn!("Cannot remove extended attribute {} from {:?}: {}", name, path, e);
+                }
+            }
+        }
+
+        for (name, value) in xattrs {
+            if !xattr_transferable(name) {
+                tracing::warn!("Not setting extended attribute {} on {:?}: namespace not transferred", name, path);
+                continue;
+            }
+            if let Err(e) = linux::set_xattr(path, name, value) {
+                tracing::warn!("Cannot set extended attribute {} on {:?}: {}", name, path, e);
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        if !xattrs.is_empty() {
+            tracing::warn!("Extended attributes are not supported here; {} skipped on {:?}", xattrs.len(), path);
+        }
+        Ok(())
+    }
+}
+
+/// Create a symbolic link at `link` pointing to `target`
+pub fn create_symlink(target: &Path, link: &Path) -> Result<()> {
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(target, link)?;
+
     #[cfg(windows)]
+    std::os::windows::fs::symlink_file(target, link)?;
+
+    Ok(())
+}
+
+/// Recreate a FIFO or device node. Device nodes need privileges.
+pub fn create_special(path: &Path, mode: u32, rdev: u64) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        crate::platform::linux::make_node(path, mode, rdev)
+    }
+
+    #[cfg(not(target_os = "linux"))]
     {
-        use std::os::windows::fs::MetadataExt;
-        Ok(FileMetadata {
-            size: metadata.len(),
-            modified: metadata.last_write_time(),
-            mode: 0o644, // Default permissions on Windows
-            uid: 0,
-            gid: 0,
-        })
+        let _ = (mode, rdev);
+        Err(crate::error::BbcprError::Unsupported(format!(
+            "Creating special file {:?} on this platform", path
+        )))
     }
-}
\ No newline at end of file
+}
+
+/// Whether an `st_mode` describes a character or block device
+pub fn is_device(mode: u32) -> bool {
+    const S_IFMT: u32 = 0o170000;
+    const S_IFCHR: u32 = 0o020000;
+    const S_IFBLK: u32 = 0o060000;
+    matches!(mode & S_IFMT, S_IFCHR | S_IFBLK)
+}
+
+/// Whether this process may change file ownership
+pub fn is_privileged() -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        crate::platform::linux::is_privileged()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        false
+    }
+}
+
+/// Check whether a process with the given PID exists on this host.
+///
+/// On platforms where this cannot be determined the process is assumed
+/// to be alive, so callers never discard state that may still be in use.
+pub fn process_alive(pid: u32) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        crate::platform::linux::process_alive(pid)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = pid;
+        true
+    }
+}
+
+/// Get the name of this host
+pub fn hostname() -> String {
+    #[cfg(target_os = "linux")]
+    {
+        crate::platform::linux::hostname()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        std::env::var("HOSTNAME")
+            .or_else(|_| std::env::var("COMPUTERNAME"))
+            .unwrap_or_else(|_| "localhost".to_string())
+    }
+}
+
+/// Preallocate `len` bytes for `file` and set its length to exactly `len`.
+///
+/// On Linux the space is reserved with fallocate(2), so out-of-space errors
+/// surface before any data is written and the file is laid out contiguously
+/// where the filesystem allows. Elsewhere, or when fallocate is unsupported,
+/// the length is only set. Either way a previously longer file is truncated.
+pub fn preallocate(file: &std::fs::File, len: u64) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        if !crate::platform::linux::fallocate_file(file, len)? {
+            tracing::debug!("fallocate not supported, falling back to set_len");
+        }
+    }
+
+    file.set_len(len)?;
+    Ok(())
+}
+
+/// The data extents of `file` within `range`; the gaps between them are
+/// holes. Where holes cannot be detected the whole range is one extent.
+pub fn data_extents(file: &std::fs::File, range: std::ops::Range<u64>) -> Result<Vec<std::ops::Range<u64>>> {
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(extents) = crate::platform::linux::data_extents(file, range.start, range.end)? {
+            return Ok(extents.into_iter().map(|(start, end)| start..end).collect());
+        }
+    }
+
+    let _ = file;
+    Ok(if range.is_empty() { Vec::new() } else { vec![range] })
+}
+
+/// Make `len` bytes of `file` at `offset` read as zeros, deallocating them
+/// where the filesystem supports it and writing zeros otherwise
+pub fn punch_hole(file: &std::fs::File, offset: u64, len: u64) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        if crate::platform::linux::punch_hole(file, offset, len)? {
+            return Ok(());
+        }
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileExt;
+
+        let zeros = vec![0u8; len.min(1024 * 1024) as usize];
+        let mut written = 0;
+        while written < len {
+            let n = zeros.len().min((len - written) as usize);
+            file.write_all_at(&zeros[..n], offset + written)?;
+            written += n as u64;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = (file, offset, len);
+        Err(crate::error::BbcprError::Unsupported("Writing holes on this platform".to_string()))
+    }
+}
+
+/// Free space available to unprivileged users on the filesystem holding
+/// `path`. If `path` does not exist yet its nearest existing ancestor is used.
+pub fn available_space(path: &Path) -> Result<u64> {
+    let existing = path.ancestors()
+        .find(|p| !p.as_os_str().is_empty() && p.exists())
+        .unwrap_or_else(|| Path::new("."));
+
+    #[cfg(target_os = "linux")]
+    {
+        crate::platform::linux::LinuxFileSystem::get_available_space(existing)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        Err(crate::error::BbcprError::Unsupported(format!(
+            "Free space query for {:?} on this platform", existing
+        )))
+    }
+}
@@ -0,0 +1,228 @@
+// Linux-specific implementations
+
+use nix::errno::Errno;
+use nix::fcntl::{fallocate, FallocateFlags};
+use nix::sys::signal::kill;
+use nix::sys::stat::{mknod, Mode, SFlag};
+use nix::sys::statvfs::statvfs;
+use nix::unistd::{geteuid, gethostname, lseek, Pid, Whence};
+use std::ffi::CString;
+use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use crate::error::{BbcprError, Result};
+use crate::platform::common::FileSystem;
+
+pub struct LinuxFileSystem;
+
+impl FileSystem for LinuxFileSystem {
+    fn get_file_size(path: &Path) -> Result<u64> {
+        Ok(std::fs::metadata(path)?.len())
+    }
+
+    fn get_available_space(path: &Path) -> Result<u64> {
+        let stat = statvfs(path)
+            .map_err(|e| BbcprError::Platform(format!("statvfs {:?} failed: {}", path, e)))?;
+        Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+    }
+
+    fn set_permissions(path: &Path, mode: u32) -> Result<()> {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        Ok(())
+    }
+
+    fn sync_file(path: &Path) -> Result<()> {
+        File::open(path)?.sync_all()?;
+        Ok(())
+    }
+
+    fn get_block_size(path: &Path) -> Result<u64> {
+        let stat = statvfs(path)
+            .map_err(|e| BbcprError::Platform(format!("statvfs {:?} failed: {}", path, e)))?;
+        Ok(stat.block_size() as u64)
+    }
+}
+
+/// Check whether a process with the given PID exists on this host
+pub fn process_alive(pid: u32) -> bool {
+    match kill(Pid::from_raw(pid as i32), None) {
+        Ok(()) => true,
+        // The process exists but belongs to another user
+        Err(Errno::EPERM) => true,
+        Err(_) => false,
+    }
+}
+
+/// Get the name of this host
+pub fn hostname() -> String {
+    gethostname()
+        .ok()
+        .and_then(|name| name.into_string().ok())
+        .unwrap_or_else(|| "localhost".to_string())
+}
+
+/// Reserve `len` bytes for `file` with fallocate(2).
+///
+/// Returns `Ok(false)` when the filesystem does not support preallocation,
+/// so the caller can fall back to a plain `set_len`.
+pub fn fallocate_file(file: &File, len: u64) -> Result<bool> {
+    if len == 0 {
+        return Ok(true);
+    }
+
+    match fallocate(file.as_raw_fd(), FallocateFlags::empty(), 0, len as nix::libc::off_t) {
+        Ok(()) => Ok(true),
+        Err(Errno::EOPNOTSUPP) | Err(Errno::ENOSYS) | Err(Errno::EINVAL) => Ok(false),
+        Err(Errno::ENOSPC) => Err(BbcprError::Platform(format!(
+            "Not enough space to preallocate {} bytes", len
+        ))),
+        Err(e) => Err(BbcprError::Io(e.into())),
+    }
+}
+
+/// The data extents of `file` within `start..end`, found with
+/// SEEK_DATA/SEEK_HOLE. Everything between them is a hole.
+///
+/// Returns `Ok(None)` when the filesystem cannot report holes.
+pub fn data_extents(file: &File, start: u64, end: u64) -> Result<Option<Vec<(u64, u64)>>> {
+    let fd = file.as_raw_fd();
+    let mut extents = Vec::new();
+    let mut offset = start;
+
+    while offset < end {
+        let data = match lseek(fd, offset as nix::libc::off_t, Whence::SeekData) {
+            Ok(data) => data as u64,
+            // No data past this offset: the rest is a hole
+            Err(Errno::ENXIO) => break,
+            Err(Errno::EINVAL) | Err(Errno::EOPNOTSUPP) => return Ok(None),
+            Err(e) => return Err(BbcprError::Io(e.into())),
+        };
+        if data >= end {
+            break;
+        }
+
+        let hole = match lseek(fd, data as nix::libc::off_t, Whence::SeekHole) {
+            Ok(hole) => (hole as u64).min(end),
+            Err(Errno::ENXIO) => end,
+            Err(e) => return Err(BbcprError::Io(e.into())),
+        };
+        extents.push((data, hole));
+        offset = hole;
+    }
+
+    Ok(Some(extents))
+}
+
+/// Deallocate `len` bytes of `file` at `offset`, keeping its size.
+///
+/// Returns `Ok(false)` when the filesystem cannot punch holes.
+pub fn punch_hole(file: &File, offset: u64, len: u64) -> Result<bool> {
+    if len == 0 {
+        return Ok(true);
+    }
+
+    let flags = FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE;
+    match fallocate(file.as_raw_fd(), flags, offset as nix::libc::off_t, len as nix::libc::off_t) {
+        Ok(()) => Ok(true),
+        Err(Errno::EOPNOTSUPP) | Err(Errno::ENOSYS) | Err(Errno::EINVAL) => Ok(false),
+        Err(e) => Err(BbcprError::Io(e.into())),
+    }
+}
+
+/// Whether this process may change file ownership
+pub fn is_privileged() -> bool {
+    geteuid().is_root()
+}
+
+fn c_path(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| BbcprError::Platform(format!("Path {:?} contains a NUL byte", path)))
+}
+
+fn c_name(name: &str) -> Result<CString> {
+    CString::new(name)
+        .map_err(|_| BbcprError::Platform(format!("Attribute name {:?} contains a NUL byte", name)))
+}
+
+/// Call a size-probing xattr function: first with an empty buffer to learn
+/// the size, then with a buffer of that size, retrying if it grew meanwhile
+fn read_sized(mut call: impl FnMut(*mut nix::libc::c_void, usize) -> isize) -> std::io::Result<Vec<u8>> {
+    loop {
+        let size = call(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let mut buffer = vec![0u8; size as usize];
+        let read = call(buffer.as_mut_ptr().cast(), buffer.len());
+        if read >= 0 {
+            buffer.truncate(read as usize);
+            return Ok(buffer);
+        }
+
+        let error = std::io::Error::last_os_error();
+        if error.raw_os_error() != Some(nix::libc::ERANGE) {
+            return Err(error);
+        }
+    }
+}
+
+/// Names of the extended attributes of `path`
+pub fn list_xattrs(path: &Path) -> Result<Vec<String>> {
+    let c_path = c_path(path)?;
+    let names = read_sized(|buffer, size| unsafe {
+        nix::libc::listxattr(c_path.as_ptr(), buffer.cast(), size)
+    })?;
+
+    Ok(names.split(|&b| b == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .collect())
+}
+
+/// Value of the extended attribute `name` of `path`, or `None` if it is not set
+pub fn get_xattr(path: &Path, name: &str) -> Result<Option<Vec<u8>>> {
+    let (c_path, c_name) = (c_path(path)?, c_name(name)?);
+    match read_sized(|buffer, size| unsafe {
+        nix::libc::getxattr(c_path.as_ptr(), c_name.as_ptr(), buffer, size)
+    }) {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.raw_os_error() == Some(nix::libc::ENODATA) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn set_xattr(path: &Path, name: &str, value: &[u8]) -> Result<()> {
+    let (c_path, c_name) = (c_path(path)?, c_name(name)?);
+    let result = unsafe {
+        nix::libc::setxattr(c_path.as_ptr(), c_name.as_ptr(), value.as_ptr().cast(), value.len(), 0)
+    };
+    if result < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+pub fn remove_xattr(path: &Path, name: &str) -> Result<()> {
+    let (c_path, c_name) = (c_path(path)?, c_name(name)?);
+    let result = unsafe { nix::libc::removexattr(c_path.as_ptr(), c_name.as_ptr()) };
+    if result < 0 {
+        let error = std::io::Error::last_os_error();
+        if error.raw_os_error() != Some(nix::libc::ENODATA) {
+            return Err(error.into());
+        }
+    }
+    Ok(())
+}
+
+/// Create a FIFO or device node at `path`. `mode` holds the file type and
+/// permission bits as in `st_mode`; `rdev` is the device number.
+pub fn make_node(path: &Path, mode: u32, rdev: u64) -> Result<()> {
+    let kind = SFlag::from_bits_truncate(mode & SFlag::S_IFMT.bits());
+    let permissions = Mode::from_bits_truncate(mode & 0o7777);
+    mknod(path, kind, permissions, rdev as nix::libc::dev_t)
+        .map_err(|e| BbcprError::Io(e.into()))
+}
@@ -0,0 +1,884 @@
+// Remote agents for third-party and single-remote transfers
+//
+// For `hostA:/f hostB:/g` the controller starts one agent on each host
+// over SSH. The sending agent streams the file as a `FileInfo` record
+// followed by `DataChunk` records and `Complete`. The receiving agent
+// writes it and reports `Progress` records, then `Complete` or `Error`,
+// on its standard output, which the controller reads. In direct mode the
+// receiver listens and announces its port in a `Handshake` record, and
+// the sender opens one connection per stream and sends a `Part` of the
+// file over each. Every stream opens with a `Handshake` record carrying
+// the transfer's token, which the controller generated and passed to both
+// agents; the listener refuses streams without it. It tells the controller
+// on its stdout whether it got through; if not, the controller starts one
+// sender per part and carries the parts to the receiver's port over SSH.
+// In relay mode both agents use their stdio and the controller passes the
+// data along. Before any of this, the controller may start a query agent
+// on each host to learn the size of the source and the free space at the
+// destination. For `f host:/g` and `host:/f g` this host is the other end
+// (see `remote`).
+
+use anyhow::{Context, Result};
+use bytes::{Bytes, BytesMut};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+
+use crate::network::endpoint;
+use crate::network::protocol::{self, EntryKind, FileInfo, MessageType, ProtocolMessage};
+use crate::platform;
+use crate::transfer::{batch, space};
+
+/// How long a listening agent waits for the sending agent to connect
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long a sending agent tries to reach the listening agent before
+/// the controller falls back to tunnelling through SSH
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a parallel receive checks whether to report progress
+const REPORT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Bytes written between progress reports
+const PROGRESS_INTERVAL: u64 = 4 * 1024 * 1024;
+
+/// What an agent does (--agent)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AgentRole {
+    /// Stream a file to stdout, or to a listening agent
+    Send,
+    /// Write a file streamed to stdin
+    Receive,
+    /// Announce a port and write a file streamed to it
+    Listen,
+    /// Answer space, checksum and file queries read from stdin
+    Query,
+}
+
+impl AgentRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AgentRole::Send => "send",
+            AgentRole::Receive => "receive",
+            AgentRole::Listen => "listen",
+            AgentRole::Query => "query",
+        }
+    }
+}
+
+impl FromStr for AgentRole {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "send" => Ok(AgentRole::Send),
+            "receive" => Ok(AgentRole::Receive),
+            "listen" => Ok(AgentRole::Listen),
+            "query" => Ok(AgentRole::Query),
+            other => anyhow::bail!("Invalid agent role {:?}: expected send, receive, listen or query", other),
+        }
+    }
+}
+
+/// One end of a third-party transfer, run on a remote host
+pub struct Agent {
+    role: AgentRole,
+    path: PathBuf,
+    peer: Option<String>,
+    port_range: Option<(u16, u16)>,
+    token: Option<String>,
+    loopback: bool,
+    streams: usize,
+    part: Option<(usize, usize)>,
+    chunk_size: usize,
+    force: bool,
+    partial_suffix: Option<String>,
+    preserve: bool,
+    mode: Option<u32>,
+    xattrs: bool,
+}
+
+impl Agent {
+    pub fn new(role: AgentRole, path: PathBuf) -> Self {
+        Self {
+            role,
+            path,
+            peer: None,
+            port_range: None,
+            token: None,
+            loopback: false,
+            streams: 1,
+            part: None,
+            chunk_size: crate::DEFAULT_BUFFER_SIZE,
+            force: false,
+            partial_suffix: None,
+            preserve: false,
+            mode: None,
+            xattrs: false,
+        }
+    }
+
+    /// Address of the listening agent a sender connects to
+    pub fn with_peer(mut self, peer: Option<String>) -> Self {
+        self.peer = peer;
+        self
+    }
+
+    /// Ports a listening agent may bind (-Z)
+    pub fn with_port_range(mut self, range: Option<(u16, u16)>) -> Self {
+        self.port_range = range;
+        self
+    }
+
+    /// Secret each stream to a listening agent opens with
+    pub fn with_token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    /// Make a listening agent bind the loopback address, for streams that
+    /// only arrive through SSH tunnels
+    pub fn with_loopback(mut self, loopback: bool) -> Self {
+        self.loopback = loopback;
+        self
+    }
+
+    /// Parallel connections between a sender and a listening agent
+    pub fn with_streams(mut self, streams: usize) -> Self {
+        self.streams = streams.max(1);
+        self
+    }
+
+    /// Send only part `index` of `count` to stdout, for one stream of a
+    /// transfer tunnelled through the controller
+    pub fn with_part(mut self, part: Option<(usize, usize)>) -> Self {
+        self.part = part;
+        self
+    }
+
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    pub fn with_force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    pub fn with_partial_suffix(mut self, suffix: Option<String>) -> Self {
+        self.partial_suffix = suffix;
+        self
+    }
+
+    /// Give the received file the sender's attributes (-p)
+    pub fn with_preserve(mut self, preserve: bool) -> Self {
+        self.preserve = preserve;
+        self
+    }
+
+    /// Permission bits forced onto the received file (-m)
+    pub fn with_mode(mut self, mode: Option<u32>) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Send the file's extended attributes and ACLs along with it (-X)
+    pub fn with_xattrs(mut self, xattrs: bool) -> Self {
+        self.xattrs = xattrs;
+        self
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        self.run_with(tokio::io::stdin(), tokio::io::stdout()).await
+    }
+
+    /// Run with `input` and `output` standing in for stdin and stdout
+    pub async fn run_with<R, W>(&self, input: R, mut output: W) -> Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        match self.role {
+            AgentRole::Send => match (&self.peer, self.part) {
+                (Some(peer), _) => self.send_to(peer, &mut output).await.map(|_| ()),
+                (None, Some((index, count))) => {
+                    self.send_token(&mut output).await?;
+                    send_part(&self.path, output, index, count, self.chunk_size, self.xattrs).await.map(|_| ())
+                }
+                (None, None) => send_file(&self.path, output, self.chunk_size, self.xattrs).await.map(|_| ()),
+            },
+            AgentRole::Receive => {
+                let result = self.receive(input, &mut output).await;
+                finish_report(&mut output, &result).await?;
+                result.map(|_| ())
+            }
+            AgentRole::Listen => {
+                let result = self.listen(&mut output).await;
+                finish_report(&mut output, &result).await?;
+                result.map(|_| ())
+            }
+            AgentRole::Query => serve_queries(input, output).await,
+        }
+    }
+
+    /// Connect every stream to the listening agent, tell the controller
+    /// on `report` whether that worked, then send one part over each
+    async fn send_to<W: AsyncWrite + Unpin>(&self, peer: &str, report: &mut W) -> Result<u64> {
+        let connected = self.connect_streams(peer).await;
+        let message = match &connected {
+            Ok(_) => ProtocolMessage::new(MessageType::Handshake, Bytes::new()),
+            Err(e) => ProtocolMessage::new(MessageType::Error, Bytes::from(format!("{:#}", e))),
+        };
+        protocol::write_message(report, &message).await?;
+        report.flush().await?;
+
+        let streams = connected?;
+        let count = streams.len();
+        let sends = streams.into_iter().enumerate().map(|(index, mut stream)| async move {
+            self.send_token(&mut stream).await?;
+            send_part(&self.path, stream, index, count, self.chunk_size, self.xattrs).await
+        });
+        Ok(futures::future::try_join_all(sends).await?.into_iter().sum())
+    }
+
+    /// Open a stream to the listening agent with the transfer's token
+    async fn send_token<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
+        let token = self.token.as_deref().context("Sending to a listening agent needs a token")?;
+        let handshake = ProtocolMessage::new(MessageType::Handshake, Bytes::copy_from_slice(token.as_bytes()));
+        protocol::write_message(writer, &handshake).await?;
+        Ok(())
+    }
+
+    /// All or nothing: a sender that can't open every stream closes the
+    /// ones it has, and the listener drops them unused
+    async fn connect_streams(&self, peer: &str) -> Result<Vec<TcpStream>> {
+        let mut streams = Vec::with_capacity(self.streams);
+        for _ in 0..self.streams {
+            let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(peer)).await
+                .with_context(|| format!("Timed out connecting to receiving agent at {}", peer))?
+                .with_context(|| format!("Failed to connect to receiving agent at {}", peer))?;
+            let _ = stream.set_nodelay(true);
+            streams.push(stream);
+        }
+        Ok(streams)
+    }
+
+    /// Bind a port, announce it to the controller and receive one part
+    /// from each of the sender's streams
+    async fn listen<W: AsyncWrite + Unpin>(&self, report: &mut W) -> Result<u64> {
+        let token = self.token.as_deref().context("A listening agent needs a token")?;
+        let listener = bind(self.port_range, self.loopback).await?;
+        let port = listener.local_addr()?.port();
+        let handshake = ProtocolMessage::new(MessageType::Handshake, Bytes::copy_from_slice(&port.to_be_bytes()));
+        protocol::write_message(report, &handshake).await?;
+        report.flush().await?;
+
+        let mut parts = Vec::with_capacity(self.streams);
+        while parts.len() < self.streams {
+            let (mut stream, peer) = tokio::time::timeout(ACCEPT_TIMEOUT, listener.accept()).await
+                .context("The sending agent did not connect in time")??;
+            match read_part_header(&mut stream, token).await
+                .with_context(|| format!("Refused a stream from {}", peer))?
+            {
+                Some((info, offset, len)) => parts.push(Part { stream, info, offset, len }),
+                None => debug!("Dropped an unused stream from {}", peer),
+            }
+        }
+        let info = check_parts(&mut parts)?;
+        info!("Receiving {:?} over {} streams", self.path, parts.len());
+
+        let (target, partial) = self.prepare(&info).await?;
+        let result = async {
+            let received = receive_parts(parts, &partial, &info, report).await?;
+            self.apply_metadata(&partial, &info).await?;
+            Ok(received)
+        }.await;
+        commit(&target, &partial, result).await
+    }
+
+    /// Write the file streamed by `reader` to the agent's path, reporting
+    /// progress to `report`
+    async fn receive<R, W>(&self, mut reader: R, report: &mut W) -> Result<u64>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let message = protocol::read_message(&mut reader).await?
+            .context("Sending agent closed the stream before describing the file")?;
+        let info = file_info(&message)?;
+
+        let (target, partial) = self.prepare(&info).await?;
+        let result = async {
+            let received = receive_data(&mut reader, &partial, &info, report).await?;
+            self.apply_metadata(&partial, &info).await?;
+            Ok(received)
+        }.await;
+        commit(&target, &partial, result).await
+    }
+
+    /// Check that `info` may be written and return where it goes: the
+    /// agent's path, or a file of the same name inside it when that is a
+    /// directory, and the partial file to write it to
+    async fn prepare(&self, info: &FileInfo) -> Result<(PathBuf, PathBuf)> {
+        if info.kind != EntryKind::File {
+            anyhow::bail!("Remote transfers copy single files; {} is a directory", info.path);
+        }
+
+        let target = if tokio::fs::metadata(&self.path).await.is_ok_and(|metadata| metadata.is_dir()) {
+            let name = Path::new(&info.path);
+            if !matches!(name.components().collect::<Vec<_>>().as_slice(), [Component::Normal(_)]) {
+                anyhow::bail!("Refusing unsafe file name {:?}", info.path);
+            }
+            self.path.join(name)
+        } else {
+            self.path.clone()
+        };
+
+        if !self.force && tokio::fs::try_exists(&target).await? {
+            anyhow::bail!("Destination {:?} already exists; use -f/--force to replace it", target);
+        }
+        let partial = super::partial_path(&target, self.partial_suffix.as_deref())?;
+        Ok((target, partial))
+    }
+
+    /// Apply the attributes `info` carries to the complete `partial` file,
+    /// before it is moved into place
+    async fn apply_metadata(&self, partial: &Path, info: &FileInfo) -> Result<()> {
+        if !self.preserve && self.mode.is_none() && info.xattrs.is_none() {
+            return Ok(());
+        }
+        let (partial, info, preserve, mode) = (partial.to_path_buf(), info.clone(), self.preserve, self.mode);
+        tokio::task::spawn_blocking(move || batch::apply_metadata(&partial, &info, preserve, mode))
+            .await
+            .context("Metadata task panicked")?
+            .map_err(|(_, e)| anyhow::anyhow!(e))
+    }
+}
+
+/// Move a complete partial file into place, or remove a failed one
+async fn commit(target: &Path, partial: &Path, result: Result<u64>) -> Result<u64> {
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(partial).await;
+    }
+    let received = result?;
+
+    if partial != target {
+        tokio::fs::rename(partial, target).await
+            .with_context(|| format!("Failed to rename {:?} to {:?}", partial, target))?;
+    }
+    debug!("Received {} bytes into {:?}", received, target);
+    Ok(received)
+}
+
+/// One stream of a parallel receive
+struct Part {
+    stream: TcpStream,
+    info: FileInfo,
+    offset: u64,
+    len: u64,
+}
+
+/// The byte range part `index` of `count` covers in a file of `size` bytes
+pub fn part_range(size: u64, index: usize, count: usize) -> (u64, u64) {
+    let share = size / count as u64;
+    let offset = share * index as u64;
+    let len = if index + 1 == count { size - offset } else { share };
+    (offset, len)
+}
+
+/// A random token for the streams of one transfer
+pub fn new_token() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|e| anyhow::anyhow!("Failed to generate a transfer token: {}", e))?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Parse a hidden `--agent-part INDEX/COUNT`
+pub fn parse_part(value: &str) -> Result<(usize, usize)> {
+    let parsed = value.split_once('/')
+        .and_then(|(index, count)| Some((index.parse().ok()?, count.parse().ok()?)));
+    match parsed {
+        Some((index, count)) if index < count => Ok((index, count)),
+        _ => anyhow::bail!("Invalid agent part {:?}: expected INDEX/COUNT", value),
+    }
+}
+
+fn file_info(message: &ProtocolMessage) -> Result<FileInfo> {
+    match message.message_type {
+        MessageType::FileInfo => Ok(FileInfo::from_message(message)?),
+        MessageType::Error => anyhow::bail!("Sending agent failed: {}", String::from_utf8_lossy(&message.data)),
+        other => anyhow::bail!("Expected a file description, got {:?}", other),
+    }
+}
+
+/// The file description and part record a stream opens with, or `None`
+/// for a stream closed unused. The stream must first present `token`.
+async fn read_part_header<R: AsyncRead + Unpin>(reader: &mut R, token: &str) -> Result<Option<(FileInfo, u64, u64)>> {
+    let Some(message) = protocol::read_message(reader).await? else {
+        return Ok(None);
+    };
+    if message.message_type != MessageType::Handshake || message.data != token.as_bytes() {
+        anyhow::bail!("The stream did not present the transfer's token");
+    }
+
+    let message = protocol::read_message(reader).await?
+        .context("Sending agent closed a stream before describing the file")?;
+    let info = file_info(&message)?;
+    let message = protocol::read_message(reader).await?
+        .context("Sending agent closed a stream before naming its part")?;
+    if message.message_type != MessageType::Part {
+        anyhow::bail!("Expected a part record, got {:?}", message.message_type);
+    }
+    let (offset, len) = protocol::part_values(&message)?;
+    Ok(Some((info, offset, len)))
+}
+
+/// Check that the parts describe one file and cover it exactly, and
+/// return its description
+fn check_parts(parts: &mut [Part]) -> Result<FileInfo> {
+    parts.sort_by_key(|part| part.offset);
+    let info = parts[0].info.clone();
+    let mut end = 0;
+    for part in parts.iter() {
+        if part.info.path != info.path || part.info.size != info.size {
+            anyhow::bail!("Streams describe different files: {} and {}", info.path, part.info.path);
+        }
+        if part.offset != end {
+            anyhow::bail!("Streams of {} leave a gap or overlap at byte {}", info.path, end);
+        }
+        end += part.len;
+    }
+    if end != info.size {
+        anyhow::bail!("Streams of {} cover {} of {} bytes", info.path, end, info.size);
+    }
+    Ok(info)
+}
+
+/// Answer each query read from `input` on `output`, until `input` closes
+async fn serve_queries<R, W>(mut input: R, mut output: W) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    while let Some(query) = protocol::read_message(&mut input).await? {
+        // The controller passes remote paths through unexpanded
+        let path = endpoint::expand_home(&String::from_utf8_lossy(&query.data));
+        let query = ProtocolMessage::new(query.message_type, Bytes::from(path.to_string_lossy().into_owned()));
+
+        let reply = match query.message_type {
+            MessageType::SpaceQuery => space::answer_space_query(&query),
+            MessageType::InfoQuery => answer_info_query(&path).await,
+            other => ProtocolMessage::new(MessageType::Error, Bytes::from(format!("Unexpected {:?} query", other))),
+        };
+        protocol::write_message(&mut output, &reply).await?;
+        output.flush().await?;
+    }
+    Ok(())
+}
+
+/// Describe the file at `path`, as a sending agent would
+async fn answer_info_query(path: &Path) -> ProtocolMessage {
+    let described = async {
+        let metadata = tokio::fs::metadata(path).await
+            .with_context(|| format!("Failed to read {:?}", path))?;
+        let name = path.file_name().map(Path::new).unwrap_or(path);
+        Ok::<_, anyhow::Error>(batch::file_info(name, &metadata).to_message()?)
+    };
+    match described.await {
+        Ok(info) => info,
+        Err(e) => ProtocolMessage::new(MessageType::Error, Bytes::from(format!("{:#}", e))),
+    }
+}
+
+/// Ask a query agent, through its `queries` and `replies` streams, to
+/// describe the file at `path`
+pub async fn query_file_info<R, W>(replies: &mut R, queries: &mut W, path: &str) -> Result<FileInfo>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let query = ProtocolMessage::new(MessageType::InfoQuery, Bytes::copy_from_slice(path.as_bytes()));
+    protocol::write_message(queries, &query).await?;
+    queries.flush().await?;
+
+    let reply = protocol::read_message(replies).await?
+        .context("Query agent exited before describing the file")?;
+    match reply.message_type {
+        MessageType::FileInfo => Ok(FileInfo::from_message(&reply)?),
+        MessageType::Error => anyhow::bail!("Failed to query {}: {}", path, String::from_utf8_lossy(&reply.data)),
+        other => anyhow::bail!("Unexpected {:?} reply to a file query", other),
+    }
+}
+
+/// Stream the file at `path` to `writer`, with its extended attributes
+/// when `xattrs` is set
+pub async fn send_file<W: AsyncWrite + Unpin>(path: &Path, mut writer: W, chunk_size: usize, xattrs: bool) -> Result<u64> {
+    let (mut file, metadata) = open_source(path).await?;
+    protocol::write_message(&mut writer, &describe(path, &metadata, xattrs).await?.to_message()?).await?;
+
+    let sent = send_chunks(&mut file, &mut writer, chunk_size).await
+        .with_context(|| format!("Failed to read {:?}", path))?;
+    debug!("Sent {} bytes of {:?}", sent, path);
+    Ok(sent)
+}
+
+/// Stream part `index` of `count` of the file at `path` to `writer`
+pub async fn send_part<W: AsyncWrite + Unpin>(
+    path: &Path,
+    mut writer: W,
+    index: usize,
+    count: usize,
+    chunk_size: usize,
+    xattrs: bool,
+) -> Result<u64> {
+    let (mut file, metadata) = open_source(path).await?;
+    protocol::write_message(&mut writer, &describe(path, &metadata, xattrs).await?.to_message()?).await?;
+
+    let (offset, len) = part_range(metadata.len(), index, count);
+    protocol::write_message(&mut writer, &protocol::part_message(offset, len)).await?;
+    file.seek(std::io::SeekFrom::Start(offset)).await?;
+
+    let sent = send_chunks(&mut file.take(len), &mut writer, chunk_size).await
+        .with_context(|| format!("Failed to read {:?}", path))?;
+    if sent != len {
+        anyhow::bail!("{:?} shrank while it was being sent", path);
+    }
+    debug!("Sent bytes {}..{} of {:?}", offset, offset + len, path);
+    Ok(sent)
+}
+
+/// Describe the file at `path` by name, with its extended attributes if requested
+async fn describe(path: &Path, metadata: &std::fs::Metadata, xattrs: bool) -> Result<FileInfo> {
+    let name = path.file_name().map(Path::new).unwrap_or(path);
+    let mut info = batch::file_info(name, metadata);
+    if xattrs {
+        let source = path.to_path_buf();
+        let xattrs = tokio::task::spawn_blocking(move || platform::read_xattrs(&source))
+            .await
+            .context("Extended attribute task panicked")?
+            .with_context(|| format!("Failed to read extended attributes of {:?}", path))?;
+        info.xattrs = Some(xattrs);
+    }
+    Ok(info)
+}
+
+async fn open_source(path: &Path) -> Result<(tokio::fs::File, std::fs::Metadata)> {
+    let file = tokio::fs::File::open(path).await
+        .with_context(|| format!("Failed to open {:?}", path))?;
+    let metadata = file.metadata().await?;
+    if !metadata.is_file() {
+        anyhow::bail!("Remote transfers copy single files; {:?} is not one", path);
+    }
+    Ok((file, metadata))
+}
+
+/// Send everything `reader` yields as `DataChunk` records, then `Complete`
+async fn send_chunks<R, W>(reader: &mut R, writer: &mut W, chunk_size: usize) -> Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut sent = 0u64;
+    loop {
+        let mut chunk = BytesMut::zeroed(chunk_size);
+        let n = reader.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        chunk.truncate(n);
+        protocol::write_message(writer, &ProtocolMessage::new(MessageType::DataChunk, chunk.freeze())).await?;
+        sent += n as u64;
+    }
+
+    protocol::write_message(writer, &ProtocolMessage::new(MessageType::Complete, Bytes::new())).await?;
+    writer.flush().await?;
+    writer.shutdown().await?;
+    Ok(sent)
+}
+
+async fn receive_data<R, W>(reader: &mut R, partial: &Path, info: &FileInfo, report: &mut W) -> Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut file = tokio::fs::File::create(partial).await
+        .with_context(|| format!("Failed to create {:?}", partial))?;
+    let mut received = 0u64;
+    let mut reported = 0u64;
+
+    loop {
+        let message = protocol::read_message(reader).await?
+            .context("Sending agent closed the stream early")?;
+        match message.message_type {
+            MessageType::DataChunk => {
+                file.write_all(&message.data).await
+                    .with_context(|| format!("Failed to write {:?}", partial))?;
+                received += message.data.len() as u64;
+            }
+            MessageType::Hole => {
+                let len = protocol::hole_length(&message)?;
+                file.seek(std::io::SeekFrom::Current(len as i64)).await?;
+                received += len;
+            }
+            MessageType::Complete => break,
+            MessageType::Error => {
+                anyhow::bail!("Sending agent failed: {}", String::from_utf8_lossy(&message.data));
+            }
+            other => anyhow::bail!("Unexpected {:?} record from the sending agent", other),
+        }
+
+        if received > info.size {
+            anyhow::bail!("{} is longer than announced", info.path);
+        }
+        if received - reported >= PROGRESS_INTERVAL {
+            reported = received;
+            protocol::write_message(report, &protocol::progress_message(received, info.size)).await?;
+            report.flush().await?;
+        }
+    }
+
+    if received != info.size {
+        anyhow::bail!("{} ended after {} of {} bytes", info.path, received, info.size);
+    }
+    // Trailing holes are covered by the length
+    file.set_len(received).await?;
+    file.sync_all().await?;
+
+    protocol::write_message(report, &protocol::progress_message(received, info.size)).await?;
+    Ok(received)
+}
+
+/// Write each part at its offset in the partial file, reporting the
+/// combined progress to `report`
+async fn receive_parts<W: AsyncWrite + Unpin>(parts: Vec<Part>, partial: &Path, info: &FileInfo, report: &mut W) -> Result<u64> {
+    let file = tokio::fs::File::create(partial).await
+        .with_context(|| format!("Failed to create {:?}", partial))?;
+    file.set_len(info.size).await?;
+
+    let received = AtomicU64::new(0);
+    let writes = futures::future::try_join_all(parts.into_iter().map(|part| receive_part(part, partial, &received)));
+    tokio::pin!(writes);
+
+    let mut reported = 0u64;
+    loop {
+        tokio::select! {
+            result = &mut writes => {
+                result?;
+                break;
+            }
+            _ = tokio::time::sleep(REPORT_INTERVAL) => {
+                let now = received.load(Ordering::Relaxed);
+                if now - reported >= PROGRESS_INTERVAL {
+                    reported = now;
+                    protocol::write_message(report, &protocol::progress_message(now, info.size)).await?;
+                    report.flush().await?;
+                }
+            }
+        }
+    }
+    file.sync_all().await?;
+
+    protocol::write_message(report, &protocol::progress_message(info.size, info.size)).await?;
+    Ok(info.size)
+}
+
+/// Write one stream's part, adding what it writes to `received`
+async fn receive_part(mut part: Part, partial: &Path, received: &AtomicU64) -> Result<()> {
+    // Its own handle, as a cloned one would share the file position
+    let mut file = tokio::fs::OpenOptions::new().write(true).open(partial).await
+        .with_context(|| format!("Failed to open {:?}", partial))?;
+    file.seek(std::io::SeekFrom::Start(part.offset)).await?;
+    let mut written = 0u64;
+
+    loop {
+        let message = protocol::read_message(&mut part.stream).await?
+            .context("Sending agent closed a stream early")?;
+        let len = match message.message_type {
+            MessageType::DataChunk => {
+                file.write_all(&message.data).await
+                    .with_context(|| format!("Failed to write {:?}", partial))?;
+                message.data.len() as u64
+            }
+            MessageType::Hole => {
+                let len = protocol::hole_length(&message)?;
+                file.seek(std::io::SeekFrom::Current(len as i64)).await?;
+                len
+            }
+            MessageType::Complete => break,
+            MessageType::Error => {
+                anyhow::bail!("Sending agent failed: {}", String::from_utf8_lossy(&message.data));
+            }
+            other => anyhow::bail!("Unexpected {:?} record from the sending agent", other),
+        };
+        written += len;
+        received.fetch_add(len, Ordering::Relaxed);
+        if written > part.len {
+            anyhow::bail!("The part of {} at byte {} is longer than announced", part.info.path, part.offset);
+        }
+    }
+
+    if written != part.len {
+        anyhow::bail!("The part of {} at byte {} ended after {} of {} bytes", part.info.path, part.offset, written, part.len);
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+/// Tell the controller how the receive ended
+async fn finish_report<W: AsyncWrite + Unpin>(report: &mut W, result: &Result<u64>) -> Result<()> {
+    let message = match result {
+        Ok(_) => ProtocolMessage::new(MessageType::Complete, Bytes::new()),
+        Err(e) => ProtocolMessage::new(MessageType::Error, Bytes::from(format!("{:#}", e))),
+    };
+    protocol::write_message(report, &message).await?;
+    report.flush().await?;
+    Ok(())
+}
+
+/// Listen on the first free port of `range`, or any port, on every
+/// address or only on `loopback`
+async fn bind(range: Option<(u16, u16)>, loopback: bool) -> Result<TcpListener> {
+    let address = if loopback { "127.0.0.1" } else { "0.0.0.0" };
+    let Some((first, last)) = range else {
+        return Ok(TcpListener::bind((address, 0)).await?);
+    };
+
+    for port in first..=last {
+        if let Ok(listener) = TcpListener::bind((address, port)).await {
+            return Ok(listener);
+        }
+    }
+    anyhow::bail!("No free port between {} and {}", first, last)
+}
+
+/// Parse a `-Z PORT1:PORT2` range
+pub fn parse_port_range(value: &str) -> Result<(u16, u16)> {
+    let (first, last) = value.split_once(':').unwrap_or((value, value));
+    let first: u16 = first.parse().with_context(|| format!("Invalid port range {:?}", value))?;
+    let last: u16 = last.parse().with_context(|| format!("Invalid port range {:?}", value))?;
+    if first > last {
+        anyhow::bail!("Invalid port range {:?}: {} is above {}", value, first, last);
+    }
+    Ok((first, last))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send a file from one agent to a listening one over `streams` connections
+    async fn transfer_over_tcp(streams: usize) {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source.bin");
+        let target = dir.path().join("target.bin");
+        let data: Vec<u8> = (0..5_000_000u32).map(|i| (i % 253) as u8).collect();
+        std::fs::write(&source, &data).unwrap();
+
+        // The listening agent's report stream, as the controller sees it
+        let (mut report_tx, mut report_rx) = tokio::io::duplex(1024);
+        let receiver = Agent::new(AgentRole::Listen, target.clone())
+            .with_token(Some("secret".to_string()))
+            .with_streams(streams)
+            .with_partial_suffix(Some("part".to_string()));
+        let listen = tokio::spawn(async move {
+            let result = receiver.listen(&mut report_tx).await;
+            finish_report(&mut report_tx, &result).await.unwrap();
+            result
+        });
+
+        let handshake = protocol::read_message(&mut report_rx).await.unwrap().unwrap();
+        assert_eq!(handshake.message_type, MessageType::Handshake);
+        let port = u16::from_be_bytes([handshake.data[0], handshake.data[1]]);
+
+        // The sender's own report says it reached the listener
+        let sender = Agent::new(AgentRole::Send, source)
+            .with_token(Some("secret".to_string()))
+            .with_streams(streams);
+        let mut sender_report = Vec::new();
+        sender.send_to(&format!("127.0.0.1:{}", port), &mut sender_report).await.unwrap();
+        let reached = protocol::read_message(&mut sender_report.as_slice()).await.unwrap().unwrap();
+        assert_eq!(reached.message_type, MessageType::Handshake);
+
+        let mut last_progress = 0;
+        loop {
+            let message = protocol::read_message(&mut report_rx).await.unwrap().unwrap();
+            match message.message_type {
+                MessageType::Progress => last_progress = protocol::progress_values(&message).unwrap().0,
+                MessageType::Complete => break,
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert_eq!(last_progress, data.len() as u64);
+        assert_eq!(listen.await.unwrap().unwrap(), data.len() as u64);
+        assert_eq!(std::fs::read(&target).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_agents_over_tcp() {
+        transfer_over_tcp(1).await;
+    }
+
+    #[tokio::test]
+    async fn test_agents_over_parallel_streams() {
+        transfer_over_tcp(3).await;
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_listener_is_reported() {
+        // Bind and drop a listener to find a port nobody is listening on
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let sender = Agent::new(AgentRole::Send, PathBuf::from("/nonexistent"));
+        let mut report = Vec::new();
+
+        assert!(sender.send_to(&format!("127.0.0.1:{}", port), &mut report).await.is_err());
+        let message = protocol::read_message(&mut report.as_slice()).await.unwrap().unwrap();
+        assert_eq!(message.message_type, MessageType::Error);
+    }
+
+    #[tokio::test]
+    async fn test_listener_refuses_wrong_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source.bin");
+        let target = dir.path().join("target.bin");
+        std::fs::write(&source, b"data").unwrap();
+
+        let (mut report_tx, mut report_rx) = tokio::io::duplex(1024);
+        let receiver = Agent::new(AgentRole::Listen, target.clone())
+            .with_token(Some("secret".to_string()))
+            .with_loopback(true);
+        let listen = tokio::spawn(async move { receiver.listen(&mut report_tx).await });
+
+        let handshake = protocol::read_message(&mut report_rx).await.unwrap().unwrap();
+        let port = u16::from_be_bytes([handshake.data[0], handshake.data[1]]);
+        let sender = Agent::new(AgentRole::Send, source).with_token(Some("guess".to_string()));
+        let _ = sender.send_to(&format!("127.0.0.1:{}", port), &mut Vec::new()).await;
+
+        assert!(listen.await.unwrap().is_err());
+        assert!(!target.exists());
+    }
+
+    #[test]
+    fn test_part_ranges() {
+        assert_eq!(part_range(10, 0, 3), (0, 3));
+        assert_eq!(part_range(10, 1, 3), (3, 3));
+        assert_eq!(part_range(10, 2, 3), (6, 4));
+        assert_eq!(part_range(2, 0, 4), (0, 0));
+        assert_eq!(part_range(2, 3, 4), (0, 2));
+        assert_eq!(parse_part("1/4").unwrap(), (1, 4));
+        assert!(parse_part("4/4").is_err());
+        assert!(parse_part("1").is_err());
+    }
+
+    #[test]
+    fn test_parse_port_range() {
+        assert_eq!(parse_port_range("5000:5010").unwrap(), (5000, 5010));
+        assert_eq!(parse_port_range("5000").unwrap(), (5000, 5000));
+        assert!(parse_port_range("5010:5000").is_err());
+    }
+}
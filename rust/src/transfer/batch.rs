@@ -0,0 +1,636 @@
+// Small-file batching: many files packed into one data stream
+//
+// Each entry is sent as a `FileInfo` record followed by its contents in
+// `DataChunk` records, with the file's holes (and, if asked, runs of zero
+// blocks) sent as `Hole` records, and the batch ends with `Complete`. There is no
+// per-file handshake and no fsync per file; the receiver pipelines
+// directory creation, file writes and metadata application. A file the
+// sender can't read is left out of the stream and reported by the sender.
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use std::collections::HashSet;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
+use tracing::{debug, warn};
+
+use crate::network::protocol::{self, EntryKind, FileInfo, MessageType, ProtocolMessage};
+use crate::platform;
+use crate::transfer::mode::TargetMode;
+use crate::transfer::sparse::{self, Extent};
+
+/// Default size below which files in a tree are batched
+pub const DEFAULT_BATCH_THRESHOLD: u64 = 64 * 1024;
+
+/// Outcome of receiving a batch
+#[derive(Debug, Default)]
+pub struct BatchStats {
+    pub files: u64,
+    pub directories: u64,
+    pub bytes: u64,
+    /// Entries that could not be written, with the reason
+    pub failures: Vec<(String, String)>,
+}
+
+/// Describe a local entry for the receiver
+pub fn file_info(relative: &Path, metadata: &std::fs::Metadata) -> FileInfo {
+    let kind = if metadata.is_dir() { EntryKind::Directory } else { EntryKind::File };
+    let path = relative.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+
+    let attributes = platform::FileMetadata::from_std(metadata);
+
+    FileInfo {
+        path,
+        kind,
+        size: if metadata.is_dir() { 0 } else { metadata.len() },
+        mode: attributes.mode,
+        uid: attributes.uid,
+        gid: attributes.gid,
+        mtime: attributes.modified as i64,
+        mtime_nsec: attributes.modified_nsec,
+        atime: attributes.accessed as i64,
+        atime_nsec: attributes.accessed_nsec,
+        xattrs: None,
+    }
+}
+
+/// The attributes carried by `info`, for applying on the receiver
+fn attributes(info: &FileInfo) -> platform::FileMetadata {
+    platform::FileMetadata {
+        size: info.size,
+        modified: info.mtime.max(0) as u64,
+        modified_nsec: info.mtime_nsec,
+        accessed: info.atime.max(0) as u64,
+        accessed_nsec: info.atime_nsec,
+        mode: info.mode,
+        uid: info.uid,
+        gid: info.gid,
+        xattrs: Vec::new(),
+    }
+}
+
+/// Writes entries under `root` into a single batch stream
+pub struct BatchSender<W> {
+    writer: W,
+    root: PathBuf,
+    chunk_size: usize,
+    xattrs: bool,
+    sparse: bool,
+    zero_holes: bool,
+    /// Entries left out because they couldn't be read, with the reason
+    failures: Vec<(String, String)>,
+}
+
+impl<W: AsyncWrite + Unpin> BatchSender<W> {
+    pub fn new(writer: W, root: PathBuf, chunk_size: usize) -> Self {
+        Self {
+            writer,
+            root,
+            chunk_size: chunk_size.max(1),
+            xattrs: false,
+            sparse: false,
+            zero_holes: false,
+            failures: Vec::new(),
+        }
+    }
+
+    /// Send each entry's extended attributes and ACLs along with it
+    pub fn with_xattrs(mut self, xattrs: bool) -> Self {
+        self.xattrs = xattrs;
+        self
+    }
+
+    /// Send the holes of sparse files as `Hole` records instead of data
+    pub fn with_sparse(mut self, sparse: bool) -> Self {
+        self.sparse = sparse;
+        self
+    }
+
+    /// Send runs of zero blocks as `Hole` records too
+    pub fn with_zero_holes(mut self, zero_holes: bool) -> Self {
+        self.zero_holes = zero_holes;
+        self
+    }
+
+    /// Describe the entry at `relative`, with its extended attributes if requested
+    async fn describe(&self, relative: &Path, metadata: &std::fs::Metadata) -> Result<FileInfo> {
+        let mut info = file_info(relative, metadata);
+        if self.xattrs {
+            let path = self.root.join(relative);
+            let xattrs = tokio::task::spawn_blocking(move || platform::read_xattrs(&path))
+                .await
+                .context("Extended attribute task panicked")?
+                .with_context(|| format!("Failed to read extended attributes of {:?}", self.root.join(relative)))?;
+            info.xattrs = Some(xattrs);
+        }
+        Ok(info)
+    }
+
+    pub async fn send_directory(&mut self, relative: &Path) -> Result<()> {
+        let metadata = tokio::fs::metadata(self.root.join(relative)).await
+            .with_context(|| format!("Failed to read {:?}", self.root.join(relative)))?;
+        let info = self.describe(relative, &metadata).await?;
+        protocol::write_message(&mut self.writer, &info.to_message()?).await?;
+        Ok(())
+    }
+
+    /// Send one file, returning the number of data bytes sent. A file that
+    /// can't be read is skipped and reported by `finish`; errors are left
+    /// for a stream that can't be written.
+    pub async fn send_file(&mut self, relative: &Path) -> Result<u64> {
+        let (info, data, runs) = match self.read_file(relative).await {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Skipping {:?}: {:#}", self.root.join(relative), e);
+                self.failures.push((relative.to_string_lossy().into_owned(), format!("{:#}", e)));
+                return Ok(0);
+            }
+        };
+        protocol::write_message(&mut self.writer, &info.to_message()?).await?;
+
+        let data = Bytes::from(data);
+        for (run, zero) in runs {
+            if zero {
+                protocol::write_message(&mut self.writer, &protocol::hole_message(run.len() as u64)).await?;
+                continue;
+            }
+
+            let mut offset = run.start;
+            while offset < run.end {
+                let end = (offset + self.chunk_size).min(run.end);
+                let chunk = ProtocolMessage::new(MessageType::DataChunk, data.slice(offset..end));
+                protocol::write_message(&mut self.writer, &chunk).await?;
+                offset = end;
+            }
+        }
+
+        Ok(info.size)
+    }
+
+    /// Read and describe a file before any of it is sent, so a failure
+    /// leaves nothing half-sent
+    async fn read_file(&self, relative: &Path) -> Result<(FileInfo, Vec<u8>, Vec<(Range<usize>, bool)>)> {
+        let path = self.root.join(relative);
+        let metadata = tokio::fs::metadata(&path).await
+            .with_context(|| format!("Failed to read {:?}", path))?;
+        let data = tokio::fs::read(&path).await
+            .with_context(|| format!("Failed to read {:?}", path))?;
+
+        // The file may have changed since the walk; describe what we read
+        let mut info = self.describe(relative, &metadata).await?;
+        info.size = data.len() as u64;
+        let runs = self.runs(&path, &data).await?;
+        Ok((info, data, runs))
+    }
+
+    /// Split `data`, read from `path`, into runs of data and runs to send
+    /// as holes
+    async fn runs(&self, path: &Path, data: &[u8]) -> Result<Vec<(Range<usize>, bool)>> {
+        let extents = if self.sparse {
+            let file = std::fs::File::open(path)
+                .with_context(|| format!("Failed to open {:?}", path))?;
+            let len = data.len() as u64;
+            tokio::task::spawn_blocking(move || sparse::extents(&file, 0..len))
+                .await
+                .context("Extent scan panicked")??
+        } else {
+            vec![Extent::Data(0..data.len() as u64)]
+        };
+
+        let mut runs = Vec::with_capacity(extents.len());
+        for extent in extents {
+            match extent {
+                Extent::Hole(range) => runs.push((range.start as usize..range.end as usize, true)),
+                Extent::Data(range) if self.zero_holes => {
+                    let start = range.start as usize;
+                    runs.extend(sparse::zero_runs(&data[start..range.end as usize]).into_iter()
+                        .map(|(run, zero)| (start + run.start..start + run.end, zero)));
+                }
+                Extent::Data(range) => runs.push((range.start as usize..range.end as usize, false)),
+            }
+        }
+        Ok(runs)
+    }
+
+    /// End the batch and close the stream, returning the entries that
+    /// were skipped
+    pub async fn finish(mut self) -> Result<Vec<(String, String)>> {
+        protocol::write_message(&mut self.writer, &ProtocolMessage::new(MessageType::Complete, Bytes::new())).await?;
+        self.writer.flush().await?;
+        self.writer.shutdown().await?;
+        Ok(self.failures)
+    }
+}
+
+/// Writes a received batch under `root`
+pub struct BatchReceiver {
+    root: PathBuf,
+    partial_suffix: Option<String>,
+    keep_partial: bool,
+    force: bool,
+    preserve: bool,
+    mode: TargetMode,
+    sparse: bool,
+    writers: usize,
+}
+
+impl BatchReceiver {
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            partial_suffix: None,
+            keep_partial: false,
+            force: false,
+            preserve: false,
+            mode: TargetMode::default(),
+            sparse: false,
+            writers: 8,
+        }
+    }
+
+    pub fn with_partial_suffix(mut self, suffix: Option<String>) -> Self {
+        self.partial_suffix = suffix;
+        self
+    }
+
+    /// Keep the partial file of an entry that fails to write (-k)
+    pub fn with_keep_partial(mut self, keep_partial: bool) -> Self {
+        self.keep_partial = keep_partial;
+        self
+    }
+
+    pub fn with_force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    pub fn with_preserve(mut self, preserve: bool) -> Self {
+        self.preserve = preserve;
+        self
+    }
+
+    /// Force these permission bits onto received files and directories (-m)
+    pub fn with_mode(mut self, mode: TargetMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Leave the `Hole` records of received files as holes
+    pub fn with_sparse(mut self, sparse: bool) -> Self {
+        self.sparse = sparse;
+        self
+    }
+
+    pub fn with_writers(mut self, writers: usize) -> Self {
+        self.writers = writers.max(1);
+        self
+    }
+
+    pub async fn receive<R: AsyncRead + Unpin>(&self, mut reader: R) -> Result<BatchStats> {
+        let mut stats = BatchStats::default();
+        let mut created_dirs: HashSet<PathBuf> = HashSet::new();
+
+        // Metadata is applied by its own task once a file's data is written
+        let (metadata_tx, mut metadata_rx) = mpsc::channel::<(PathBuf, FileInfo)>(1024);
+        let preserve = self.preserve;
+        let mode = self.mode;
+        let finisher = tokio::spawn(async move {
+            let mut failures = Vec::new();
+            while let Some((target, info)) = metadata_rx.recv().await {
+                let entry_mode = mode.for_entry(info.kind == EntryKind::Directory);
+                if preserve || entry_mode.is_some() || info.xattrs.is_some() {
+                    let result = tokio::task::spawn_blocking(move || {
+                        apply_metadata(&target, &info, preserve, entry_mode)
+                    }).await;
+                    if let Ok(Err(failure)) = result {
+                        failures.push(failure);
+                    }
+                }
+            }
+            failures
+        });
+
+        let semaphore = Arc::new(Semaphore::new(self.writers));
+        let mut writers = JoinSet::new();
+        let mut pending: Option<PendingFile> = None;
+        // Directory metadata waits until their contents are written
+        let mut directories = Vec::new();
+
+        loop {
+            let message = protocol::read_message(&mut reader).await?
+                .context("Batch stream ended without a Complete record")?;
+
+            match message.message_type {
+                MessageType::FileInfo => {
+                    if let Some((info, _, _)) = pending.take() {
+                        anyhow::bail!("Batch entry {} ended before all {} bytes arrived", info.path, info.size);
+                    }
+
+                    let info = FileInfo::from_message(&message)?;
+                    let target = resolve_path(&self.root, &info.path)?;
+
+                    if info.kind == EntryKind::Directory {
+                        tokio::fs::create_dir_all(&target).await
+                            .with_context(|| format!("Failed to create {:?}", target))?;
+                        created_dirs.insert(target.clone());
+                        stats.directories += 1;
+                        directories.push((target, info));
+                        continue;
+                    }
+
+                    // Parents normally arrive as directory records first
+                    if let Some(parent) = target.parent() {
+                        if !created_dirs.contains(parent) {
+                            tokio::fs::create_dir_all(parent).await
+                                .with_context(|| format!("Failed to create {:?}", parent))?;
+                            created_dirs.insert(parent.to_path_buf());
+                        }
+                    }
+
+                    pending = Some((info, Vec::new(), Vec::new()));
+                }
+                MessageType::DataChunk => {
+                    let (info, data, _) = pending.as_mut()
+                        .context("Data chunk outside of a batch entry")?;
+                    data.extend_from_slice(&message.data);
+                    if data.len() as u64 > info.size {
+                        anyhow::bail!("Batch entry {} is longer than announced", info.path);
+                    }
+                }
+                MessageType::Hole => {
+                    let (info, data, holes) = pending.as_mut()
+                        .context("Hole outside of a batch entry")?;
+                    let len = protocol::hole_length(&message)?;
+                    if data.len() as u64 + len > info.size {
+                        anyhow::bail!("Batch entry {} is longer than announced", info.path);
+                    }
+                    holes.push(data.len()..data.len() + len as usize);
+                    data.resize(data.len() + len as usize, 0);
+                }
+                MessageType::Complete => {
+                    if let Some((info, _, _)) = pending.take() {
+                        anyhow::bail!("Batch ended inside entry {}", info.path);
+                    }
+                    break;
+                }
+                MessageType::Error => {
+                    anyhow::bail!("Sender aborted batch: {}", String::from_utf8_lossy(&message.data));
+                }
+                other => anyhow::bail!("Unexpected {:?} record in batch", other),
+            }
+
+            // Hand complete files to the writers
+            if let Some((info, data, holes)) = take_complete(&mut pending) {
+                let target = resolve_path(&self.root, &info.path)?;
+                let permit = semaphore.clone().acquire_owned().await?;
+                let partial_suffix = self.partial_suffix.clone();
+                let keep_partial = self.keep_partial;
+                let force = self.force;
+                let sparse = self.sparse;
+                let metadata_tx = metadata_tx.clone();
+
+                writers.spawn(async move {
+                    let _permit = permit;
+                    let holes = if sparse { holes } else { Vec::new() };
+                    let result = write_file(&target, data, holes, partial_suffix.as_deref(), keep_partial, force).await;
+                    if result.is_ok() {
+                        let _ = metadata_tx.send((target, info.clone())).await;
+                    }
+                    (info, result)
+                });
+            }
+
+            // Collect finished writes without blocking the reader
+            while let Some(result) = writers.try_join_next() {
+                record_write(&mut stats, result.context("Batch writer task panicked")?);
+            }
+        }
+
+        while let Some(result) = writers.join_next().await {
+            record_write(&mut stats, result.context("Batch writer task panicked")?);
+        }
+
+        // Children before parents, so read-only directories are applied last
+        for directory in directories.into_iter().rev() {
+            let _ = metadata_tx.send(directory).await;
+        }
+        drop(metadata_tx);
+        stats.failures.extend(finisher.await.context("Batch metadata task panicked")?);
+
+        debug!("Received batch: {} files, {} directories, {} bytes", stats.files, stats.directories, stats.bytes);
+        Ok(stats)
+    }
+}
+
+/// An entry being received: its record, its data so far and the ranges of
+/// it that arrived as holes
+type PendingFile = (FileInfo, Vec<u8>, Vec<Range<usize>>);
+
+/// Take the pending entry once all of its data has arrived
+fn take_complete(pending: &mut Option<PendingFile>) -> Option<PendingFile> {
+    match pending {
+        Some((info, data, _)) if data.len() as u64 == info.size => pending.take(),
+        _ => None,
+    }
+}
+
+fn record_write(stats: &mut BatchStats, (info, result): (FileInfo, Result<()>)) {
+    match result {
+        Ok(()) => {
+            stats.files += 1;
+            stats.bytes += info.size;
+        }
+        Err(e) => {
+            warn!("Failed to write batch entry {}: {:#}", info.path, e);
+            stats.failures.push((info.path, format!("{:#}", e)));
+        }
+    }
+}
+
+/// Join a received relative path onto `root`, refusing anything that
+/// could escape it
+fn resolve_path(root: &Path, relative: &str) -> Result<PathBuf> {
+    let relative = Path::new(relative);
+    if relative.components().any(|component| !matches!(component, Component::Normal(_))) {
+        anyhow::bail!("Refusing unsafe path {:?} in batch", relative);
+    }
+    Ok(root.join(relative))
+}
+
+async fn write_file(
+    target: &Path,
+    data: Vec<u8>,
+    holes: Vec<Range<usize>>,
+    partial_suffix: Option<&str>,
+    keep_partial: bool,
+    force: bool,
+) -> Result<()> {
+    if !force && tokio::fs::try_exists(target).await? {
+        anyhow::bail!("{:?} already exists; use -f/--force to replace it", target);
+    }
+
+    let partial = super::partial_path(target, partial_suffix)?;
+    let written = if !holes.is_empty() {
+        let partial = partial.clone();
+        tokio::task::spawn_blocking(move || sparse::write_sparse(&partial, &data, &holes))
+            .await
+            .context("Sparse write panicked")?
+    } else {
+        tokio::fs::write(&partial, data).await
+            .with_context(|| format!("Failed to write {:?}", partial))
+    };
+    if let Err(e) = written {
+        if keep_partial {
+            debug!("Keeping partial file {:?}", partial);
+        } else {
+            let _ = tokio::fs::remove_file(&partial).await;
+        }
+        return Err(e);
+    }
+
+    if partial != target {
+        tokio::fs::rename(&partial, target).await
+            .with_context(|| format!("Failed to rename {:?} to {:?}", partial, target))?;
+    }
+    Ok(())
+}
+
+/// Apply the attributes of `info` if `preserve` is set, then an explicit
+/// `mode`, then any extended attributes it carries
+pub(crate) fn apply_metadata(
+    target: &Path,
+    info: &FileInfo,
+    preserve: bool,
+    mode: Option<u32>,
+) -> std::result::Result<(), (String, String)> {
+    let fail = |e: crate::error::BbcprError| (info.path.clone(), format!("Failed to apply metadata: {}", e));
+
+    if preserve {
+        platform::apply_metadata(target, &attributes(info)).map_err(fail)?;
+    }
+    if let Some(mode) = mode {
+        platform::set_mode(target, mode).map_err(fail)?;
+    }
+    // After the mode, which would otherwise rewrite the ACL mask
+    if let Some(xattrs) = &info.xattrs {
+        platform::apply_xattrs(target, xattrs).map_err(fail)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_batch_round_trip() {
+        let source = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(source.path().join("sub")).unwrap();
+        std::fs::write(source.path().join("a.txt"), b"alpha").unwrap();
+        std::fs::write(source.path().join("sub/b.txt"), vec![7u8; 10_000]).unwrap();
+        std::fs::write(source.path().join("sub/empty"), b"").unwrap();
+        let modified = std::time::UNIX_EPOCH + std::time::Duration::new(1_600_000_000, 123_456_789);
+        std::fs::File::options().write(true).open(source.path().join("a.txt")).unwrap()
+            .set_modified(modified).unwrap();
+
+        let (client, server) = tokio::io::duplex(4096);
+        let root = source.path().to_path_buf();
+        let sender = tokio::spawn(async move {
+            let mut sender = BatchSender::new(client, root, 1024);
+            sender.send_directory(Path::new("sub")).await.unwrap();
+            sender.send_file(Path::new("a.txt")).await.unwrap();
+            // Unreadable entries are skipped and reported, and the batch goes on
+            assert_eq!(sender.send_file(Path::new("missing")).await.unwrap(), 0);
+            sender.send_file(Path::new("sub/b.txt")).await.unwrap();
+            sender.send_file(Path::new("sub/empty")).await.unwrap();
+            sender.finish().await.unwrap()
+        });
+
+        let stats = BatchReceiver::new(target.path().to_path_buf())
+            .with_partial_suffix(Some("bbcpr-partial".to_string()))
+            .with_preserve(true)
+            .receive(server)
+            .await
+            .unwrap();
+        let skipped = sender.await.unwrap();
+
+        assert_eq!(stats.files, 3);
+        assert_eq!(stats.directories, 1);
+        assert_eq!(stats.bytes, 10_005);
+        assert!(stats.failures.is_empty());
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].0, "missing");
+        assert_eq!(std::fs::read(target.path().join("a.txt")).unwrap(), b"alpha");
+        assert_eq!(std::fs::read(target.path().join("sub/b.txt")).unwrap(), vec![7u8; 10_000]);
+        assert!(!target.path().join(".a.txt.bbcpr-partial").exists());
+        assert_eq!(std::fs::metadata(target.path().join("a.txt")).unwrap().modified().unwrap(), modified);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_batch_carries_xattrs() {
+        let source = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+        let file = source.path().join("tagged.dat");
+        std::fs::write(&file, b"data").unwrap();
+        if platform::linux::set_xattr(&file, "user.lineage", b"run-42").is_err() {
+            // The filesystem has no user xattr support
+            return;
+        }
+
+        let (client, server) = tokio::io::duplex(4096);
+        let root = source.path().to_path_buf();
+        let sender = tokio::spawn(async move {
+            let mut sender = BatchSender::new(client, root, 1024).with_xattrs(true);
+            sender.send_file(Path::new("tagged.dat")).await.unwrap();
+            sender.finish().await.unwrap();
+        });
+
+        let stats = BatchReceiver::new(target.path().to_path_buf())
+            .receive(server)
+            .await
+            .unwrap();
+        sender.await.unwrap();
+
+        assert!(stats.failures.is_empty());
+        assert_eq!(
+            platform::linux::get_xattr(&target.path().join("tagged.dat"), "user.lineage").unwrap(),
+            Some(b"run-42".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_zero_blocks_are_holes_only_when_asked() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("zeros");
+        let mut data = vec![0u8; 3 * sparse::ZERO_BLOCK];
+        data[0] = 1;
+        std::fs::write(&path, &data).unwrap();
+
+        // A written file of zeros has no holes to keep
+        let sender = BatchSender::new(tokio::io::sink(), dir.path().to_path_buf(), 1024).with_sparse(true);
+        assert_eq!(sender.runs(&path, &data).await.unwrap(), vec![(0..data.len(), false)]);
+
+        let sender = sender.with_zero_holes(true);
+        assert_eq!(
+            sender.runs(&path, &data).await.unwrap(),
+            vec![(0..sparse::ZERO_BLOCK, false), (sparse::ZERO_BLOCK..data.len(), true)]
+        );
+    }
+
+    #[test]
+    fn test_resolve_path_rejects_escapes() {
+        let root = Path::new("/target");
+        assert!(resolve_path(root, "a/b.txt").is_ok());
+        assert!(resolve_path(root, "../etc/passwd").is_err());
+        assert!(resolve_path(root, "/etc/passwd").is_err());
+    }
+}
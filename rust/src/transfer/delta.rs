@@ -0,0 +1,620 @@
+// Delta transfer: rebuild a changed destination from its own blocks
+//
+// The receiver splits its existing copy into fixed-size blocks and sends a
+// signature of each: an Adler-32 weak hash and an MD5 strong hash. The sender
+// slides a window over the source, rolling the weak hash one byte at a time,
+// and emits a block reference wherever both hashes match and literal data
+// everywhere else. The source is partitioned into block-aligned ranges that
+// are diffed independently, one per stream.
+//
+// On the wire the receiver sends its signature as `Signature` records
+// followed by `Complete`. Each of the sender's streams opens with a `Part`
+// record giving its range, then carries that range's `DeltaOp` records and
+// `Complete`; the receiver applies them with a `DeltaWriter` at the range's
+// offset.
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tracing::{debug, info};
+
+use crate::checksum::adler32::Adler32Checksum;
+use crate::checksum::md5::MD5Checksum;
+use crate::checksum::Checksum;
+use crate::network::protocol::{self, MessageType, ProtocolMessage};
+use crate::transfer::engine::TransferMessage;
+
+const MIN_BLOCK_SIZE: u64 = 4 * 1024;
+const MAX_BLOCK_SIZE: u64 = 1024 * 1024;
+
+/// Literal runs are sent in pieces of at most this many bytes
+const MAX_LITERAL: usize = 256 * 1024;
+
+/// Block signatures per `Signature` record, keeping records well under
+/// the frame size limit however large the file
+const SIGNATURE_BLOCKS_PER_RECORD: usize = 64 * 1024;
+
+/// Operations buffered between a stream's diff and its writer
+const OPS_IN_FLIGHT: usize = 64;
+
+/// Block size for a destination of `len` bytes: about the square root of the
+/// length, so that signature size and literal granularity grow together
+pub fn block_size_for(len: u64) -> u64 {
+    let root = (len as f64).sqrt() as u64;
+    root.next_multiple_of(1024).clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: Vec<u8>,
+}
+
+/// Signatures of every full block of the receiver's existing file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Signature {
+    pub block_size: u64,
+    pub blocks: Vec<BlockSignature>,
+    #[serde(skip)]
+    index: HashMap<u32, Vec<u64>>,
+}
+
+impl Signature {
+    fn new(block_size: u64, blocks: Vec<BlockSignature>) -> Self {
+        let mut signature = Self {
+            block_size,
+            blocks,
+            index: HashMap::new(),
+        };
+        signature.build_index();
+        signature
+    }
+
+    /// Rebuild the weak hash lookup, e.g. after deserializing
+    pub fn build_index(&mut self) {
+        self.index.clear();
+        for (i, block) in self.blocks.iter().enumerate() {
+            self.index.entry(block.weak).or_default().push(i as u64);
+        }
+    }
+
+    /// A block whose content equals `window`, which hashes to `weak`
+    fn find(&self, weak: u32, window: &[u8]) -> Option<u64> {
+        let candidates = self.index.get(&weak)?;
+        let strong = strong_hash(window);
+        candidates.iter().copied().find(|&i| self.blocks[i as usize].strong == strong)
+    }
+}
+
+/// One instruction for rebuilding a range of the file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeltaOp {
+    /// Copy block `index` of the receiver's existing file
+    Copy { index: u64 },
+    /// Bytes the receiver does not have
+    Literal(Vec<u8>),
+}
+
+impl DeltaOp {
+    pub fn to_message(&self) -> Result<ProtocolMessage> {
+        let data = bincode::serialize(self).context("Failed to encode delta operation")?;
+        Ok(ProtocolMessage::new(MessageType::DeltaOp, Bytes::from(data)))
+    }
+
+    pub fn from_message(message: &ProtocolMessage) -> Result<Self> {
+        bincode::deserialize(&message.data).context("Invalid delta operation")
+    }
+}
+
+/// Bytes matched from the existing file versus sent as literals
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DeltaStats {
+    pub matched_bytes: u64,
+    pub literal_bytes: u64,
+}
+
+impl DeltaStats {
+    fn add(&mut self, other: DeltaStats) {
+        self.matched_bytes += other.matched_bytes;
+        self.literal_bytes += other.literal_bytes;
+    }
+}
+
+fn strong_hash(data: &[u8]) -> Vec<u8> {
+    let mut checksum = MD5Checksum::new();
+    checksum.update(data);
+    checksum.finalize()
+}
+
+fn weak_hash(data: &[u8]) -> Adler32Checksum {
+    let mut checksum = Adler32Checksum::new();
+    checksum.update(data);
+    checksum
+}
+
+/// Read until `buffer` is full or the file ends, returning the bytes read
+fn read_full(file: &mut File, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// Receiver side: compute the block signatures of `path`
+pub fn compute_signature(path: &Path, block_size: u64) -> Result<Signature> {
+    let mut file = File::open(path)
+        .with_context(|| format!("Failed to open {:?}", path))?;
+    let mut block = vec![0u8; block_size as usize];
+    let mut blocks = Vec::new();
+
+    loop {
+        let n = read_full(&mut file, &mut block)
+            .with_context(|| format!("Failed to read {:?}", path))?;
+        // A short last block can't match a full window; it is left out
+        if n < block.len() {
+            break;
+        }
+        blocks.push(BlockSignature {
+            weak: weak_hash(&block).value(),
+            strong: strong_hash(&block),
+        });
+    }
+
+    debug!("Signature of {:?}: {} blocks of {} bytes", path, blocks.len(), block_size);
+    Ok(Signature::new(block_size, blocks))
+}
+
+/// Sender side: diff `range` of `source` against `signature`, passing each
+/// operation to `emit` in order
+pub fn compute_delta(
+    source: &mut File,
+    range: Range<u64>,
+    signature: &Signature,
+    mut emit: impl FnMut(DeltaOp) -> Result<()>,
+) -> Result<DeltaStats> {
+    let block_size = signature.block_size as usize;
+    let read_size = (block_size * 16).max(1024 * 1024);
+    let mut stats = DeltaStats::default();
+
+    let mut buffer: Vec<u8> = Vec::with_capacity(read_size + block_size);
+    let mut position = 0;
+    let mut next_read = range.start;
+    let mut literal = Vec::new();
+    let mut rolling: Option<Adler32Checksum> = None;
+
+    source.seek(SeekFrom::Start(range.start))?;
+
+    loop {
+        // Keep a full window buffered while the range has data left
+        if buffer.len() - position < block_size && next_read < range.end {
+            buffer.drain(..position);
+            position = 0;
+            let want = (read_size as u64).min(range.end - next_read) as usize;
+            let start = buffer.len();
+            buffer.resize(start + want, 0);
+            source.read_exact(&mut buffer[start..])?;
+            next_read += want as u64;
+            continue;
+        }
+
+        // Less than a block left: the rest is literal
+        if buffer.len() - position < block_size {
+            literal.extend_from_slice(&buffer[position..]);
+            break;
+        }
+
+        let window = &buffer[position..position + block_size];
+        let weak = rolling.get_or_insert_with(|| weak_hash(window)).value();
+
+        if let Some(index) = signature.find(weak, window) {
+            flush_literal(&mut literal, &mut stats, &mut emit)?;
+            emit(DeltaOp::Copy { index })?;
+            stats.matched_bytes += block_size as u64;
+            position += block_size;
+            rolling = None;
+            continue;
+        }
+
+        literal.push(buffer[position]);
+        if literal.len() >= MAX_LITERAL {
+            flush_literal(&mut literal, &mut stats, &mut emit)?;
+        }
+
+        // Roll forward if the next window is buffered; otherwise rehash after the next read
+        match rolling.as_mut() {
+            Some(checksum) if position + block_size < buffer.len() => {
+                checksum.roll(buffer[position], buffer[position + block_size], block_size);
+            }
+            _ => rolling = None,
+        }
+        position += 1;
+    }
+
+    flush_literal(&mut literal, &mut stats, &mut emit)?;
+    Ok(stats)
+}
+
+fn flush_literal(
+    literal: &mut Vec<u8>,
+    stats: &mut DeltaStats,
+    emit: &mut impl FnMut(DeltaOp) -> Result<()>,
+) -> Result<()> {
+    if literal.is_empty() {
+        return Ok(());
+    }
+    stats.literal_bytes += literal.len() as u64;
+    emit(DeltaOp::Literal(std::mem::take(literal)))
+}
+
+/// Receiver side: writes the operations for one range into the new file
+pub struct DeltaWriter {
+    basis: File,
+    output: File,
+    block_size: u64,
+    block: Vec<u8>,
+}
+
+impl DeltaWriter {
+    /// Rebuild into `output` starting at `offset`, copying blocks from `basis`
+    pub fn new(basis: &Path, output: &Path, offset: u64, block_size: u64) -> Result<Self> {
+        let basis = File::open(basis)
+            .with_context(|| format!("Failed to open {:?}", basis))?;
+        let mut output = std::fs::OpenOptions::new()
+            .write(true)
+            .open(output)
+            .with_context(|| format!("Failed to open {:?}", output))?;
+        output.seek(SeekFrom::Start(offset))?;
+
+        Ok(Self {
+            basis,
+            output,
+            block_size,
+            block: vec![0u8; block_size as usize],
+        })
+    }
+
+    pub fn apply(&mut self, op: DeltaOp) -> Result<()> {
+        match op {
+            DeltaOp::Copy { index } => {
+                self.basis.seek(SeekFrom::Start(index * self.block_size))?;
+                self.basis.read_exact(&mut self.block)
+                    .with_context(|| format!("Failed to read block {} of the existing file", index))?;
+                self.output.write_all(&self.block)?;
+            }
+            DeltaOp::Literal(data) => self.output.write_all(&data)?,
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        self.output.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Receiver side: send the signature of `basis` as `Signature` records
+/// followed by `Complete`, returning its block size
+pub async fn send_signature<W: AsyncWrite + Unpin>(basis: &Path, writer: &mut W) -> Result<u64> {
+    let basis_size = tokio::fs::metadata(basis).await
+        .with_context(|| format!("Failed to read {:?}", basis))?
+        .len();
+    let block_size = block_size_for(basis_size);
+    let basis_path = basis.to_path_buf();
+    let signature = tokio::task::spawn_blocking(move || compute_signature(&basis_path, block_size))
+        .await
+        .context("Signature task panicked")??;
+
+    // An empty signature still sends one record, to carry the block size
+    let mut records = signature.blocks.chunks(SIGNATURE_BLOCKS_PER_RECORD).peekable();
+    if records.peek().is_none() {
+        write_signature_record(writer, block_size, &[]).await?;
+    }
+    for blocks in records {
+        write_signature_record(writer, block_size, blocks).await?;
+    }
+    protocol::write_message(writer, &ProtocolMessage::new(MessageType::Complete, Bytes::new())).await?;
+    writer.flush().await?;
+    Ok(block_size)
+}
+
+async fn write_signature_record<W: AsyncWrite + Unpin>(writer: &mut W, block_size: u64, blocks: &[BlockSignature]) -> Result<()> {
+    let data = bincode::serialize(&(block_size, blocks)).context("Failed to encode signature")?;
+    protocol::write_message(writer, &ProtocolMessage::new(MessageType::Signature, Bytes::from(data))).await?;
+    Ok(())
+}
+
+/// Sender side: read the receiver's signature up to its `Complete` record
+pub async fn read_signature<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Signature> {
+    let mut block_size = None;
+    let mut blocks = Vec::new();
+    loop {
+        let message = protocol::read_message(reader).await?
+            .context("Signature stream ended early")?;
+        match message.message_type {
+            MessageType::Signature => {
+                let (size, mut part): (u64, Vec<BlockSignature>) = bincode::deserialize(&message.data)
+                    .context("Invalid signature record")?;
+                if size == 0 || *block_size.get_or_insert(size) != size {
+                    anyhow::bail!("Signature records disagree on the block size");
+                }
+                blocks.append(&mut part);
+            }
+            MessageType::Complete => break,
+            MessageType::Error => anyhow::bail!("Receiver failed: {}", String::from_utf8_lossy(&message.data)),
+            other => anyhow::bail!("Unexpected {:?} record in a signature", other),
+        }
+    }
+    let block_size = block_size.context("Signature stream carried no signature")?;
+    Ok(Signature::new(block_size, blocks))
+}
+
+/// Sender side: diff `range` of `source` against `signature` and send it
+/// to `writer` as a `Part` record, the `DeltaOp` records and `Complete`
+pub async fn send_delta<W: AsyncWrite + Unpin>(
+    source: &Path,
+    range: Range<u64>,
+    signature: Arc<Signature>,
+    writer: &mut W,
+) -> Result<DeltaStats> {
+    protocol::write_message(writer, &protocol::part_message(range.start, range.end - range.start)).await?;
+
+    // The diff reads the file on a blocking thread and hands its
+    // operations over as it goes
+    let (op_tx, mut op_rx) = mpsc::channel::<DeltaOp>(OPS_IN_FLIGHT);
+    let path = source.to_path_buf();
+    let diff = tokio::task::spawn_blocking(move || -> Result<DeltaStats> {
+        let mut file = File::open(&path)
+            .with_context(|| format!("Failed to open {:?}", path))?;
+        compute_delta(&mut file, range, &signature, |op| {
+            op_tx.blocking_send(op).map_err(|_| anyhow::anyhow!("Delta stream closed"))
+        })
+    });
+
+    let sent: Result<()> = async {
+        while let Some(op) = op_rx.recv().await {
+            protocol::write_message(writer, &op.to_message()?).await?;
+        }
+        Ok(())
+    }.await;
+    // Stop the diff if the stream broke, then report whichever failed first
+    drop(op_rx);
+    let stats = diff.await.context("Delta task panicked")?;
+    sent?;
+    let stats = stats?;
+
+    protocol::write_message(writer, &ProtocolMessage::new(MessageType::Complete, Bytes::new())).await?;
+    writer.flush().await?;
+    Ok(stats)
+}
+
+/// Receiver side: apply one stream of delta operations from `reader` to
+/// `output`, which already has the source's length, copying blocks from
+/// `basis`. Returns the number of bytes the stream covered.
+pub async fn receive_delta<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    basis: &Path,
+    output: &Path,
+    block_size: u64,
+) -> Result<u64> {
+    let header = protocol::read_message(reader).await?
+        .context("Delta stream ended before its range")?;
+    if header.message_type != MessageType::Part {
+        anyhow::bail!("Delta stream opened with {:?} instead of its range", header.message_type);
+    }
+    let (offset, len) = protocol::part_values(&header)?;
+
+    let (op_tx, mut op_rx) = mpsc::channel::<DeltaOp>(OPS_IN_FLIGHT);
+    let (basis, output) = (basis.to_path_buf(), output.to_path_buf());
+    let writer = tokio::task::spawn_blocking(move || -> Result<u64> {
+        let mut writer = DeltaWriter::new(&basis, &output, offset, block_size)?;
+        let mut written = 0u64;
+        while let Some(op) = op_rx.blocking_recv() {
+            written += match &op {
+                DeltaOp::Copy { .. } => block_size,
+                DeltaOp::Literal(data) => data.len() as u64,
+            };
+            if written > len {
+                anyhow::bail!("Delta stream at {} overruns its {} bytes", offset, len);
+            }
+            writer.apply(op)?;
+        }
+        writer.finish()?;
+        Ok(written)
+    });
+
+    let read: Result<()> = async {
+        loop {
+            let message = protocol::read_message(reader).await?
+                .context("Delta stream ended early")?;
+            match message.message_type {
+                MessageType::DeltaOp => {
+                    if op_tx.send(DeltaOp::from_message(&message)?).await.is_err() {
+                        break;
+                    }
+                }
+                MessageType::Complete => break,
+                MessageType::Error => anyhow::bail!("Sender failed: {}", String::from_utf8_lossy(&message.data)),
+                other => anyhow::bail!("Unexpected {:?} record in a delta stream", other),
+            }
+        }
+        Ok(())
+    }.await;
+    drop(op_tx);
+    let written = writer.await.context("Delta writer panicked")?;
+    read?;
+    let written = written?;
+
+    if written != len {
+        anyhow::bail!("Delta stream at {} rebuilt {} of {} bytes", offset, written, len);
+    }
+    Ok(written)
+}
+
+/// Rebuild `source` at `output` using the blocks of `basis`, over up to
+/// `streams` in-process streams that carry the same records as a transfer
+/// between hosts. `output` must not be `basis`.
+pub async fn copy_delta(
+    source: &Path,
+    basis: &Path,
+    output: &Path,
+    streams: u32,
+    progress_tx: &mpsc::Sender<TransferMessage>,
+) -> Result<DeltaStats> {
+    let total_size = tokio::fs::metadata(source).await
+        .with_context(|| format!("Failed to read {:?}", source))?
+        .len();
+
+    // The receiver sends its signature back to the sender
+    let (mut signature_tx, mut signature_rx) = tokio::io::duplex(1024 * 1024);
+    let (block_size, signature) = tokio::try_join!(
+        send_signature(basis, &mut signature_tx),
+        read_signature(&mut signature_rx),
+    )?;
+    let signature = Arc::new(signature);
+
+    let output_path = output.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        File::create(&output_path)?.set_len(total_size)
+    })
+    .await
+    .context("Output creation task panicked")?
+    .with_context(|| format!("Failed to create {:?}", output))?;
+
+    let mut tasks = JoinSet::new();
+    for range in partition(total_size, block_size, streams) {
+        let (mut sender, mut receiver) = tokio::io::duplex(1024 * 1024);
+        let source: PathBuf = source.to_path_buf();
+        let (basis, output) = (basis.to_path_buf(), output.to_path_buf());
+        let signature = signature.clone();
+
+        tasks.spawn(async move {
+            let (stats, bytes) = tokio::try_join!(
+                send_delta(&source, range, signature, &mut sender),
+                receive_delta(&mut receiver, &basis, &output, block_size),
+            )?;
+            Ok::<_, anyhow::Error>((bytes, stats))
+        });
+    }
+
+    let mut stats = DeltaStats::default();
+    while let Some(result) = tasks.join_next().await {
+        let (bytes, range_stats) = result.context("Delta task panicked")??;
+        stats.add(range_stats);
+        let _ = progress_tx.send(TransferMessage::Progress {
+            bytes_transferred: bytes,
+            total_bytes: total_size,
+        }).await;
+    }
+
+    info!(
+        "Delta of {:?}: {} bytes matched, {} bytes sent as literals",
+        source, stats.matched_bytes, stats.literal_bytes
+    );
+    Ok(stats)
+}
+
+/// Split `0..len` into up to `parts` ranges whose boundaries fall on blocks
+fn partition(len: u64, block_size: u64, parts: u32) -> Vec<Range<u64>> {
+    let blocks = len.div_ceil(block_size);
+    let per_part = blocks.div_ceil(parts.max(1) as u64).max(1) * block_size;
+
+    (0..len)
+        .step_by(per_part as usize)
+        .map(|start| start..(start + per_part).min(len))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rebuild(source: &[u8], basis: &[u8], block_size: u64) -> (Vec<u8>, DeltaStats) {
+        let dir = tempfile::tempdir().unwrap();
+        let (source_path, basis_path) = (dir.path().join("source"), dir.path().join("basis"));
+        std::fs::write(&source_path, source).unwrap();
+        std::fs::write(&basis_path, basis).unwrap();
+
+        let signature = compute_signature(&basis_path, block_size).unwrap();
+        let mut ops = Vec::new();
+        let mut stats = DeltaStats::default();
+        for range in partition(source.len() as u64, block_size, 3) {
+            let mut file = File::open(&source_path).unwrap();
+            stats.add(compute_delta(&mut file, range, &signature, |op| {
+                ops.push(op);
+                Ok(())
+            }).unwrap());
+        }
+
+        let mut rebuilt = Vec::new();
+        for op in ops {
+            match op {
+                DeltaOp::Copy { index } => {
+                    let start = (index * block_size) as usize;
+                    rebuilt.extend_from_slice(&basis[start..start + block_size as usize]);
+                }
+                DeltaOp::Literal(data) => rebuilt.extend(data),
+            }
+        }
+        (rebuilt, stats)
+    }
+
+    #[test]
+    fn test_delta_rebuilds_shifted_data() {
+        let basis: Vec<u8> = (0..64 * 1024u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        // Insert a few bytes near the start and change a byte near the end
+        let mut source = basis[..1000].to_vec();
+        source.extend_from_slice(b"inserted");
+        source.extend_from_slice(&basis[1000..]);
+        let last = source.len() - 10;
+        source[last] ^= 0xff;
+
+        let (rebuilt, stats) = rebuild(&source, &basis, 4096);
+        assert_eq!(rebuilt, source);
+        assert!(stats.matched_bytes >= 12 * 4096, "only {} bytes matched", stats.matched_bytes);
+        // The insert, the changed byte and the two range boundaries cost up to a block each
+        assert!(stats.literal_bytes < 5 * 4096, "{} literal bytes", stats.literal_bytes);
+    }
+
+    #[tokio::test]
+    async fn test_copy_delta_over_streams() {
+        let dir = tempfile::tempdir().unwrap();
+        let basis: Vec<u8> = (0..300 * 1024u32).map(|i| (i.wrapping_mul(2654435761) >> 11) as u8).collect();
+        let mut source = basis.clone();
+        source.splice(5000..5000, b"inserted".iter().copied());
+        source.truncate(250 * 1024);
+        let (source_path, basis_path, output) = (dir.path().join("source"), dir.path().join("basis"), dir.path().join("output"));
+        std::fs::write(&source_path, &source).unwrap();
+        std::fs::write(&basis_path, &basis).unwrap();
+
+        let (progress_tx, mut progress_rx) = mpsc::channel(1024);
+        tokio::spawn(async move { while progress_rx.recv().await.is_some() {} });
+
+        let stats = copy_delta(&source_path, &basis_path, &output, 4, &progress_tx).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), source);
+        assert!(stats.matched_bytes > stats.literal_bytes);
+
+        let op = DeltaOp::Literal(b"abc".to_vec());
+        assert_eq!(DeltaOp::from_message(&op.to_message().unwrap()).unwrap(), op);
+    }
+
+    #[test]
+    fn test_partition_is_block_aligned() {
+        let ranges = partition(10 * 4096 + 5, 4096, 4);
+        assert_eq!(ranges.first().unwrap().start, 0);
+        assert_eq!(ranges.last().unwrap().end, 10 * 4096 + 5);
+        assert!(ranges.iter().all(|r| r.start % 4096 == 0));
+    }
+}
@@ -1,12 +1,12 @@
 use anyhow::{Context, Result};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
 use tracing::{debug, info, warn};
 
-use crate::checksum::Checksum;
-use crate::network::Connection;
-use crate::transfer::{TransferOptions, state::{TransferState, ChunkState}};
+use crate::platform;
+use crate::transfer::{TransferOptions, delta, lock::TransferLock, space, sparse::{self, Extent}, state::{self, TransferState, ChunkState}, sync};
 
 pub struct TransferEngine {
     options: TransferOptions,
@@ -16,6 +16,7 @@ pub struct TransferEngine {
 
 #[derive(Debug)]
 pub enum TransferMessage {
+    /// `bytes_transferred` bytes were moved since the sender's last report
     Progress { bytes_transferred: u64, total_bytes: u64 },
     Checksum { algorithm: String, value: Vec<u8> },
     Complete,
@@ -32,29 +33,70 @@ impl TransferEngine {
         }
     }
 
-    pub async fn transfer<C: Connection + 'static>(
-        &self,
-        mut connection: C,
-        progress_tx: mpsc::Sender<TransferMessage>,
-    ) -> Result<()> {
-        info!("Starting transfer from {:?} to {:?}", self.source_path, self.destination_path);
-        
-        // Connect to remote if needed
-        connection.connect().await
-            .context("Failed to establish connection")?;
+    /// Copy the source to the destination over parallel streams, without
+    /// sending the final `Complete`/`Error` message, which is left to the
+    /// caller as many files may share one transfer.
+    pub async fn copy(&self, progress_tx: &mpsc::Sender<TransferMessage>) -> Result<()> {
+        if let Some(policy) = self.options.sync {
+            if let Some(reason) = sync::skip_reason(policy, &self.source_path, &self.destination_path).await? {
+                info!("Skipping {:?}: {}", self.destination_path, reason);
+                return Ok(());
+            }
+        }
 
         // Get file metadata
         let metadata = tokio::fs::metadata(&self.source_path).await
             .context("Failed to read source file metadata")?;
         let total_size = metadata.len();
 
+        // Hold the transfer lock until we return, so a second invocation
+        // can't load the same state and write into the same destination
+        let dest_str = self.destination_path.to_string_lossy();
+        let transfer_id = state::generate_transfer_id(&self.source_path.to_string_lossy(), &dest_str);
+        let _lock = TransferLock::acquire(&transfer_id)?;
+
         // Check for existing transfer state
-        let mut transfer_state = if self.options.resume {
+        let transfer_state = if self.options.resume {
             self.load_or_create_transfer_state(total_size).await?
         } else {
             self.create_new_transfer_state(total_size).await?
         };
 
+        // Refuse to replace an existing target unless forced. When writing
+        // in place, a resumed transfer finds its own partial data there.
+        let partial_path = self.partial_path()?;
+        let resuming_in_place = partial_path == self.destination_path
+            && transfer_state.bytes_transferred > 0;
+        if !self.options.replaces_existing() && !resuming_in_place
+            && tokio::fs::try_exists(&self.destination_path).await?
+        {
+            anyhow::bail!(
+                "Destination {:?} already exists; use -f/--force to replace it",
+                self.destination_path
+            );
+        }
+
+        // Fail fast if the target can't hold what is left to write
+        if self.options.space_check {
+            // Space already taken by partial data is reused
+            let already_written = tokio::fs::metadata(&partial_path).await
+                .map(|metadata| metadata.len())
+                .unwrap_or(0);
+            space::check_local_space(&partial_path, total_size.saturating_sub(already_written))?;
+        }
+
+        // A changed destination is rebuilt from its own blocks plus the
+        // source data that differs. The old copy must stay intact while it
+        // is read, so this needs a partial file.
+        if self.options.delta
+            && tokio::fs::metadata(&self.destination_path).await.is_ok_and(|m| m.is_file())
+        {
+            if partial_path != self.destination_path {
+                return self.copy_delta(&partial_path, progress_tx).await;
+            }
+            warn!("Delta transfer needs a partial file; copying {:?} in full", self.source_path);
+        }
+
         // Save initial state to disk
         transfer_state.save_to_disk()
             .context("Failed to save transfer state")?;
@@ -70,6 +112,77 @@ impl TransferEngine {
             }).await;
         }
 
+        // Write everything to the partial file and only move it into place
+        // once the data is complete and verified
+        let result = match self.copy_chunks(&transfer_state, &partial_path, progress_tx).await {
+            Ok(()) => self.finish_destination(&partial_path).await,
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            self.discard_partial(&transfer_state, &partial_path).await;
+            return Err(e);
+        }
+
+        // Clean up state file on successful completion
+        if self.options.cleanup_on_success {
+            transfer_state.delete_from_disk()
+                .context("Failed to cleanup transfer state")?;
+        }
+
+        debug!("Copied {:?} to {:?}", self.source_path, self.destination_path);
+        Ok(())
+    }
+
+    /// Rebuild the destination into the partial file by delta against the
+    /// existing destination, then move it into place
+    async fn copy_delta(&self, partial_path: &Path, progress_tx: &mpsc::Sender<TransferMessage>) -> Result<()> {
+        let result = match delta::copy_delta(
+            &self.source_path,
+            &self.destination_path,
+            partial_path,
+            self.options.streams,
+            progress_tx,
+        ).await {
+            Ok(_) => self.finish_destination(partial_path).await,
+            Err(e) => Err(e),
+        };
+
+        if result.is_err() && !self.options.keep_partial {
+            let _ = tokio::fs::remove_file(partial_path).await;
+        }
+        result
+    }
+
+    /// Run a stream for every incomplete chunk and verify the result
+    async fn copy_chunks(
+        &self,
+        transfer_state: &TransferState,
+        partial_path: &Path,
+        progress_tx: &mpsc::Sender<TransferMessage>,
+    ) -> Result<()> {
+        // Create and preallocate the file before the streams start, so they
+        // write into reserved space and empty sources still produce a file
+        let path = partial_path.to_path_buf();
+        let total_size = transfer_state.total_size;
+        let sparse = self.options.sparse || self.options.zero_holes;
+        tokio::task::spawn_blocking(move || {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)?;
+            // Reserving the space would fill in the holes
+            if sparse {
+                file.set_len(total_size).map_err(Into::into)
+            } else {
+                platform::preallocate(&file, total_size)
+            }
+        })
+        .await
+        .context("Preallocation task panicked")?
+        .with_context(|| format!("Failed to create {:?} with {} bytes", partial_path, total_size))?;
+
         // Start parallel transfer streams for incomplete chunks only
         let incomplete_chunks = transfer_state.get_incomplete_chunks();
         let mut handles: Vec<JoinHandle<Result<()>>> = Vec::new();
@@ -79,22 +192,16 @@ impl TransferEngine {
                 let handle = self.spawn_transfer_stream(
                     chunk_id,
                     chunk_state,
-                    &transfer_state,
+                    transfer_state,
+                    partial_path,
                     progress_tx.clone(),
                 );
                 handles.push(handle);
             }
         }
 
-        // If no incomplete chunks, we're already done
         if handles.is_empty() {
-            info!("Transfer already complete, cleaning up state");
-            if self.options.cleanup_on_success {
-                transfer_state.delete_from_disk()
-                    .context("Failed to cleanup transfer state")?;
-            }
-            let _ = progress_tx.send(TransferMessage::Complete).await;
-            return Ok(());
+            info!("All chunks already transferred");
         }
 
         // Wait for all streams to complete
@@ -104,30 +211,92 @@ impl TransferEngine {
                 .context("Transfer stream failed")?;
         }
 
-        // Final verification and cleanup
+        // Final verification
         if self.options.checksum {
-            self.verify_transfer_checksum(&transfer_state).await?;
+            self.verify_transfer_checksum(transfer_state).await?;
         }
 
-        // Clean up state file on successful completion
-        if self.options.cleanup_on_success {
-            transfer_state.delete_from_disk()
-                .context("Failed to cleanup transfer state")?;
+        Ok(())
+    }
+
+    fn partial_path(&self) -> Result<PathBuf> {
+        super::partial_path(&self.destination_path, self.options.partial_suffix.as_deref())
+    }
+
+    /// Atomically move the completed partial file to the destination name
+    async fn finish_destination(&self, partial_path: &Path) -> Result<()> {
+        // Attributes go on last, so no later write changes the timestamps
+        if self.options.preserve || self.options.xattrs || self.options.target_mode.file.is_some() {
+            self.preserve_attributes(partial_path).await?;
         }
 
-        // Send completion message
-        let _ = progress_tx.send(TransferMessage::Complete).await;
-        
-        info!("Transfer completed successfully");
+        if partial_path == self.destination_path {
+            return Ok(());
+        }
+
+        let exists = tokio::fs::try_exists(&self.destination_path).await?;
+        if exists && !self.options.replaces_existing() {
+            anyhow::bail!(
+                "Destination {:?} appeared during the transfer; use -f/--force to replace it",
+                self.destination_path
+            );
+        }
+
+        // rename() only replaces existing files on Unix
+        #[cfg(windows)]
+        if exists {
+            tokio::fs::remove_file(&self.destination_path).await
+                .context("Failed to remove existing destination")?;
+        }
+
+        tokio::fs::rename(partial_path, &self.destination_path).await
+            .with_context(|| format!("Failed to rename {:?} to {:?}", partial_path, self.destination_path))?;
+
+        debug!("Renamed {:?} to {:?}", partial_path, self.destination_path);
         Ok(())
     }
 
+    /// Copy the source's mode, timestamps and (when privileged) ownership
+    /// onto `path` with -p, and its extended attributes with -X. A -m file
+    /// mode overrides the mode bits.
+    async fn preserve_attributes(&self, path: &Path) -> Result<()> {
+        let source = self.source_path.clone();
+        let target = path.to_path_buf();
+        let (preserve, xattrs) = (self.options.preserve, self.options.xattrs);
+        let mode = self.options.target_mode.file;
+        tokio::task::spawn_blocking(move || {
+            platform::copy_attributes(&source, &target, preserve, mode, xattrs)
+        })
+        .await
+        .context("Attribute task panicked")?
+        .with_context(|| format!("Failed to preserve attributes on {:?}", path))
+    }
+
+    /// Keep or remove the partial file after a failure. It is kept with
+    /// -k/--keep, and when resuming, since the saved state refers to it.
+    async fn discard_partial(&self, transfer_state: &TransferState, partial_path: &Path) {
+        if self.options.keep_partial || self.options.resume {
+            info!("Keeping partial file {:?}", partial_path);
+            return;
+        }
+
+        match tokio::fs::remove_file(partial_path).await {
+            Ok(()) => debug!("Removed partial file {:?}", partial_path),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to remove partial file {:?}: {}", partial_path, e),
+        }
+
+        if let Err(e) = transfer_state.delete_from_disk() {
+            warn!("Failed to remove transfer state: {}", e);
+        }
+    }
+
     async fn load_or_create_transfer_state(&self, total_size: u64) -> Result<TransferState> {
         let source_str = self.source_path.to_string_lossy();
         let dest_str = self.destination_path.to_string_lossy();
 
         // Try to load existing state
-        if let Some(existing_state) = TransferState::find_existing_transfer(&source_str, &dest_str)? {
+        if let Some(mut existing_state) = TransferState::find_existing_transfer(&source_str, &dest_str)? {
             // Validate that the existing state matches current parameters
             if existing_state.total_size == total_size 
                 && existing_state.streams == self.options.streams 
@@ -135,6 +304,12 @@ impl TransferEngine {
                 
                 info!("Found existing transfer state, resuming from {:.1}% complete",
                       existing_state.get_completion_percentage());
+
+                // Move the state to where this run keeps it
+                if existing_state.sidecar != self.options.sidecar_state {
+                    existing_state.delete_from_disk()?;
+                    existing_state.sidecar = self.options.sidecar_state;
+                }
                 return Ok(existing_state);
             } else {
                 warn!("Existing transfer state incompatible with current parameters, starting fresh");
@@ -156,7 +331,7 @@ impl TransferEngine {
             total_size,
             self.options.streams,
             self.options.compress,
-        );
+        ).with_sidecar(self.options.sidecar_state);
 
         state.initialize_chunks();
         Ok(state)
@@ -167,15 +342,22 @@ impl TransferEngine {
         chunk_id: u32,
         chunk_state: &ChunkState,
         transfer_state: &TransferState,
+        dest_path: &Path,
         progress_tx: mpsc::Sender<TransferMessage>,
     ) -> JoinHandle<Result<()>> {
         let source_path = self.source_path.clone();
-        let dest_path = self.destination_path.clone();
+        let dest_path = dest_path.to_path_buf();
         let buffer_size = self.options.buffer_size;
+        let sparse = self.options.sparse;
+        let zero_holes = self.options.zero_holes;
         let chunk_state = chunk_state.clone();
-        let transfer_id = transfer_state.transfer_id.clone();
+        let total_size = transfer_state.total_size;
+        let state_file = transfer_state.state_file();
         
         tokio::spawn(async move {
+            let state_file = state_file?;
+
+
             // Calculate actual transfer range (accounting for already completed bytes)
             let start_offset = chunk_state.start_offset + chunk_state.bytes_completed;
             let end_offset = chunk_state.end_offset;
@@ -196,52 +378,100 @@ impl TransferEngine {
             let mut source_file = tokio::fs::File::open(&source_path).await
                 .context("Failed to open source file")?;
 
-            // Seek to the correct position
-            source_file.seek(SeekFrom::Start(start_offset)).await
-                .context("Failed to seek in source file")?;
-
-            // Open/create destination file for this chunk
+            // Open destination file for this chunk
             let mut dest_file = tokio::fs::OpenOptions::new()
-                .create(true)
                 .write(true)
                 .open(&dest_path).await
                 .context("Failed to open destination file")?;
 
-            // Seek to the correct position in destination
-            dest_file.seek(SeekFrom::Start(start_offset)).await
-                .context("Failed to seek in destination file")?;
+            // Sparse copies skip the source's holes and punch matching ones
+            // into the destination, which may hold older data
+            let extents = if sparse {
+                let source = source_file.try_clone().await?.into_std().await;
+                tokio::task::spawn_blocking(move || {
+                    sparse::extents(&source, start_offset..end_offset)
+                }).await.context("Extent scan panicked")??
+            } else {
+                vec![Extent::Data(start_offset..end_offset)]
+            };
+            let hole_file = match sparse || zero_holes {
+                true => Some(Arc::new(dest_file.try_clone().await?.into_std().await)),
+                false => None,
+            };
 
             // Transfer data
             let mut buffer = vec![0u8; buffer_size];
             let mut bytes_transferred = 0u64;
             let mut total_chunk_bytes = chunk_state.bytes_completed;
+            let mut reported = 0u64;
+            let mut next_report = buffer_size as u64 * 10;
+
+            for extent in extents {
+                let range = match extent {
+                    Extent::Hole(range) => {
+                        let file = hole_file.clone().context("Hole in a dense transfer")?;
+                        punch_hole(file, range.start, range.end - range.start).await?;
+                        bytes_transferred += range.end - range.start;
+                        total_chunk_bytes += range.end - range.start;
+                        continue;
+                    }
+                    Extent::Data(range) => range,
+                };
+
+                // Seek to the correct position
+                source_file.seek(SeekFrom::Start(range.start)).await
+                    .context("Failed to seek in source file")?;
+                dest_file.seek(SeekFrom::Start(range.start)).await
+                    .context("Failed to seek in destination file")?;
+
+                let mut position = range.start;
+                while position < range.end {
+                    let to_read = buffer_size.min((range.end - position) as usize);
+                    let bytes_read = source_file.read(&mut buffer[..to_read]).await
+                        .context("Failed to read from source file")?;
+
+                    if bytes_read == 0 {
+                        break; // EOF
+                    }
 
-            while bytes_transferred < remaining_bytes {
-                let to_read = buffer_size.min((remaining_bytes - bytes_transferred) as usize);
-                let bytes_read = source_file.read(&mut buffer[..to_read]).await
-                    .context("Failed to read from source file")?;
-
-                if bytes_read == 0 {
-                    break; // EOF
-                }
+                    match hole_file.as_ref().filter(|_| zero_holes) {
+                        // Zero blocks in the data become holes as well
+                        Some(file) => {
+                            for (run, zero) in sparse::zero_runs(&buffer[..bytes_read]) {
+                                if zero {
+                                    punch_hole(file.clone(), position + run.start as u64, run.len() as u64).await?;
+                                    dest_file.seek(SeekFrom::Current(run.len() as i64)).await
+                                        .context("Failed to seek in destination file")?;
+                                } else {
+                                    dest_file.write_all(&buffer[run]).await
+                                        .context("Failed to write to destination file")?;
+                                }
+                            }
+                        }
+                        None => {
+                            dest_file.write_all(&buffer[..bytes_read]).await
+                                .context("Failed to write to destination file")?;
+                        }
+                    }
 
-                dest_file.write_all(&buffer[..bytes_read]).await
-                    .context("Failed to write to destination file")?;
+                    position += bytes_read as u64;
+                    bytes_transferred += bytes_read as u64;
+                    total_chunk_bytes += bytes_read as u64;
 
-                bytes_transferred += bytes_read as u64;
-                total_chunk_bytes += bytes_read as u64;
+                    // Update progress periodically
+                    if bytes_transferred >= next_report {
+                        next_report = bytes_transferred + buffer_size as u64 * 10;
+                        // Load current state, update, and save
+                        if let Ok(Some(mut state)) = TransferState::load_from_file(&state_file) {
+                            state.update_chunk_progress(chunk_id, total_chunk_bytes);
+                            let _ = state.save_to_disk();
+                        }
 
-                // Update progress periodically
-                if bytes_transferred % (buffer_size as u64 * 10) == 0 {
-                    // Load current state, update, and save
-                    if let Ok(Some(mut state)) = TransferState::load_from_disk(&transfer_id) {
-                        state.update_chunk_progress(chunk_id, total_chunk_bytes);
-                        let _ = state.save_to_disk();
-                        
                         let _ = progress_tx.send(TransferMessage::Progress {
-                            bytes_transferred: state.bytes_transferred,
-                            total_bytes: state.total_size,
+                            bytes_transferred: bytes_transferred - reported,
+                            total_bytes: total_size,
                         }).await;
+                        reported = bytes_transferred;
                     }
                 }
             }
@@ -253,16 +483,16 @@ impl TransferEngine {
                 .context("Failed to sync destination file")?;
 
             // Mark chunk as complete
-            if let Ok(Some(mut state)) = TransferState::load_from_disk(&transfer_id) {
+            if let Ok(Some(mut state)) = TransferState::load_from_file(&state_file) {
                 state.mark_chunk_complete(chunk_id, None); // TODO: Add chunk-level checksums
                 let _ = state.save_to_disk();
-                
-                let _ = progress_tx.send(TransferMessage::Progress {
-                    bytes_transferred: state.bytes_transferred,
-                    total_bytes: state.total_size,
-                }).await;
             }
 
+            let _ = progress_tx.send(TransferMessage::Progress {
+                bytes_transferred: bytes_transferred - reported,
+                total_bytes: total_size,
+            }).await;
+
             debug!("Stream {} completed successfully", chunk_id);
             Ok(())
         })
@@ -287,6 +517,13 @@ impl TransferEngine {
 
     pub async fn cancel_transfer(transfer_id: &str) -> Result<bool> {
         if let Some(state) = TransferState::load_from_disk(transfer_id)? {
+            if let Some(holder) = TransferLock::holder(&state.transfer_id)? {
+                anyhow::bail!(
+                    "Transfer {} is still running as pid {} on {}",
+                    transfer_id, holder.pid, holder.hostname
+                );
+            }
+
             state.delete_from_disk()
                 .context("Failed to delete transfer state")?;
             Ok(true)
@@ -299,4 +536,131 @@ impl TransferEngine {
         crate::transfer::state::cleanup_old_transfers(max_age_days)
             .context("Failed to cleanup old transfers")
     }
-}
\ No newline at end of file
+}
+
+/// Make a range of the destination read as zeros without writing them
+async fn punch_hole(file: Arc<std::fs::File>, offset: u64, len: u64) -> Result<()> {
+    tokio::task::spawn_blocking(move || platform::punch_hole(&file, offset, len))
+        .await
+        .context("Hole punching panicked")?
+        .with_context(|| format!("Failed to punch a hole of {} bytes at {}", len, offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Options that keep transfer state inside the test's directory
+    fn options() -> TransferOptions {
+        TransferOptions {
+            streams: 3,
+            buffer_size: 4096,
+            sidecar_state: true,
+            ..TransferOptions::default()
+        }
+    }
+
+    fn write_source(dir: &Path, len: usize) -> (PathBuf, Vec<u8>) {
+        let source = dir.join("source.dat");
+        let data: Vec<u8> = (0..len).map(|i| (i % 253) as u8).collect();
+        std::fs::write(&source, &data).unwrap();
+        (source, data)
+    }
+
+    async fn copy(source: &Path, destination: &Path, options: TransferOptions) -> Result<()> {
+        let (tx, mut rx) = mpsc::channel(100);
+        tokio::spawn(async move { while rx.recv().await.is_some() {} });
+        TransferEngine::new(source.to_path_buf(), destination.to_path_buf(), options)
+            .copy(&tx)
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_renames_partial_on_success() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, data) = write_source(dir.path(), 100_000);
+        let destination = dir.path().join("copy.dat");
+
+        copy(&source, &destination, options()).await.unwrap();
+
+        assert_eq!(std::fs::read(&destination).unwrap(), data);
+        assert!(!dir.path().join(".copy.dat.bbcpr-partial").exists());
+    }
+
+    #[tokio::test]
+    async fn test_keeps_partial_on_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, data) = write_source(dir.path(), 50_000);
+        // Renaming a file over a directory fails once the data is written
+        let destination = dir.path().join("copy.dat");
+        std::fs::create_dir(&destination).unwrap();
+        let partial = dir.path().join(".copy.dat.bbcpr-partial");
+
+        let dropped = TransferOptions { force: true, ..options() };
+        assert!(copy(&source, &destination, dropped).await.is_err());
+        assert!(!partial.exists());
+
+        let kept = TransferOptions { force: true, keep_partial: true, ..options() };
+        assert!(copy(&source, &destination, kept).await.is_err());
+        assert_eq!(std::fs::read(&partial).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_refuses_existing_destination_without_force() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, data) = write_source(dir.path(), 10_000);
+        let destination = dir.path().join("copy.dat");
+        std::fs::write(&destination, b"keep me").unwrap();
+
+        let error = copy(&source, &destination, options()).await.unwrap_err();
+        assert!(error.to_string().contains("-f/--force"));
+        assert_eq!(std::fs::read(&destination).unwrap(), b"keep me");
+
+        copy(&source, &destination, TransferOptions { force: true, ..options() }).await.unwrap();
+        assert_eq!(std::fs::read(&destination).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_in_place_copy_truncates_longer_destination() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, data) = write_source(dir.path(), 50_000);
+        let destination = dir.path().join("copy.dat");
+        std::fs::write(&destination, vec![0xFFu8; 200_000]).unwrap();
+
+        let in_place = TransferOptions { force: true, partial_suffix: None, ..options() };
+        copy(&source, &destination, in_place).await.unwrap();
+
+        assert_eq!(std::fs::read(&destination).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_empty_source_creates_empty_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, _) = write_source(dir.path(), 0);
+        let destination = dir.path().join("copy.dat");
+
+        copy(&source, &destination, options()).await.unwrap();
+
+        assert_eq!(std::fs::metadata(&destination).unwrap().len(), 0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_preserve_applies_mode_and_times() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::time::{Duration, SystemTime};
+
+        let dir = tempfile::tempdir().unwrap();
+        let (source, _) = write_source(dir.path(), 20_000);
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        std::fs::File::options().write(true).open(&source).unwrap().set_modified(modified).unwrap();
+        std::fs::set_permissions(&source, std::fs::Permissions::from_mode(0o640)).unwrap();
+        let destination = dir.path().join("copy.dat");
+
+        copy(&source, &destination, TransferOptions { preserve: true, ..options() }).await.unwrap();
+
+        let metadata = std::fs::metadata(&destination).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o640);
+        assert_eq!(metadata.modified().unwrap(), modified);
+    }
+}
@@ -0,0 +1,260 @@
+// File list input (-I FILE)
+//
+// Newline-delimited lists hold one `SOURCE` or `SOURCE DEST` pair per line;
+// spaces inside paths are escaped with a backslash, blank lines and lines
+// starting with `#` are ignored. NUL-delimited lists (`find -print0`) hold
+// one source path per entry, taken verbatim.
+
+use anyhow::{Context, Result};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+
+use crate::transfer::engine::{TransferEngine, TransferMessage};
+use crate::transfer::tree::TreeTransfer;
+use crate::transfer::TransferOptions;
+
+/// One entry of a file list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileListEntry {
+    pub source: PathBuf,
+    /// Explicit target path, overriding the command-line destination
+    pub destination: Option<PathBuf>,
+    /// Line (or entry) number, for error messages
+    pub line: usize,
+}
+
+/// Read a file list from `path`, or from stdin when `path` is `-`.
+/// NUL-delimited input is detected automatically when `nul` is not set.
+pub fn read_file_list(path: &Path, nul: bool) -> Result<Vec<FileListEntry>> {
+    let mut contents = Vec::new();
+    if path == Path::new("-") {
+        std::io::stdin().read_to_end(&mut contents)
+            .context("Failed to read file list from stdin")?;
+    } else {
+        contents = std::fs::read(path)
+            .with_context(|| format!("Failed to read file list {:?}", path))?;
+    }
+
+    let nul = nul || contents.contains(&0);
+    parse_file_list(&contents, nul)
+}
+
+/// Parse file list contents
+pub fn parse_file_list(contents: &[u8], nul: bool) -> Result<Vec<FileListEntry>> {
+    let mut entries = Vec::new();
+
+    if nul {
+        for (index, entry) in contents.split(|&b| b == 0).enumerate() {
+            if entry.is_empty() {
+                continue;
+            }
+            entries.push(FileListEntry {
+                source: bytes_to_path(entry),
+                destination: None,
+                line: index + 1,
+            });
+        }
+        return Ok(entries);
+    }
+
+    for (index, line) in contents.split(|&b| b == b'\n').enumerate() {
+        let line_number = index + 1;
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.iter().all(u8::is_ascii_whitespace) || line.first() == Some(&b'#') {
+            continue;
+        }
+
+        let mut fields = split_escaped(line).into_iter();
+        let source = fields.next()
+            .with_context(|| format!("File list line {}: missing source", line_number))?;
+        let destination = fields.next();
+        if fields.next().is_some() {
+            anyhow::bail!(
+                "File list line {}: expected SOURCE or SOURCE DEST (escape spaces with '\\')",
+                line_number
+            );
+        }
+
+        entries.push(FileListEntry {
+            source: bytes_to_path(&source),
+            destination: destination.map(|d| bytes_to_path(&d)),
+            line: line_number,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Split on unescaped whitespace, resolving `\<char>` escapes
+fn split_escaped(line: &[u8]) -> Vec<Vec<u8>> {
+    let mut fields = Vec::new();
+    let mut current = Vec::new();
+    let mut bytes = line.iter();
+
+    while let Some(&b) = bytes.next() {
+        match b {
+            b'\\' => {
+                if let Some(&escaped) = bytes.next() {
+                    current.push(escaped);
+                }
+            }
+            b' ' | b'\t' => {
+                if !current.is_empty() {
+                    fields.push(std::mem::take(&mut current));
+                }
+            }
+            _ => current.push(b),
+        }
+    }
+    if !current.is_empty() {
+        fields.push(current);
+    }
+
+    fields
+}
+
+#[cfg(unix)]
+fn bytes_to_path(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn bytes_to_path(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Outcome of copying a file list
+#[derive(Debug, Default)]
+pub struct FileListReport {
+    pub copied: usize,
+    pub failures: Vec<(PathBuf, String)>,
+}
+
+/// Copies every entry of a file list, reporting failed entries
+/// individually instead of aborting the rest
+pub struct FileListTransfer {
+    options: TransferOptions,
+    entries: Vec<FileListEntry>,
+    destination: PathBuf,
+    recursive: bool,
+}
+
+impl FileListTransfer {
+    pub fn new(entries: Vec<FileListEntry>, destination: PathBuf, options: TransferOptions) -> Self {
+        Self {
+            options,
+            entries,
+            destination,
+            recursive: false,
+        }
+    }
+
+    /// Copy directory entries recursively instead of rejecting them
+    pub fn with_recursive(mut self, recursive: bool) -> Self {
+        self.recursive = recursive;
+        self
+    }
+
+    pub async fn copy(&self, progress_tx: &mpsc::Sender<TransferMessage>) -> Result<FileListReport> {
+        let semaphore = Arc::new(Semaphore::new(self.options.concurrent_files.max(1)));
+        let mut tasks = JoinSet::new();
+        let mut report = FileListReport::default();
+
+        for entry in &self.entries {
+            let metadata = match tokio::fs::metadata(&entry.source).await {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    warn!("File list entry {} ({:?}): {}", entry.line, entry.source, e);
+                    report.failures.push((entry.source.clone(), e.to_string()));
+                    continue;
+                }
+            };
+
+            let target = self.target_for(entry);
+            let source = entry.source.clone();
+            let options = self.options.clone();
+            let recursive = self.recursive;
+            let semaphore = semaphore.clone();
+            let progress_tx = progress_tx.clone();
+
+            tasks.spawn(async move {
+                let _permit = semaphore.acquire_owned().await;
+                let result = if metadata.is_dir() {
+                    if recursive {
+                        TreeTransfer::new(source.clone(), target, options).copy(&progress_tx).await
+                    } else {
+                        Err(anyhow::anyhow!("is a directory (use -r to copy it)"))
+                    }
+                } else {
+                    TransferEngine::new(source.clone(), target, options).copy(&progress_tx).await
+                };
+                (source, result)
+            });
+        }
+
+        while let Some(result) = tasks.join_next().await {
+            let (source, result) = result.context("File list task panicked")?;
+            match result {
+                Ok(()) => report.copied += 1,
+                Err(e) => {
+                    warn!("Failed to copy {:?}: {:#}", source, e);
+                    report.failures.push((source, format!("{:#}", e)));
+                }
+            }
+        }
+
+        info!("Copied {} of {} file list entries", report.copied, self.entries.len());
+        Ok(report)
+    }
+
+    /// Explicit targets are used as given; otherwise an existing destination
+    /// directory receives the entry under its own name
+    fn target_for(&self, entry: &FileListEntry) -> PathBuf {
+        if let Some(destination) = &entry.destination {
+            return destination.clone();
+        }
+
+        match entry.source.file_name() {
+            Some(name) if self.destination.is_dir() => self.destination.join(name),
+            _ => self.destination.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_newline_list_with_pairs() {
+        let list = b"# comment\n/data/a.dat\n\n/data/my\\ file.dat /backup/renamed.dat\r\n";
+        let entries = parse_file_list(list, false).unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].source, PathBuf::from("/data/a.dat"));
+        assert_eq!(entries[0].destination, None);
+        assert_eq!(entries[1].source, PathBuf::from("/data/my file.dat"));
+        assert_eq!(entries[1].destination, Some(PathBuf::from("/backup/renamed.dat")));
+        assert_eq!(entries[1].line, 4);
+    }
+
+    #[test]
+    fn test_parse_nul_list_is_verbatim() {
+        let list = b"./a b.txt\0./c\\d\0\0";
+        let entries = parse_file_list(list, true).unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].source, PathBuf::from("./a b.txt"));
+        assert_eq!(entries[1].source, PathBuf::from("./c\\d"));
+    }
+
+    #[test]
+    fn test_parse_rejects_extra_fields() {
+        assert!(parse_file_list(b"a b c\n", false).is_err());
+    }
+}
@@ -0,0 +1,323 @@
+// Include/exclude filters for recursive transfers
+//
+// Patterns follow rsync: a pattern without `/` matches the entry's name at
+// any depth, a leading `/` anchors it to the transfer root, a trailing `/`
+// matches directories only, and `**` matches across directories. Include
+// patterns override exclude patterns. Ignore files (e.g. `.gitignore`) use
+// gitignore semantics relative to the directory they are found in.
+
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobMatcher};
+use ignore::gitignore::Gitignore;
+use std::fs::Metadata;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+
+struct Pattern {
+    original: String,
+    matcher: GlobMatcher,
+    /// Matched against the whole relative path rather than the name
+    anchored: bool,
+    directories_only: bool,
+}
+
+impl Pattern {
+    fn new(pattern: &str) -> Result<Self> {
+        let directories_only = pattern.ends_with('/');
+        let trimmed = pattern.trim_end_matches('/');
+        let anchored = trimmed.contains('/');
+        let glob = trimmed.trim_start_matches('/');
+
+        let matcher = GlobBuilder::new(glob)
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("Invalid filter pattern {:?}", pattern))?
+            .compile_matcher();
+
+        Ok(Self {
+            original: pattern.to_string(),
+            matcher,
+            anchored,
+            directories_only,
+        })
+    }
+
+    fn matches(&self, relative: &Path, is_dir: bool) -> bool {
+        if self.directories_only && !is_dir {
+            return false;
+        }
+
+        if self.anchored {
+            self.matcher.is_match(relative)
+        } else {
+            relative.file_name().is_some_and(|name| self.matcher.is_match(name))
+        }
+    }
+}
+
+/// Ignore files found in the directories above an entry, innermost last
+#[derive(Clone, Default)]
+pub struct IgnoreStack(Vec<Arc<Gitignore>>);
+
+impl IgnoreStack {
+    /// Whether the innermost ignore file with an opinion ignores `path`
+    fn ignores(&self, path: &Path, is_dir: bool) -> Option<String> {
+        for gitignore in self.0.iter().rev() {
+            let matched = gitignore.matched(path, is_dir);
+            if matched.is_whitelist() {
+                return None;
+            }
+            if let Some(glob) = matched.inner().filter(|_| matched.is_ignore()) {
+                return Some(format!(
+                    "ignored by {:?} pattern {:?}",
+                    glob.from().unwrap_or_else(|| gitignore.path()),
+                    glob.original()
+                ));
+            }
+        }
+        None
+    }
+}
+
+/// Decides which entries of a tree are transferred
+#[derive(Default)]
+pub struct Filter {
+    includes: Vec<Pattern>,
+    excludes: Vec<Pattern>,
+    ignore_files: Vec<String>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    newer_than: Option<SystemTime>,
+}
+
+impl Filter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn include(mut self, pattern: &str) -> Result<Self> {
+        self.includes.push(Pattern::new(pattern)?);
+        Ok(self)
+    }
+
+    pub fn exclude(mut self, pattern: &str) -> Result<Self> {
+        self.excludes.push(Pattern::new(pattern)?);
+        Ok(self)
+    }
+
+    /// Add exclude patterns from a file, one per line; blank lines and
+    /// lines starting with `#` or `;` are ignored
+    pub fn exclude_from(mut self, path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read exclude file {:?}", path))?;
+        for line in contents.lines().map(str::trim_end) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            self = self.exclude(line)?;
+        }
+        Ok(self)
+    }
+
+    /// Honour ignore files with this name (e.g. `.gitignore`) in every directory
+    pub fn ignore_file(mut self, name: &str) -> Self {
+        self.ignore_files.push(name.to_string());
+        self
+    }
+
+    pub fn min_size(mut self, size: Option<u64>) -> Self {
+        self.min_size = size;
+        self
+    }
+
+    pub fn max_size(mut self, size: Option<u64>) -> Self {
+        self.max_size = size;
+        self
+    }
+
+    /// Only transfer files modified after `time`
+    pub fn newer_than(mut self, time: Option<SystemTime>) -> Self {
+        self.newer_than = time;
+        self
+    }
+
+    /// Load the ignore files in `directory` on top of its parent's stack
+    pub fn enter_directory(&self, directory: &Path, parent: &IgnoreStack) -> IgnoreStack {
+        let mut stack = parent.clone();
+        for name in &self.ignore_files {
+            let path = directory.join(name);
+            if !path.is_file() {
+                continue;
+            }
+
+            let (gitignore, error) = Gitignore::new(&path);
+            if let Some(e) = error {
+                warn!("Problem reading ignore file {:?}: {}", path, e);
+            }
+            if !gitignore.is_empty() {
+                stack.0.push(Arc::new(gitignore));
+            }
+        }
+        stack
+    }
+
+    /// Why the entry at `relative` (absolute path `path`) should be skipped,
+    /// or `None` to transfer it. Size and age limits apply to files only.
+    pub fn skip_reason(
+        &self,
+        relative: &Path,
+        path: &Path,
+        metadata: &Metadata,
+        ignores: &IgnoreStack,
+    ) -> Option<String> {
+        let is_dir = metadata.is_dir();
+
+        if let Some(reason) = ignores.ignores(path, is_dir) {
+            return Some(reason);
+        }
+
+        if !self.includes.iter().any(|p| p.matches(relative, is_dir)) {
+            if let Some(pattern) = self.excludes.iter().find(|p| p.matches(relative, is_dir)) {
+                return Some(format!("excluded by pattern {:?}", pattern.original));
+            }
+        }
+
+        if is_dir {
+            return None;
+        }
+
+        let size = metadata.len();
+        if let Some(min) = self.min_size.filter(|&min| size < min) {
+            return Some(format!("size {} is below --min-size {}", size, min));
+        }
+        if let Some(max) = self.max_size.filter(|&max| size > max) {
+            return Some(format!("size {} is above --max-size {}", size, max));
+        }
+
+        if let Some(newer_than) = self.newer_than {
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            if modified <= newer_than {
+                return Some("not modified within --newer-than".to_string());
+            }
+        }
+
+        None
+    }
+}
+
+/// Parse a size such as `4096`, `64K`, `1.5M` or `2G` (binary units)
+pub fn parse_size(value: &str) -> Result<u64> {
+    let value = value.trim();
+    let split = value.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().trim_end_matches(['B', 'I']) {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => anyhow::bail!("Invalid size {:?}: unknown unit {:?}", value, unit),
+    };
+
+    let number: f64 = number.parse()
+        .with_context(|| format!("Invalid size {:?}", value))?;
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Parse an age such as `90s`, `30m`, `12h`, `7d` or `2w` into the cutoff
+/// time that many seconds ago, or `@<unix seconds>` for an absolute time
+pub fn parse_newer_than(value: &str) -> Result<SystemTime> {
+    let value = value.trim();
+    if let Some(epoch) = value.strip_prefix('@') {
+        let seconds: u64 = epoch.parse()
+            .with_context(|| format!("Invalid timestamp {:?}", value))?;
+        return Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds));
+    }
+
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse()
+        .with_context(|| format!("Invalid age {:?}", value))?;
+
+    let seconds = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => anyhow::bail!("Invalid age {:?}: use s, m, h, d or w", value),
+    };
+
+    Ok(SystemTime::now() - Duration::from_secs(number * seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(filter: &Filter, dir: &Path, relative: &str) -> Option<String> {
+        let path = dir.join(relative);
+        let metadata = std::fs::metadata(&path).unwrap();
+        filter.skip_reason(Path::new(relative), &path, &metadata, &IgnoreStack::default())
+    }
+
+    #[test]
+    fn test_patterns() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("src/build")).unwrap();
+        std::fs::write(dir.path().join("src/main.o"), b"").unwrap();
+        std::fs::write(dir.path().join("src/keep.o"), b"").unwrap();
+        std::fs::write(dir.path().join("src/main.c"), b"").unwrap();
+
+        let filter = Filter::new()
+            .exclude("*.o").unwrap()
+            .exclude("build/").unwrap()
+            .include("/src/keep.o").unwrap();
+
+        assert!(check(&filter, dir.path(), "src/main.o").unwrap().contains("*.o"));
+        assert!(check(&filter, dir.path(), "src/build").is_some());
+        assert!(check(&filter, dir.path(), "src/keep.o").is_none());
+        assert!(check(&filter, dir.path(), "src/main.c").is_none());
+    }
+
+    #[test]
+    fn test_size_limits_skip_files_only() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("small"), vec![0u8; 10]).unwrap();
+
+        let filter = Filter::new().min_size(Some(100));
+        assert!(check(&filter, dir.path(), "small").unwrap().contains("--min-size"));
+        assert!(check(&filter, dir.path(), "sub").is_none());
+    }
+
+    #[test]
+    fn test_ignore_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(".gitignore"), "*.log\n!important.log\n").unwrap();
+        std::fs::write(dir.path().join("debug.log"), b"").unwrap();
+        std::fs::write(dir.path().join("important.log"), b"").unwrap();
+
+        let filter = Filter::new().ignore_file(".gitignore");
+        let stack = filter.enter_directory(dir.path(), &IgnoreStack::default());
+
+        let skip = |name: &str| {
+            let path = dir.path().join(name);
+            filter.skip_reason(Path::new(name), &path, &std::fs::metadata(&path).unwrap(), &stack)
+        };
+        assert!(skip("debug.log").is_some());
+        assert!(skip("important.log").is_none());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("64K").unwrap(), 65536);
+        assert_eq!(parse_size("1.5M").unwrap(), 1572864);
+        assert_eq!(parse_size("2GiB").unwrap(), 2 << 30);
+        assert!(parse_size("12Q").is_err());
+    }
+}
@@ -0,0 +1,362 @@
+// Advisory per-transfer locking
+
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+use crate::error::{BbcprError, Result};
+use crate::platform;
+use crate::transfer::state;
+
+/// Contents of a transfer's `.lock` file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockInfo {
+    pub pid: u32,
+    pub hostname: String,
+    pub timestamp: u64,
+}
+
+impl LockInfo {
+    fn current() -> Self {
+        Self {
+            pid: std::process::id(),
+            hostname: platform::hostname(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+
+    /// Stands in for the holder of a lock that can't be read yet
+    fn unknown() -> Self {
+        Self {
+            pid: 0,
+            hostname: "unknown".to_string(),
+            timestamp: 0,
+        }
+    }
+
+    /// A lock is stale when it was taken on this host by a process that no
+    /// longer exists. Locks held on other hosts cannot be checked and are
+    /// always treated as live.
+    fn is_stale(&self) -> bool {
+        self.hostname == platform::hostname() && !platform::process_alive(self.pid)
+    }
+}
+
+/// How long an empty or unparseable lock file is taken to belong to a
+/// process still writing it, and how long a lock breaker may take
+const LOCK_GRACE: Duration = Duration::from_secs(30);
+
+/// Tells apart the private files of concurrent acquires in one process
+static PRIVATE_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// What a lock file holds
+enum LockFile {
+    Free,
+    Held(LockInfo),
+    /// Empty or unparseable; `recent` while its writer may still be filling it
+    Unreadable { recent: bool },
+}
+
+/// Advisory lock preventing two bbcpr processes from running the same
+/// transfer at once. The lock is `<state dir>/<transfer id>.lock` and is
+/// removed when the guard is dropped.
+#[derive(Debug)]
+pub struct TransferLock {
+    path: PathBuf,
+}
+
+impl TransferLock {
+    /// Acquire the lock for `transfer_id`
+    pub fn acquire(transfer_id: &str) -> Result<Self> {
+        Self::acquire_at(lock_path(transfer_id)?, transfer_id)
+    }
+
+    fn acquire_at(path: PathBuf, transfer_id: &str) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let info = LockInfo::current();
+
+        // A stale lock is broken and acquisition retried; if another
+        // process wins the race in between, its lock is reported instead.
+        for _ in 0..3 {
+            if publish(&path, &info)? {
+                debug!("Acquired lock for transfer {}", transfer_id);
+                return Ok(Self { path });
+            }
+
+            match read_lock(&path)? {
+                LockFile::Held(holder) if holder.is_stale() => {
+                    warn!(
+                        "Removing stale lock for transfer {} (pid {} on {} is gone)",
+                        transfer_id, holder.pid, holder.hostname
+                    );
+                    break_stale_lock(&path)?;
+                }
+                LockFile::Held(holder) => return Err(locked(transfer_id, holder)),
+                LockFile::Unreadable { recent: true } => return Err(locked(transfer_id, LockInfo::unknown())),
+                LockFile::Unreadable { recent: false } => {
+                    warn!("Removing unreadable lock for transfer {}", transfer_id);
+                    break_stale_lock(&path)?;
+                }
+                // Released in between, try again
+                LockFile::Free => {}
+            }
+        }
+
+        let holder = match read_lock(&path)? {
+            LockFile::Held(holder) => holder,
+            _ => LockInfo::unknown(),
+        };
+        Err(locked(transfer_id, holder))
+    }
+
+    /// Return the live holder of the lock for `transfer_id`, if any
+    pub fn holder(transfer_id: &str) -> Result<Option<LockInfo>> {
+        holder_at(&lock_path(transfer_id)?)
+    }
+}
+
+fn holder_at(path: &Path) -> Result<Option<LockInfo>> {
+    Ok(match read_lock(path)? {
+        LockFile::Held(info) => Some(info).filter(|info| !info.is_stale()),
+        LockFile::Unreadable { recent: true } => Some(LockInfo::unknown()),
+        LockFile::Unreadable { recent: false } | LockFile::Free => None,
+    })
+}
+
+impl Drop for TransferLock {
+    fn drop(&mut self) {
+        if let Err(e) = remove_lock_file(&self.path) {
+            warn!("Failed to release transfer lock {:?}: {}", self.path, e);
+        }
+    }
+}
+
+fn lock_path(transfer_id: &str) -> Result<PathBuf> {
+    Ok(state::get_state_directory()?.join(format!("{}.lock", transfer_id)))
+}
+
+/// `path` with `suffix` appended to its file name
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn locked(transfer_id: &str, holder: LockInfo) -> BbcprError {
+    BbcprError::TransferLocked {
+        transfer_id: transfer_id.to_string(),
+        pid: holder.pid,
+        hostname: holder.hostname,
+    }
+}
+
+/// Create the lock file at `path` holding `info`, unless it exists. The
+/// contents go to a private file first, which is then hard-linked into
+/// place, so no other process ever sees the lock half written.
+fn publish(path: &Path, info: &LockInfo) -> Result<bool> {
+    let contents = serde_json::to_string(info)?;
+    let private = with_suffix(path, &format!(
+        ".{}-{}.tmp", std::process::id(), PRIVATE_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let published = (|| {
+        let mut file = OpenOptions::new().write(true).create_new(true).open(&private)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        match fs::hard_link(&private, path) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(false),
+            // Without hard links the file is created in place; while it is
+            // still empty, read_lock takes it as held
+            Err(e) => {
+                debug!("Hard-linking {:?} failed ({}); creating the lock in place", path, e);
+                match OpenOptions::new().write(true).create_new(true).open(path) {
+                    Ok(mut file) => {
+                        file.write_all(contents.as_bytes())?;
+                        file.sync_all()?;
+                        Ok(true)
+                    }
+                    Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(false),
+                    Err(e) => Err(BbcprError::Io(e)),
+                }
+            }
+        }
+    })();
+
+    remove_lock_file(&private)?;
+    published
+}
+
+/// Remove the stale lock at `path`. Breakers take a `.break` lock and check
+/// the lock again under it, so a lock that was released and taken by a
+/// live process after it was found stale is never removed.
+fn break_stale_lock(path: &Path) -> Result<()> {
+    let breaker = with_suffix(path, ".break");
+    if !publish(&breaker, &LockInfo::current())? {
+        // Another process is breaking it. One that died doing so is
+        // cleared out once it can't still be at work.
+        if let LockFile::Held(holder) = read_lock(&breaker)? {
+            if holder.is_stale() || lock_age(&breaker)?.is_some_and(|age| age > LOCK_GRACE) {
+                remove_lock_file(&breaker)?;
+            }
+        }
+        return Ok(());
+    }
+
+    let result = match read_lock(path) {
+        Ok(LockFile::Held(holder)) if holder.is_stale() => remove_lock_file(path),
+        Ok(LockFile::Unreadable { recent: false }) => remove_lock_file(path),
+        Ok(_) => Ok(()),
+        Err(e) => Err(e),
+    };
+    remove_lock_file(&breaker)?;
+    result
+}
+
+fn read_lock(path: &Path) -> Result<LockFile> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(LockFile::Free),
+        Err(e) => return Err(BbcprError::Io(e)),
+    };
+
+    match serde_json::from_str(&contents) {
+        Ok(info) => Ok(LockFile::Held(info)),
+        Err(_) => match lock_age(path)? {
+            Some(age) => Ok(LockFile::Unreadable { recent: age <= LOCK_GRACE }),
+            None => Ok(LockFile::Free),
+        },
+    }
+}
+
+/// How long ago the lock file at `path` was last written, if it exists
+fn lock_age(path: &Path) -> Result<Option<Duration>> {
+    match fs::metadata(path) {
+        // A clock that went backwards makes the file look new
+        Ok(metadata) => Ok(Some(metadata.modified()?.elapsed().unwrap_or_default())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(BbcprError::Io(e)),
+    }
+}
+
+fn remove_lock_file(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(BbcprError::Io(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_second_acquire_fails_while_held() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("abc123.lock");
+        let _lock = TransferLock::acquire_at(path.clone(), "abc123").unwrap();
+
+        let err = TransferLock::acquire_at(path.clone(), "abc123").unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("abc123"));
+        assert!(message.contains(&format!("pid {}", std::process::id())));
+    }
+
+    #[test]
+    fn test_lock_lives_in_state_directory() {
+        let path = lock_path("abc123").unwrap();
+        assert_eq!(path.parent().unwrap(), state::get_state_directory().unwrap());
+        assert_eq!(path.file_name().unwrap(), "abc123.lock");
+    }
+
+    #[test]
+    fn test_lock_released_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("abc123.lock");
+        {
+            let _lock = TransferLock::acquire_at(path.clone(), "abc123").unwrap();
+            assert!(holder_at(&path).unwrap().is_some());
+        }
+        assert!(holder_at(&path).unwrap().is_none());
+        assert!(TransferLock::acquire_at(path.clone(), "abc123").is_ok());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_stale_lock_is_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let stale = LockInfo {
+            pid: i32::MAX as u32,
+            hostname: platform::hostname(),
+            timestamp: 0,
+        };
+        let path = dir.path().join("abc123.lock");
+        fs::write(&path, serde_json::to_string(&stale).unwrap()).unwrap();
+
+        assert!(holder_at(&path).unwrap().is_none());
+        assert!(TransferLock::acquire_at(path.clone(), "abc123").is_ok());
+    }
+
+    #[test]
+    fn test_lock_from_other_host_is_respected() {
+        let dir = tempfile::tempdir().unwrap();
+        let remote = LockInfo {
+            pid: 4242,
+            hostname: "some-other-host.invalid".to_string(),
+            timestamp: 0,
+        };
+        let path = dir.path().join("abc123.lock");
+        fs::write(&path, serde_json::to_string(&remote).unwrap()).unwrap();
+
+        let err = TransferLock::acquire_at(path.clone(), "abc123").unwrap_err();
+        assert!(err.to_string().contains("pid 4242"));
+    }
+
+    #[test]
+    fn test_empty_lock_is_held_until_old() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("abc123.lock");
+        // As left by a process between creating the file and writing it
+        fs::write(&path, b"").unwrap();
+
+        assert!(TransferLock::acquire_at(path.clone(), "abc123").is_err());
+        assert!(holder_at(&path).unwrap().is_some());
+        assert!(path.exists());
+
+        let old = SystemTime::now() - LOCK_GRACE * 2;
+        fs::File::options().write(true).open(&path).unwrap().set_modified(old).unwrap();
+        assert!(holder_at(&path).unwrap().is_none());
+        assert!(TransferLock::acquire_at(path.clone(), "abc123").is_ok());
+    }
+
+    #[test]
+    fn test_concurrent_acquire_has_one_winner() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("abc123.lock");
+        let barrier = std::sync::Barrier::new(8);
+
+        let winners: Vec<TransferLock> = std::thread::scope(|scope| {
+            let attempts: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| {
+                    barrier.wait();
+                    TransferLock::acquire_at(path.clone(), "abc123")
+                }))
+                .collect();
+            attempts.into_iter().filter_map(|attempt| attempt.join().unwrap().ok()).collect()
+        });
+
+        assert_eq!(winners.len(), 1);
+        // Only the lock itself is left behind
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
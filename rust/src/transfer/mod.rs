@@ -1,13 +1,30 @@
 // File transfer engine
 
-use anyhow::Result;
-use std::path::Path;
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+pub mod agent;
+pub mod batch;
+pub mod delta;
 pub mod engine;
+pub mod filelist;
+pub mod filter;
+pub mod lock;
+pub mod mode;
+pub mod pipe;
 pub mod progress;
+pub mod remote;
+pub mod space;
+pub mod sparse;
 pub mod state;
 pub mod stream;
+pub mod sync;
+pub mod third_party;
+pub mod tree;
+pub mod walker;
 
+#[derive(Clone)]
 pub struct TransferOptions {
     pub streams: u32,
     pub buffer_size: usize,
@@ -18,4 +35,92 @@ pub struct TransferOptions {
     pub force: bool,
     pub resume: bool,
     pub cleanup_on_success: bool,
-}
\ No newline at end of file
+    /// Keep transfer state next to the destination instead of in the state directory
+    pub sidecar_state: bool,
+    /// Write to `.<name>.<suffix>` and rename on success; `None` writes in place
+    pub partial_suffix: Option<String>,
+    /// Keep the partial file when the transfer fails
+    pub keep_partial: bool,
+    /// Check free space on the target before starting (disabled by -F)
+    pub space_check: bool,
+    /// Number of files copied at once in recursive transfers
+    pub concurrent_files: usize,
+    /// Files up to this size are batched into shared streams (0 disables)
+    pub batch_threshold: u64,
+    /// Include/exclude rules applied to recursive transfers
+    pub filter: Option<Arc<filter::Filter>>,
+    /// Leave up-to-date destinations alone (-O, --sync); changed ones are replaced
+    pub sync: Option<sync::SyncPolicy>,
+    /// Send only the blocks that differ from an existing destination
+    pub delta: bool,
+    /// Skip holes in the source and leave them as holes on the target
+    pub sparse: bool,
+    /// Turn runs of zero blocks in the data into holes on the target
+    pub zero_holes: bool,
+    /// Carry extended attributes and POSIX ACLs to the target
+    pub xattrs: bool,
+    /// Permission bits forced onto created files and directories (-m)
+    pub target_mode: mode::TargetMode,
+    /// How recursive transfers treat symbolic links
+    pub symlinks: walker::SymlinkPolicy,
+    /// Recreate hard links between copied files instead of copying the data again
+    pub hard_links: bool,
+    /// How recursive transfers treat FIFOs and device nodes
+    pub specials: walker::SpecialPolicy,
+}
+
+impl Default for TransferOptions {
+    fn default() -> Self {
+        Self {
+            streams: crate::DEFAULT_STREAMS,
+            buffer_size: crate::DEFAULT_BUFFER_SIZE,
+            window_size: crate::DEFAULT_WINDOW_SIZE,
+            compress: None,
+            checksum: false,
+            preserve: false,
+            force: false,
+            resume: false,
+            cleanup_on_success: true,
+            sidecar_state: false,
+            partial_suffix: Some("bbcpr-partial".to_string()),
+            keep_partial: false,
+            space_check: true,
+            concurrent_files: 4,
+            batch_threshold: batch::DEFAULT_BATCH_THRESHOLD,
+            filter: None,
+            sync: None,
+            delta: false,
+            sparse: false,
+            zero_holes: false,
+            xattrs: false,
+            target_mode: mode::TargetMode::default(),
+            symlinks: walker::SymlinkPolicy::default(),
+            hard_links: false,
+            specials: walker::SpecialPolicy::default(),
+        }
+    }
+}
+
+impl TransferOptions {
+    /// Whether an existing destination may be overwritten: with -f, or when
+    /// a sync policy other than -O has decided the destination is out of date
+    pub fn replaces_existing(&self) -> bool {
+        self.force || matches!(self.sync, Some(policy) if policy != sync::SyncPolicy::Existing)
+    }
+}
+
+/// Path data is written to until a transfer completes: `.<name>.<suffix>`
+/// next to the destination, or the destination itself when no partial
+/// suffix is configured
+pub fn partial_path(destination: &Path, suffix: Option<&str>) -> Result<PathBuf> {
+    let suffix = match suffix {
+        Some(suffix) if !suffix.is_empty() => suffix,
+        _ => return Ok(destination.to_path_buf()),
+    };
+
+    let name = destination.file_name()
+        .with_context(|| format!("Destination {:?} has no file name", destination))?;
+    let parent = destination.parent().unwrap_or_else(|| Path::new(""));
+
+    Ok(parent.join(format!(".{}.{}", name.to_string_lossy(), suffix)))
+}
@@ -0,0 +1,77 @@
+// Target permission modes (-m FMODE[%DMODE])
+
+use anyhow::{Context, Result};
+use std::str::FromStr;
+
+/// Permission bits forced onto created files and directories. Either may be
+/// unset, in which case the preserved (-p) or default mode is kept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TargetMode {
+    pub file: Option<u32>,
+    pub directory: Option<u32>,
+}
+
+impl TargetMode {
+    pub fn is_set(&self) -> bool {
+        self.file.is_some() || self.directory.is_some()
+    }
+
+    /// The mode for an entry of the given kind
+    pub fn for_entry(&self, is_dir: bool) -> Option<u32> {
+        if is_dir { self.directory } else { self.file }
+    }
+}
+
+impl FromStr for TargetMode {
+    type Err = anyhow::Error;
+
+    /// Parse `644`, `0640%0750` or `%755` (directories only)
+    fn from_str(value: &str) -> Result<Self> {
+        let (file, directory) = match value.split_once('%') {
+            Some((file, directory)) => (file, Some(directory)),
+            None => (value, None),
+        };
+
+        let mode = TargetMode {
+            file: parse_octal(file)?,
+            directory: directory.map(parse_octal).transpose()?.flatten(),
+        };
+        if !mode.is_set() {
+            anyhow::bail!("Invalid mode {:?}: expected FMODE[%DMODE], e.g. 644 or 0640%0750", value);
+        }
+        Ok(mode)
+    }
+}
+
+fn parse_octal(value: &str) -> Result<Option<u32>> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+
+    let mode = u32::from_str_radix(value, 8)
+        .with_context(|| format!("Invalid mode {:?}: not an octal number", value))?;
+    if mode > 0o7777 {
+        anyhow::bail!("Invalid mode {:?}: must be at most 7777", value);
+    }
+    Ok(Some(mode))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_target_mode() {
+        assert_eq!("644".parse::<TargetMode>().unwrap(), TargetMode { file: Some(0o644), directory: None });
+        assert_eq!(
+            "0640%0750".parse::<TargetMode>().unwrap(),
+            TargetMode { file: Some(0o640), directory: Some(0o750) }
+        );
+        assert_eq!("%755".parse::<TargetMode>().unwrap(), TargetMode { file: None, directory: Some(0o755) });
+
+        assert!("".parse::<TargetMode>().is_err());
+        assert!("%".parse::<TargetMode>().is_err());
+        assert!("0649".parse::<TargetMode>().is_err());
+        assert!("17777".parse::<TargetMode>().is_err());
+    }
+}
@@ -0,0 +1,599 @@
+// Pipe sources and sinks: stdin, stdout and programs (-N), like bbcp's
+// FS_Pipe and IO_Pipe, and the null source and sink (FS_Null) for
+// measuring the stream path without storage
+//
+// Pipes can't seek, so they can't be split into byte ranges like files.
+// Instead the source is read sequentially in blocks, each block gets a
+// sequence number and goes out on whichever stream is free, and the
+// receiving side writes blocks strictly in sequence order. A window of
+// in-flight blocks bounds how far the streams may run ahead of the sink.
+// The streams are TCP connections over loopback, so the null source and
+// sink measure the network stack rather than memory copies.
+
+use anyhow::{Context, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::process::{Child, Command};
+use tokio::sync::{mpsc, Mutex, Semaphore};
+use tokio::task::JoinSet;
+use tracing::{debug, info, warn};
+
+use crate::network::endpoint::Endpoint;
+use crate::network::protocol::{self, MessageType, ProtocolMessage};
+use crate::transfer::engine::TransferMessage;
+use crate::transfer::filter::parse_size;
+use crate::transfer::lock::TransferLock;
+use crate::transfer::{state, TransferOptions};
+
+/// Prefix of the null source (`null:SIZE`) and sink (`null:`)
+const NULL_PREFIX: &str = "null:";
+
+/// Blocks each stream may have in flight before the source is paused
+const BLOCKS_PER_STREAM: usize = 4;
+
+/// Which ends of a transfer are programs rather than paths (-N)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PipeMode {
+    /// The source is a command whose stdout is read
+    pub input: bool,
+    /// The destination is a command whose stdin is written
+    pub output: bool,
+}
+
+impl FromStr for PipeMode {
+    type Err = anyhow::Error;
+
+    /// Parse `i`, `o` or `io`
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "i" => Ok(PipeMode { input: true, output: false }),
+            "o" => Ok(PipeMode { input: false, output: true }),
+            "io" | "oi" => Ok(PipeMode { input: true, output: true }),
+            other => anyhow::bail!("Invalid pipe mode {:?}: expected i, o or io", other),
+        }
+    }
+}
+
+/// Where transferred data comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    File(Endpoint),
+    Stdin,
+    /// Shell command whose standard output is the data
+    Program(String),
+    /// This many bytes of zeros, generated without touching storage
+    Null(u64),
+}
+
+impl Source {
+    /// Interpret a SOURCE argument: `-` is stdin, `null:SIZE` generates
+    /// SIZE bytes, and with -N i the argument is a command line
+    pub fn parse(spec: &str, mode: PipeMode) -> Result<Self> {
+        if mode.input {
+            Ok(Source::Program(spec.to_string()))
+        } else if spec == "-" {
+            Ok(Source::Stdin)
+        } else if let Some(size) = spec.strip_prefix(NULL_PREFIX) {
+            if size.is_empty() {
+                anyhow::bail!("The null source needs a size, e.g. null:10G");
+            }
+            Ok(Source::Null(parse_size(size)?))
+        } else {
+            Ok(Source::File(Endpoint::parse(spec)?))
+        }
+    }
+
+    pub fn is_pipe(&self) -> bool {
+        !matches!(self, Source::File(_))
+    }
+
+    fn endpoint(&self) -> Option<&Endpoint> {
+        match self {
+            Source::File(endpoint) => Some(endpoint),
+            _ => None,
+        }
+    }
+
+    /// Open the source for reading, starting the program if there is one
+    async fn open(&self) -> Result<(Box<dyn AsyncRead + Send + Unpin>, Option<Child>)> {
+        match self {
+            Source::File(endpoint) => {
+                let path = local_path(endpoint)?;
+                let file = tokio::fs::File::open(&path).await
+                    .with_context(|| format!("Failed to open {:?}", path))?;
+                Ok((Box::new(file), None))
+            }
+            Source::Stdin => Ok((Box::new(tokio::io::stdin()), None)),
+            Source::Program(command) => {
+                let mut child = shell(command)
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .with_context(|| format!("Failed to start source program {:?}", command))?;
+                let stdout = child.stdout.take().context("Source program has no stdout")?;
+                Ok((Box::new(stdout), Some(child)))
+            }
+            Source::Null(size) => Ok((Box::new(tokio::io::repeat(0).take(*size)), None)),
+        }
+    }
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::File(endpoint) => write!(f, "{}", endpoint),
+            Source::Stdin => write!(f, "stdin"),
+            Source::Program(command) => write!(f, "program `{}`", command),
+            Source::Null(size) => write!(f, "{} null bytes", size),
+        }
+    }
+}
+
+/// Where transferred data goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sink {
+    File(Endpoint),
+    Stdout,
+    /// Shell command whose standard input receives the data
+    Program(String),
+    /// Discards everything
+    Null,
+}
+
+impl Sink {
+    /// Interpret a DEST argument: `-` is stdout, `null:` discards the data,
+    /// and with -N o the argument is a command line
+    pub fn parse(spec: &str, mode: PipeMode) -> Result<Self> {
+        if mode.output {
+            Ok(Sink::Program(spec.to_string()))
+        } else if spec == "-" {
+            Ok(Sink::Stdout)
+        } else if let Some(rest) = spec.strip_prefix(NULL_PREFIX) {
+            if !rest.is_empty() {
+                anyhow::bail!("The null sink takes no arguments; use null:");
+            }
+            Ok(Sink::Null)
+        } else {
+            Ok(Sink::File(Endpoint::parse(spec)?))
+        }
+    }
+
+    pub fn is_pipe(&self) -> bool {
+        !matches!(self, Sink::File(_))
+    }
+
+    fn endpoint(&self) -> Option<&Endpoint> {
+        match self {
+            Sink::File(endpoint) => Some(endpoint),
+            _ => None,
+        }
+    }
+
+    /// Open the sink for writing, starting the program if there is one. A
+    /// file is written at `partial` and moved into place by `settle`.
+    async fn open(&self, partial: Option<&Path>, replace: bool) -> Result<(Box<dyn AsyncWrite + Send + Unpin>, Option<Child>)> {
+        match self {
+            Sink::File(endpoint) => {
+                let path = local_path(endpoint)?;
+                if !replace && tokio::fs::try_exists(&path).await? {
+                    anyhow::bail!("Destination {:?} already exists; use -f/--force to replace it", path);
+                }
+                let partial = partial.unwrap_or(&path);
+                let file = tokio::fs::File::create(partial).await
+                    .with_context(|| format!("Failed to create {:?}", partial))?;
+                Ok((Box::new(file), None))
+            }
+            Sink::Stdout => Ok((Box::new(tokio::io::stdout()), None)),
+            Sink::Program(command) => {
+                let mut child = shell(command)
+                    .stdin(Stdio::piped())
+                    .spawn()
+                    .with_context(|| format!("Failed to start target program {:?}", command))?;
+                let stdin = child.stdin.take().context("Target program has no stdin")?;
+                Ok((Box::new(stdin), Some(child)))
+            }
+            Sink::Null => Ok((Box::new(tokio::io::sink()), None)),
+        }
+    }
+}
+
+impl std::fmt::Display for Sink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Sink::File(endpoint) => write!(f, "{}", endpoint),
+            Sink::Stdout => write!(f, "stdout"),
+            Sink::Program(command) => write!(f, "program `{}`", command),
+            Sink::Null => write!(f, "null sink"),
+        }
+    }
+}
+
+/// Refuse remote file ends before anything is opened. The null source and
+/// sink measure the stream path on this host, so they can't stand in for
+/// one end of a transfer to another host.
+pub fn check_ends(source: &Source, sink: &Sink) -> Result<()> {
+    let remote = [source.endpoint(), sink.endpoint()]
+        .into_iter()
+        .flatten()
+        .find(|endpoint| endpoint.is_remote());
+    match remote {
+        Some(remote) if matches!(source, Source::Null(_)) || matches!(sink, Sink::Null) => {
+            anyhow::bail!("null: transfers measure streams on this host only; {} is remote", remote)
+        }
+        Some(remote) => local_path(remote).map(drop),
+        None => Ok(()),
+    }
+}
+
+/// The path of a file end; pipes are read and written on this host only
+fn local_path(endpoint: &Endpoint) -> Result<PathBuf> {
+    match endpoint {
+        Endpoint::Local(path) => Ok(path.clone()),
+        remote => anyhow::bail!("Pipe transfers read and write local files only; {} is remote", remote),
+    }
+}
+
+/// Move a completed file sink into place, or keep or remove its partial
+/// file after a failure
+async fn settle(target: &Path, partial: &Path, succeeded: bool, keep_partial: bool) -> Result<()> {
+    if partial == target {
+        return Ok(());
+    }
+    if succeeded {
+        tokio::fs::rename(partial, target).await
+            .with_context(|| format!("Failed to rename {:?} to {:?}", partial, target))?;
+    } else if keep_partial {
+        info!("Keeping partial file {:?}", partial);
+    } else if let Err(e) = tokio::fs::remove_file(partial).await {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!("Failed to remove partial file {:?}: {}", partial, e);
+        }
+    }
+    Ok(())
+}
+
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command).kill_on_drop(true);
+    shell
+}
+
+/// Wait for a source or target program and fail if it did
+async fn reap(child: Option<Child>, role: &str) -> Result<()> {
+    if let Some(mut child) = child {
+        let status = child.wait().await
+            .with_context(|| format!("Failed to wait for the {} program", role))?;
+        if !status.success() {
+            anyhow::bail!("The {} program failed: {}", role, status);
+        }
+    }
+    Ok(())
+}
+
+/// Copy `source` to `sink` over `options.streams` parallel streams with
+/// ordered delivery, returning the number of bytes written. A file sink is
+/// locked and written through its partial file like any other destination.
+pub async fn transfer(
+    source: &Source,
+    sink: &Sink,
+    options: &TransferOptions,
+    progress_tx: &mpsc::Sender<TransferMessage>,
+) -> Result<u64> {
+    let streams = options.streams as usize;
+    info!("Streaming from {} to {}", source, sink);
+
+    check_ends(source, sink)?;
+    let (target, _lock) = match sink {
+        Sink::File(endpoint) => {
+            let target = local_path(endpoint)?;
+            let transfer_id = state::generate_transfer_id(&source.to_string(), &target.to_string_lossy());
+            (Some(target), Some(TransferLock::acquire(&transfer_id)?))
+        }
+        _ => (None, None),
+    };
+    let partial = target.as_deref()
+        .map(|target| super::partial_path(target, options.partial_suffix.as_deref()))
+        .transpose()?;
+
+    let (reader, source_child) = source.open().await?;
+    let (writer, sink_child) = match sink.open(partial.as_deref(), options.replaces_existing()).await {
+        Ok(opened) => opened,
+        Err(e) => {
+            // Don't leave the source program blocked on a full pipe
+            if let Some(mut child) = source_child {
+                let _ = child.kill().await;
+            }
+            return Err(e);
+        }
+    };
+
+    let started = Instant::now();
+    let copied = copy_ordered(reader, writer, streams, options.buffer_size, progress_tx).await;
+
+    // A program that failed explains a short or broken stream best
+    let result = async {
+        reap(source_child, "source").await?;
+        reap(sink_child, "target").await?;
+        copied
+    }.await;
+    if let (Some(target), Some(partial)) = (&target, &partial) {
+        settle(target, partial, result.is_ok(), options.keep_partial).await?;
+    }
+
+    let copied = result?;
+    let seconds = started.elapsed().as_secs_f64();
+    info!(
+        "Streamed {} bytes in {:.2}s ({:.1} MB/s over {} streams)",
+        copied,
+        seconds,
+        copied as f64 / seconds.max(f64::EPSILON) / 1_000_000.0,
+        streams,
+    );
+    Ok(copied)
+}
+
+/// Read `reader` in blocks, spread them over `streams` framed TCP streams
+/// and write them to `writer` in their original order
+pub async fn copy_ordered<R, W>(
+    mut reader: R,
+    mut writer: W,
+    streams: usize,
+    block_size: usize,
+    progress_tx: &mpsc::Sender<TransferMessage>,
+) -> Result<u64>
+where
+    R: AsyncRead + Send + Unpin + 'static,
+    W: AsyncWrite + Unpin,
+{
+    let streams = streams.max(1);
+    let block_size = block_size.max(1);
+    let window = Arc::new(Semaphore::new(streams * BLOCKS_PER_STREAM));
+
+    // Blocks from the source, taken by whichever stream is free
+    let (block_tx, block_rx) = mpsc::channel::<(u64, Bytes)>(streams);
+    let block_rx = Arc::new(Mutex::new(block_rx));
+    // Blocks as they arrive from the streams, in any order
+    let (arrived_tx, mut arrived_rx) = mpsc::channel::<(u64, Bytes)>(streams * BLOCKS_PER_STREAM);
+
+    let mut tasks: JoinSet<Result<()>> = JoinSet::new();
+
+    let source_window = window.clone();
+    tasks.spawn(async move {
+        let mut sequence = 0u64;
+        loop {
+            source_window.acquire().await?.forget();
+
+            let mut block = BytesMut::zeroed(block_size);
+            let filled = fill(&mut reader, &mut block).await
+                .context("Failed to read from source")?;
+            if filled == 0 {
+                break;
+            }
+            block.truncate(filled);
+
+            if block_tx.send((sequence, block.freeze())).await.is_err() {
+                break;
+            }
+            sequence += 1;
+        }
+        debug!("Source finished after {} blocks", sequence);
+        Ok(())
+    });
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await
+        .context("Failed to listen on loopback")?;
+    let address = listener.local_addr()?;
+
+    for id in 0..streams {
+        let (mut stream_tx, (mut stream_rx, _)) = tokio::try_join!(TcpStream::connect(address), listener.accept())
+            .with_context(|| format!("Failed to open stream {} over loopback", id))?;
+        stream_tx.set_nodelay(true)?;
+        let block_rx = block_rx.clone();
+        tasks.spawn(async move {
+            loop {
+                let next = block_rx.lock().await.recv().await;
+                let Some((sequence, data)) = next else { break };
+
+                let mut payload = BytesMut::with_capacity(8 + data.len());
+                payload.put_u64(sequence);
+                payload.put(data);
+                let message = ProtocolMessage::new(MessageType::DataChunk, payload.freeze());
+                protocol::write_message(&mut stream_tx, &message).await?;
+            }
+            let complete = ProtocolMessage::new(MessageType::Complete, Bytes::new());
+            protocol::write_message(&mut stream_tx, &complete).await?;
+            stream_tx.shutdown().await?;
+            Ok(())
+        });
+
+        let arrived_tx = arrived_tx.clone();
+        tasks.spawn(async move {
+            loop {
+                let message = protocol::read_message(&mut stream_rx).await?
+                    .with_context(|| format!("Stream {} ended without a Complete record", id))?;
+                match message.message_type {
+                    MessageType::DataChunk => {
+                        let sequence = message.data.get(..8)
+                            .context("Data chunk without a sequence number")?;
+                        let sequence = u64::from_be_bytes(sequence.try_into()?);
+                        if arrived_tx.send((sequence, message.data.slice(8..))).await.is_err() {
+                            break;
+                        }
+                    }
+                    MessageType::Complete => break,
+                    other => anyhow::bail!("Unexpected {:?} record on stream {}", other, id),
+                }
+            }
+            Ok(())
+        });
+    }
+    drop(arrived_tx);
+
+    // Write blocks in sequence, holding back any that arrive early. A
+    // failed task ends the transfer rather than leaving the rest waiting.
+    let mut pending: BTreeMap<u64, Bytes> = BTreeMap::new();
+    let mut next = 0u64;
+    let mut written = 0u64;
+    let result: Result<()> = async {
+        loop {
+            tokio::select! {
+                arrived = arrived_rx.recv() => {
+                    let Some((sequence, data)) = arrived else { break };
+                    pending.insert(sequence, data);
+                    while let Some(data) = pending.remove(&next) {
+                        writer.write_all(&data).await.context("Failed to write to target")?;
+                        written += data.len() as u64;
+                        next += 1;
+                        window.add_permits(1);
+
+                        let _ = progress_tx.send(TransferMessage::Progress {
+                            bytes_transferred: data.len() as u64,
+                            total_bytes: 0,
+                        }).await;
+                    }
+                }
+                Some(task) = tasks.join_next() => {
+                    task.context("Pipe stream panicked")??;
+                }
+            }
+        }
+        writer.flush().await.context("Failed to flush target")?;
+        writer.shutdown().await.context("Failed to close target")?;
+
+        while let Some(task) = tasks.join_next().await {
+            task.context("Pipe stream panicked")??;
+        }
+        Ok(())
+    }.await;
+
+    if result.is_err() {
+        tasks.abort_all();
+    }
+    result?;
+
+    if let Some(sequence) = pending.keys().next() {
+        anyhow::bail!("Block {} arrived but block {} never did", sequence, next);
+    }
+    debug!("Wrote {} bytes in {} blocks", written, next);
+    Ok(written)
+}
+
+/// Read until `buffer` is full or the reader ends; pipes return short reads
+async fn fill<R: AsyncRead + Unpin>(reader: &mut R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let n = reader.read(&mut buffer[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(streams: u32, block_size: usize) -> TransferOptions {
+        TransferOptions { streams, buffer_size: block_size, ..Default::default() }
+    }
+
+    #[test]
+    fn test_parse_ends() {
+        assert_eq!("io".parse::<PipeMode>().unwrap(), PipeMode { input: true, output: true });
+        assert!("x".parse::<PipeMode>().is_err());
+
+        assert_eq!(Source::parse("-", PipeMode::default()).unwrap(), Source::Stdin);
+        assert_eq!(Source::parse("tar cf - .", "i".parse().unwrap()).unwrap(), Source::Program("tar cf - .".into()));
+        assert_eq!(Source::parse("null:2M", PipeMode::default()).unwrap(), Source::Null(2 << 20));
+        assert!(Source::parse("null:", PipeMode::default()).is_err());
+        assert_eq!(Sink::parse("-", PipeMode::default()).unwrap(), Sink::Stdout);
+        assert_eq!(Sink::parse("null:", PipeMode::default()).unwrap(), Sink::Null);
+        assert!(Sink::parse("null:x", PipeMode::default()).is_err());
+        assert_eq!(Sink::parse("out.tar", "i".parse().unwrap()).unwrap(), Sink::File(Endpoint::Local("out.tar".into())));
+        assert!(matches!(Sink::parse("node1:/out.tar", PipeMode::default()).unwrap(), Sink::File(Endpoint::Ssh { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_ordered_delivery() {
+        let data: Vec<u8> = (0..1_000_003u32).map(|i| (i % 251) as u8).collect();
+        let (progress_tx, mut progress_rx) = mpsc::channel(1024);
+        tokio::spawn(async move { while progress_rx.recv().await.is_some() {} });
+
+        let mut output = Vec::new();
+        let written = copy_ordered(std::io::Cursor::new(data.clone()), &mut output, 4, 4096, &progress_tx)
+            .await
+            .unwrap();
+        assert_eq!(written, data.len() as u64);
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn test_null_ends_stay_local() {
+        let null = Source::parse("null:1K", PipeMode::default()).unwrap();
+        let remote = Sink::parse("somehost:out", PipeMode::default()).unwrap();
+        let err = check_ends(&null, &remote).unwrap_err();
+        assert!(err.to_string().contains("somehost:out"));
+
+        let remote = Source::parse("somehost:in", PipeMode::default()).unwrap();
+        assert!(check_ends(&remote, &Sink::Null).is_err());
+        assert!(check_ends(&null, &Sink::parse("out", PipeMode::default()).unwrap()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_null_to_null() {
+        let (progress_tx, mut progress_rx) = mpsc::channel(1024);
+        tokio::spawn(async move { while progress_rx.recv().await.is_some() {} });
+
+        let copied = transfer(&Source::Null(10_000_001), &Sink::Null, &options(4, 65536), &progress_tx).await.unwrap();
+        assert_eq!(copied, 10_000_001);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_program_to_program() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("out");
+        let (progress_tx, mut progress_rx) = mpsc::channel(1024);
+        tokio::spawn(async move { while progress_rx.recv().await.is_some() {} });
+
+        let source = Source::Program("seq 1 20000".to_string());
+        let sink = Sink::Program(format!("cat > '{}'", target.display()));
+        transfer(&source, &sink, &options(3, 1000), &progress_tx).await.unwrap();
+
+        let expected: String = (1..=20000).map(|i| format!("{}\n", i)).collect();
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), expected);
+
+        let failing = Source::Program("echo partial; exit 3".to_string());
+        let sink = Sink::File(Endpoint::Local(dir.path().join("other")));
+        assert!(transfer(&failing, &sink, &options(2, 1000), &progress_tx).await.is_err());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_file_sink_is_written_through_partial() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("out");
+        let (progress_tx, mut progress_rx) = mpsc::channel(1024);
+        tokio::spawn(async move { while progress_rx.recv().await.is_some() {} });
+
+        let sink = Sink::File(Endpoint::Local(target.clone()));
+        transfer(&Source::Null(5000), &sink, &options(2, 1000), &progress_tx).await.unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), vec![0; 5000]);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        // Replacing needs -f, and a remote end is refused before anything runs
+        assert!(transfer(&Source::Null(10), &sink, &options(2, 1000), &progress_tx).await.is_err());
+        let remote = Sink::parse(&format!("node1:{}", target.display()), PipeMode::default()).unwrap();
+        assert!(transfer(&Source::Stdin, &remote, &options(2, 1000), &progress_tx).await.is_err());
+    }
+}
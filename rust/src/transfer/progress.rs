@@ -49,7 +49,11 @@ impl ProgressReporter {
                 TransferMessage::Checksum { algorithm, value } => {
                     info!("Checksum {}: {:x?}", algorithm, value);
                 }
+                TransferMessage::Resumed { previous_bytes } => {
+                    self.bytes_transferred += previous_bytes;
+                }
                 TransferMessage::Complete => {
+                    self.update_progress();
                     self.bar.finish_with_message("Transfer completed");
                     break;
                 }
@@ -62,6 +66,10 @@ impl ProgressReporter {
     }
     
     fn update_progress(&self) {
+        // Trees are sized as they are walked, so the total may be unknown
+        if self.total_bytes == 0 {
+            self.bar.set_length(self.bytes_transferred);
+        }
         self.bar.set_position(self.bytes_transferred);
         
         let elapsed = self.start_time.elapsed();
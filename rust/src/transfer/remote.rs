@@ -0,0 +1,377 @@
+// Transfers between this host and one remote host: `f host:/g`, `host:/f g`
+//
+// As in a third-party transfer, bbcpr runs as an agent on the remote host
+// over SSH (see `agent`), here with this host as the other end. An upload
+// streams the file into a receiving agent's stdin and follows its progress
+// reports; a download reads a sending agent's stdout and writes the file
+// here with a local receiving agent.
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+use tracing::info;
+
+use crate::network::endpoint::Endpoint;
+use crate::network::ssh::SshOptions;
+use crate::network::RemoteShell;
+use crate::transfer::agent::{self, Agent, AgentRole};
+use crate::transfer::engine::TransferMessage;
+use crate::transfer::space;
+use crate::transfer::third_party::{follow_reports, reap};
+
+/// Copies a single file to or from a host reached over SSH
+pub struct RemoteTransfer {
+    source: Endpoint,
+    destination: Endpoint,
+    ssh: SshOptions,
+    remote_program: String,
+    chunk_size: usize,
+    force: bool,
+    partial_suffix: Option<String>,
+    space_check: bool,
+    preserve: bool,
+    mode: Option<u32>,
+    xattrs: bool,
+}
+
+impl RemoteTransfer {
+    /// Exactly one end must be `Endpoint::Ssh` and the other local
+    pub fn new(source: Endpoint, destination: Endpoint) -> Result<Self> {
+        match (&source, &destination) {
+            (Endpoint::Local(_), Endpoint::Ssh { .. }) | (Endpoint::Ssh { .. }, Endpoint::Local(_)) => {}
+            (Endpoint::Tcp { .. }, _) | (_, Endpoint::Tcp { .. }) => {
+                anyhow::bail!("bbcpr:// endpoints need a bbcpr server, which this version can't reach yet");
+            }
+            _ => anyhow::bail!("A remote transfer needs one local and one SSH endpoint, not {} and {}", source, destination),
+        }
+        Ok(Self {
+            source,
+            destination,
+            ssh: SshOptions::default(),
+            remote_program: "bbcpr".to_string(),
+            chunk_size: crate::DEFAULT_BUFFER_SIZE,
+            force: false,
+            partial_suffix: Some("bbcpr-partial".to_string()),
+            space_check: true,
+            preserve: false,
+            mode: None,
+            xattrs: false,
+        })
+    }
+
+    /// Backend, identity and credentials used to log in to the host
+    pub fn with_ssh_options(mut self, ssh: SshOptions) -> Self {
+        self.ssh = ssh;
+        self
+    }
+
+    /// Name or path of bbcpr on the remote host
+    pub fn with_remote_program(mut self, program: String) -> Self {
+        self.remote_program = program;
+        self
+    }
+
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    pub fn with_force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Suffix of the receiver's partial file, or `None` to write in place
+    pub fn with_partial_suffix(mut self, suffix: Option<String>) -> Self {
+        self.partial_suffix = suffix;
+        self
+    }
+
+    /// Check free space on the receiving side before starting (disabled by -F)
+    pub fn with_space_check(mut self, space_check: bool) -> Self {
+        self.space_check = space_check;
+        self
+    }
+
+    /// Give the copy the source's attributes (-p)
+    pub fn with_preserve(mut self, preserve: bool) -> Self {
+        self.preserve = preserve;
+        self
+    }
+
+    /// Permission bits forced onto the copy (-m)
+    pub fn with_mode(mut self, mode: Option<u32>) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Carry extended attributes and ACLs to the copy (-X)
+    pub fn with_xattrs(mut self, xattrs: bool) -> Self {
+        self.xattrs = xattrs;
+        self
+    }
+
+    /// Run the transfer, forwarding progress to `progress_tx` and returning
+    /// the number of bytes written
+    pub async fn run(&self, progress_tx: &mpsc::Sender<TransferMessage>) -> Result<u64> {
+        let remote = if self.source.is_remote() { &self.source } else { &self.destination };
+        let Endpoint::Ssh { user, host, port, .. } = remote else {
+            anyhow::bail!("{} is not reached over SSH", remote);
+        };
+        let mut shell = self.ssh.shell(host, user.as_deref(), *port)?;
+        shell.connect().await
+            .with_context(|| format!("Failed to connect to {}", host))?;
+
+        let result = self.run_on(shell.as_ref(), progress_tx).await;
+        let _ = shell.close().await;
+        result
+    }
+
+    async fn run_on(&self, shell: &dyn RemoteShell, progress_tx: &mpsc::Sender<TransferMessage>) -> Result<u64> {
+        match (&self.source, &self.destination) {
+            (Endpoint::Local(source), remote) => self.upload(shell, source, &remote.path(), progress_tx).await,
+            (remote, Endpoint::Local(destination)) => self.download(shell, &remote.path(), destination, progress_tx).await,
+            _ => unreachable!("checked in new"),
+        }
+    }
+
+    /// Arguments that run an agent for `role` on `path`, with "-" as its
+    /// data end
+    fn agent_args(&self, role: AgentRole, path: &str) -> Vec<String> {
+        let mut args = vec!["--agent".to_string(), role.as_str().to_string()];
+        if role == AgentRole::Send && self.xattrs {
+            args.push("--xattrs".to_string());
+        }
+        if role == AgentRole::Receive {
+            if self.force {
+                args.push("--force".to_string());
+            }
+            if self.preserve {
+                args.push("--preserve".to_string());
+            }
+            if let Some(mode) = self.mode {
+                args.extend(["--mode".to_string(), format!("{:o}", mode)]);
+            }
+            match &self.partial_suffix {
+                Some(suffix) => args.extend(["--partial-suffix".to_string(), suffix.clone()]),
+                None => args.push("--inplace".to_string()),
+            }
+        }
+        match role {
+            AgentRole::Send => args.extend([path.to_string(), "-".to_string()]),
+            _ => args.extend(["-".to_string(), path.to_string()]),
+        }
+        args
+    }
+
+    /// Stream `source` to a receiving agent writing `destination`
+    async fn upload(
+        &self,
+        shell: &dyn RemoteShell,
+        source: &Path,
+        destination: &str,
+        progress_tx: &mpsc::Sender<TransferMessage>,
+    ) -> Result<u64> {
+        let metadata = tokio::fs::metadata(source).await
+            .with_context(|| format!("Failed to read {:?}", source))?;
+        if !metadata.is_file() {
+            anyhow::bail!("{:?} is not a file; remote transfers copy single files", source);
+        }
+        info!("Uploading {:?} to {}:{}", source, shell.host(), destination);
+
+        if self.space_check {
+            let mut query = shell.spawn(&self.remote_program, &self.agent_args(AgentRole::Query, "-")).await?;
+            let mut queries = query.stdin().take().context("Query agent has no stdin")?;
+            let mut replies = query.stdout().take().context("Query agent has no stdout")?;
+            space::check_remote_space(&mut replies, &mut queries, destination, metadata.len()).await?;
+            drop((queries, replies));
+            reap(query, "query").await?;
+        }
+
+        let mut receiver = shell.spawn(&self.remote_program, &self.agent_args(AgentRole::Receive, destination)).await?;
+        let data = receiver.stdin().take().context("Receiving agent has no stdin")?;
+        let mut reports = receiver.stdout().take().context("Receiving agent has no stdout")?;
+
+        let (sent, received) = tokio::join!(
+            agent::send_file(source, data, self.chunk_size, self.xattrs),
+            follow_reports(&mut reports, progress_tx),
+        );
+        drop(reports);
+
+        // The receiver's report explains a broken stream best
+        let received = received?;
+        sent?;
+        reap(receiver, "receiving").await?;
+        Ok(received)
+    }
+
+    /// Read `source` from a sending agent and write it to `destination`
+    async fn download(
+        &self,
+        shell: &dyn RemoteShell,
+        source: &str,
+        destination: &Path,
+        progress_tx: &mpsc::Sender<TransferMessage>,
+    ) -> Result<u64> {
+        info!("Downloading {}:{} to {:?}", shell.host(), source, destination);
+
+        if self.space_check {
+            let mut query = shell.spawn(&self.remote_program, &self.agent_args(AgentRole::Query, "-")).await?;
+            let mut queries = query.stdin().take().context("Query agent has no stdin")?;
+            let mut replies = query.stdout().take().context("Query agent has no stdout")?;
+            let info = agent::query_file_info(&mut replies, &mut queries, source).await?;
+            drop((queries, replies));
+            reap(query, "query").await?;
+            space::check_local_space(destination, info.size)?;
+        }
+
+        let mut sender = shell.spawn(&self.remote_program, &self.agent_args(AgentRole::Send, source)).await?;
+        let data = sender.stdout().take().context("Sending agent has no stdout")?;
+
+        // The local receiver reports as a remote one would
+        let (report_tx, mut reports) = tokio::io::duplex(64 * 1024);
+        let receiver = Agent::new(AgentRole::Receive, PathBuf::from(destination))
+            .with_chunk_size(self.chunk_size)
+            .with_force(self.force)
+            .with_partial_suffix(self.partial_suffix.clone())
+            .with_preserve(self.preserve)
+            .with_mode(self.mode);
+
+        let (_, received) = tokio::join!(
+            receiver.run_with(data, report_tx),
+            follow_reports(&mut reports, progress_tx),
+        );
+        let received = received?;
+        reap(sender, "sending").await?;
+        Ok(received)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfer::third_party::tests::LocalShell;
+
+    async fn copy(source: Endpoint, destination: Endpoint) -> Result<u64> {
+        let (progress_tx, mut progress_rx) = mpsc::channel(1024);
+        tokio::spawn(async move { while progress_rx.recv().await.is_some() {} });
+        RemoteTransfer::new(source, destination)?
+            .with_chunk_size(64 * 1024)
+            .run_on(&LocalShell, &progress_tx)
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_upload_and_download() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source.dat");
+        let data: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&source, &data).unwrap();
+
+        let uploaded = dir.path().join("uploaded.dat");
+        let received = copy(
+            Endpoint::Local(source.clone()),
+            Endpoint::parse(&format!("node1:{}", uploaded.display())).unwrap(),
+        ).await.unwrap();
+        assert_eq!(received, data.len() as u64);
+        assert_eq!(std::fs::read(&uploaded).unwrap(), data);
+
+        // Into an existing directory, under the source's name
+        let into = dir.path().join("into");
+        std::fs::create_dir(&into).unwrap();
+        copy(
+            Endpoint::parse(&format!("node1:{}", uploaded.display())).unwrap(),
+            Endpoint::Local(into.clone()),
+        ).await.unwrap();
+        assert_eq!(std::fs::read(into.join("uploaded.dat")).unwrap(), data);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_attributes_are_applied() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source.dat");
+        std::fs::write(&source, b"attributes").unwrap();
+        std::fs::set_permissions(&source, std::fs::Permissions::from_mode(0o640)).unwrap();
+        let mtime = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        std::fs::File::options().write(true).open(&source).unwrap().set_modified(mtime).unwrap();
+
+        let (progress_tx, mut progress_rx) = mpsc::channel(1024);
+        tokio::spawn(async move { while progress_rx.recv().await.is_some() {} });
+
+        // -p on an upload, applied by the remote receiving agent
+        let uploaded = dir.path().join("uploaded.dat");
+        RemoteTransfer::new(
+            Endpoint::Local(source.clone()),
+            Endpoint::parse(&format!("node1:{}", uploaded.display())).unwrap(),
+        ).unwrap().with_preserve(true).run_on(&LocalShell, &progress_tx).await.unwrap();
+        let metadata = std::fs::metadata(&uploaded).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o640);
+        assert_eq!(metadata.modified().unwrap(), mtime);
+
+        // -m on a download, applied here
+        let downloaded = dir.path().join("downloaded.dat");
+        RemoteTransfer::new(
+            Endpoint::parse(&format!("node1:{}", source.display())).unwrap(),
+            Endpoint::Local(downloaded.clone()),
+        ).unwrap().with_mode(Some(0o600)).run_on(&LocalShell, &progress_tx).await.unwrap();
+        let metadata = std::fs::metadata(&downloaded).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o600);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_download_carries_xattrs() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("tagged.dat");
+        std::fs::write(&source, b"data").unwrap();
+        if crate::platform::linux::set_xattr(&source, "user.lineage", b"run-42").is_err() {
+            // The filesystem has no user xattr support
+            return;
+        }
+
+        let (progress_tx, mut progress_rx) = mpsc::channel(1024);
+        tokio::spawn(async move { while progress_rx.recv().await.is_some() {} });
+
+        let downloaded = dir.path().join("downloaded.dat");
+        RemoteTransfer::new(
+            Endpoint::parse(&format!("node1:{}", source.display())).unwrap(),
+            Endpoint::Local(downloaded.clone()),
+        ).unwrap().with_xattrs(true).run_on(&LocalShell, &progress_tx).await.unwrap();
+        assert_eq!(
+            crate::platform::linux::get_xattr(&downloaded, "user.lineage").unwrap(),
+            Some(b"run-42".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_existing_remote_file_needs_force() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source.dat");
+        let target = dir.path().join("target.dat");
+        std::fs::write(&source, b"new").unwrap();
+        std::fs::write(&target, b"old").unwrap();
+
+        let result = copy(
+            Endpoint::Local(source),
+            Endpoint::parse(&format!("node1:{}", target.display())).unwrap(),
+        ).await;
+        assert!(result.is_err());
+        assert_eq!(std::fs::read(&target).unwrap(), b"old");
+    }
+
+    #[test]
+    fn test_needs_one_ssh_end() {
+        let remote = Endpoint::parse("node1:/data/f").unwrap();
+        let local = Endpoint::parse("/data/f").unwrap();
+        let tcp = Endpoint::parse("bbcpr://node1/data/f").unwrap();
+
+        assert!(RemoteTransfer::new(local.clone(), remote.clone()).is_ok());
+        assert!(RemoteTransfer::new(remote.clone(), local.clone()).is_ok());
+        assert!(RemoteTransfer::new(remote.clone(), remote).is_err());
+        assert!(RemoteTransfer::new(local.clone(), local.clone()).is_err());
+        assert!(RemoteTransfer::new(local, tcp).is_err());
+    }
+}
@@ -0,0 +1,137 @@
+// Target free space checks (skipped with -F)
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, warn};
+
+use crate::error::{BbcprError, Result};
+use crate::network::protocol::{self, MessageType, ProtocolMessage};
+use crate::platform;
+
+/// Fail unless the filesystem holding `destination` has room for
+/// `required` bytes. Platforms that can't tell are not checked.
+pub fn check_local_space(destination: &Path, required: u64) -> Result<()> {
+    let available = match platform::available_space(destination) {
+        Ok(available) => available,
+        Err(BbcprError::Unsupported(what)) => {
+            warn!("Skipping the target space check: {} is not supported", what);
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    debug!("Target {:?}: {} bytes needed, {} bytes available", destination, required, available);
+    ensure_space(&destination.to_string_lossy(), required, available)
+}
+
+/// Ask a query agent, through its `queries` and `replies` streams, for the
+/// free space at `destination` and fail unless it has room for `required`
+/// bytes
+pub async fn check_remote_space<R, W>(
+    replies: &mut R,
+    queries: &mut W,
+    destination: &str,
+    required: u64,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let query = ProtocolMessage::new(MessageType::SpaceQuery, Bytes::copy_from_slice(destination.as_bytes()));
+    protocol::write_message(queries, &query).await?;
+    queries.flush().await?;
+
+    let mut reply = protocol::read_message(replies).await?
+        .ok_or_else(|| BbcprError::Protocol("Remote agent exited before answering the space query".to_string()))?;
+    let available = match reply.message_type {
+        // The remote platform can't tell
+        MessageType::SpaceReply if reply.data.is_empty() => {
+            warn!("Skipping the target space check: the remote host can't report free space");
+            return Ok(());
+        }
+        MessageType::SpaceReply if reply.data.len() >= 8 => reply.data.get_u64(),
+        MessageType::Error => {
+            return Err(BbcprError::Protocol(format!(
+                "Remote space query failed: {}", String::from_utf8_lossy(&reply.data)
+            )));
+        }
+        other => {
+            return Err(BbcprError::Protocol(format!("Unexpected reply to space query: {:?}", other)));
+        }
+    };
+
+    debug!("Remote target {}: {} bytes needed, {} bytes available", destination, required, available);
+    ensure_space(destination, required, available)
+}
+
+/// Agent side of a space query: reply with the free space at the requested
+/// path, or an empty reply if this platform can't tell
+pub fn answer_space_query(query: &ProtocolMessage) -> ProtocolMessage {
+    let path = String::from_utf8_lossy(&query.data).into_owned();
+    match platform::available_space(Path::new(&path)) {
+        Ok(available) => {
+            let mut data = BytesMut::with_capacity(8);
+            data.put_u64(available);
+            ProtocolMessage::new(MessageType::SpaceReply, data.freeze())
+        }
+        Err(BbcprError::Unsupported(_)) => ProtocolMessage::new(MessageType::SpaceReply, Bytes::new()),
+        Err(e) => ProtocolMessage::new(MessageType::Error, Bytes::from(e.to_string())),
+    }
+}
+
+fn ensure_space(path: &str, required: u64, available: u64) -> Result<()> {
+    if required > available {
+        return Err(BbcprError::InsufficientSpace {
+            path: path.to_string(),
+            required,
+            available,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insufficient_space_message() {
+        let err = ensure_space("/data", 2000, 1000).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Not enough space on target /data: 2000 bytes needed, 1000 bytes available (use -F to skip this check)"
+        );
+        assert!(ensure_space("/data", 1000, 1000).is_ok());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_local_space_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("not-yet-created").join("file.dat");
+
+        assert!(check_local_space(&target, 0).is_ok());
+        assert!(check_local_space(&target, u64::MAX).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_remote_space_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let destination = dir.path().join("file.dat").to_string_lossy().into_owned();
+
+        for (required, fits) in [(0, true), (u64::MAX, false)] {
+            let (mut queries, mut agent_input) = tokio::io::duplex(1024);
+            let (mut agent_output, mut replies) = tokio::io::duplex(1024);
+            let agent = async {
+                let query = protocol::read_message(&mut agent_input).await.unwrap().unwrap();
+                protocol::write_message(&mut agent_output, &answer_space_query(&query)).await.unwrap();
+            };
+            let (result, ()) = tokio::join!(
+                check_remote_space(&mut replies, &mut queries, &destination, required),
+                agent,
+            );
+            assert_eq!(result.is_ok(), fits);
+        }
+    }
+}
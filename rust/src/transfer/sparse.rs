@@ -0,0 +1,152 @@
+// Sparse files: only data extents are copied, holes are recreated
+//
+// Holes in the source are found with SEEK_DATA/SEEK_HOLE where the
+// platform supports it. With --zero-holes, runs of all-zero blocks in the
+// data are treated as holes too, so dense files full of zeros come out
+// sparse.
+
+use anyhow::{Context, Result};
+use std::ops::Range;
+use std::path::Path;
+
+use crate::platform;
+
+/// Granularity of zero detection; matches the usual filesystem block size
+pub const ZERO_BLOCK: usize = 4096;
+
+/// A stretch of a file that either holds data or reads as zeros
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Extent {
+    Data(Range<u64>),
+    Hole(Range<u64>),
+}
+
+/// Split `range` of `file` into data and hole extents, in order
+pub fn extents(file: &std::fs::File, range: Range<u64>) -> Result<Vec<Extent>> {
+    let data = platform::data_extents(file, range.clone())
+        .context("Failed to find data extents")?;
+
+    let mut extents = Vec::with_capacity(data.len() * 2 + 1);
+    let mut offset = range.start;
+    for extent in data {
+        if extent.start > offset {
+            extents.push(Extent::Hole(offset..extent.start));
+        }
+        offset = extent.end;
+        extents.push(Extent::Data(extent));
+    }
+    if offset < range.end {
+        extents.push(Extent::Hole(offset..range.end));
+    }
+    Ok(extents)
+}
+
+/// Split `data` into alternating runs of zero and non-zero blocks. Each
+/// item is the range within `data` and whether it is all zeros; a short
+/// trailing block counts as a block of its own.
+pub fn zero_runs(data: &[u8]) -> Vec<(Range<usize>, bool)> {
+    let mut runs: Vec<(Range<usize>, bool)> = Vec::new();
+    for (index, block) in data.chunks(ZERO_BLOCK).enumerate() {
+        let start = index * ZERO_BLOCK;
+        let zero = is_zero(block);
+        match runs.last_mut() {
+            Some((range, last)) if *last == zero => range.end = start + block.len(),
+            _ => runs.push((start..start + block.len(), zero)),
+        }
+    }
+    runs
+}
+
+pub fn is_zero(data: &[u8]) -> bool {
+    data.iter().all(|&b| b == 0)
+}
+
+/// Write `data` as a new file at `path`, leaving the `holes` unallocated
+pub fn write_sparse(path: &Path, data: &[u8], holes: &[Range<usize>]) -> Result<()> {
+    use std::io::{Seek, SeekFrom, Write};
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Failed to create {:?}", path))?;
+
+    // Holes are in order; write the data between them
+    let end = data.len()..data.len();
+    let mut offset = 0;
+    for hole in holes.iter().chain([&end]) {
+        if hole.start > offset {
+            file.seek(SeekFrom::Start(offset as u64))
+                .and_then(|_| file.write_all(&data[offset..hole.start]))
+                .with_context(|| format!("Failed to write {:?}", path))?;
+        }
+        offset = offset.max(hole.end);
+    }
+    // Trailing holes are covered by the length
+    file.set_len(data.len() as u64)
+        .with_context(|| format!("Failed to set the length of {:?}", path))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_extents_cover_range() {
+        use std::os::unix::fs::FileExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sparse.img");
+        let file = std::fs::File::create(&path).unwrap();
+        file.set_len(16 * 1024 * 1024).unwrap();
+        file.write_all_at(b"head", 0).unwrap();
+        file.write_all_at(b"middle", 8 * 1024 * 1024).unwrap();
+
+        let file = std::fs::File::open(&path).unwrap();
+        let extents = extents(&file, 0..16 * 1024 * 1024).unwrap();
+
+        // Extents are contiguous and cover the range, whether or not the
+        // filesystem reports holes
+        let mut offset = 0;
+        for extent in &extents {
+            let (Extent::Data(range) | Extent::Hole(range)) = extent;
+            assert_eq!(range.start, offset);
+            offset = range.end;
+        }
+        assert_eq!(offset, 16 * 1024 * 1024);
+
+        // Written bytes are always inside data extents
+        let in_data = |at: u64| extents.iter().any(|e| matches!(e, Extent::Data(r) if r.contains(&at)));
+        assert!(in_data(0));
+        assert!(in_data(8 * 1024 * 1024));
+    }
+
+    #[test]
+    fn test_zero_runs_and_sparse_write() {
+        let mut data = vec![0u8; 5 * ZERO_BLOCK + 100];
+        data[ZERO_BLOCK + 10] = 1;
+        data[5 * ZERO_BLOCK + 50] = 2;
+
+        assert_eq!(zero_runs(&data), vec![
+            (0..ZERO_BLOCK, true),
+            (ZERO_BLOCK..2 * ZERO_BLOCK, false),
+            (2 * ZERO_BLOCK..5 * ZERO_BLOCK, true),
+            (5 * ZERO_BLOCK..data.len(), false),
+        ]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let holes = |data: &[u8]| -> Vec<_> {
+            zero_runs(data).into_iter().filter(|(_, zero)| *zero).map(|(run, _)| run).collect()
+        };
+        write_sparse(&path, &data, &holes(&data)).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+
+        data.truncate(3 * ZERO_BLOCK);
+        write_sparse(&path, &data, &holes(&data)).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+    }
+}
@@ -2,9 +2,10 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::error::BbcpError;
+use crate::error::BbcprError;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TransferState {
@@ -18,6 +19,9 @@ pub struct TransferState {
     pub timestamp: u64,
     pub streams: u32,
     pub compression_level: Option<u8>,
+    /// State is kept next to the destination instead of in the state directory
+    #[serde(default)]
+    pub sidecar: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -55,9 +59,17 @@ impl TransferState {
             timestamp,
             streams,
             compression_level,
+            sidecar: false,
         }
     }
 
+    /// Keep the state file next to the destination, so the resume
+    /// information travels with the partial file
+    pub fn with_sidecar(mut self, sidecar: bool) -> Self {
+        self.sidecar = sidecar;
+        self
+    }
+
     pub fn initialize_chunks(&mut self) {
         let chunk_size = self.total_size / self.streams as u64;
         let mut remaining = self.total_size;
@@ -127,21 +139,29 @@ impl TransferState {
             .sum();
     }
 
-    pub fn save_to_disk(&self) -> Result<(), BbcpError> {
-        let state_dir = get_state_directory()?;
-        fs::create_dir_all(&state_dir)?;
+    /// Path of the file this state is saved to
+    pub fn state_file(&self) -> Result<PathBuf, BbcprError> {
+        state_file_path(&self.transfer_id, &self.destination_path, self.sidecar)
+    }
+
+    pub fn save_to_disk(&self) -> Result<(), BbcprError> {
+        let state_file = self.state_file()?;
+        if let Some(parent) = state_file.parent() {
+            fs::create_dir_all(parent)?;
+        }
 
-        let state_file = state_dir.join(format!("{}.json", self.transfer_id));
         let state_json = serde_json::to_string_pretty(self)?;
         fs::write(state_file, state_json)?;
 
         Ok(())
     }
 
-    pub fn load_from_disk(transfer_id: &str) -> Result<Option<Self>, BbcpError> {
+    pub fn load_from_disk(transfer_id: &str) -> Result<Option<Self>, BbcprError> {
         let state_dir = get_state_directory()?;
-        let state_file = state_dir.join(format!("{}.json", transfer_id));
+        Self::load_from_file(&state_dir.join(format!("{}.json", transfer_id)))
+    }
 
+    pub fn load_from_file(state_file: &Path) -> Result<Option<Self>, BbcprError> {
         if !state_file.exists() {
             return Ok(None);
         }
@@ -151,9 +171,8 @@ impl TransferState {
         Ok(Some(state))
     }
 
-    pub fn delete_from_disk(&self) -> Result<(), BbcpError> {
-        let state_dir = get_state_directory()?;
-        let state_file = state_dir.join(format!("{}.json", self.transfer_id));
+    pub fn delete_from_disk(&self) -> Result<(), BbcprError> {
+        let state_file = self.state_file()?;
 
         if state_file.exists() {
             fs::remove_file(state_file)?;
@@ -162,12 +181,19 @@ impl TransferState {
         Ok(())
     }
 
-    pub fn find_existing_transfer(source: &str, destination: &str) -> Result<Option<Self>, BbcpError> {
+    /// Find saved state for a transfer, looking for a sidecar file next to
+    /// the destination before falling back to the state directory
+    pub fn find_existing_transfer(source: &str, destination: &str) -> Result<Option<Self>, BbcprError> {
         let transfer_id = generate_transfer_id(source, destination);
+        if let Some(state) = Self::load_from_file(&state_file_path(&transfer_id, destination, true)?)? {
+            return Ok(Some(state));
+        }
         Self::load_from_disk(&transfer_id)
     }
 
-    pub fn list_all_transfers() -> Result<Vec<Self>, BbcpError> {
+    /// List transfers saved in the state directory. Sidecar state files
+    /// are not included since they live next to their destinations.
+    pub fn list_all_transfers() -> Result<Vec<Self>, BbcprError> {
         let state_dir = get_state_directory()?;
         
         if !state_dir.exists() {
@@ -192,7 +218,7 @@ impl TransferState {
     }
 }
 
-fn generate_transfer_id(source: &str, destination: &str) -> String {
+pub(crate) fn generate_transfer_id(source: &str, destination: &str) -> String {
     use std::collections::hash_map::DefaultHasher;
     use std::hash::{Hash, Hasher};
 
@@ -202,14 +228,47 @@ fn generate_transfer_id(source: &str, destination: &str) -> String {
     format!("{:x}", hasher.finish())
 }
 
-fn get_state_directory() -> Result<PathBuf, BbcpError> {
+static STATE_DIRECTORY: OnceLock<PathBuf> = OnceLock::new();
+
+/// Override the state directory for the rest of the process, e.g. from
+/// `-a/--append DIR` or the config file. Returns false if it was already set.
+pub fn set_state_directory(dir: PathBuf) -> bool {
+    STATE_DIRECTORY.set(dir).is_ok()
+}
+
+/// Resolve the state directory: an explicit override, then
+/// `BBCPR_STATE_DIR`, then `~/.bbcpr/transfers`
+pub fn get_state_directory() -> Result<PathBuf, BbcprError> {
+    if let Some(dir) = STATE_DIRECTORY.get() {
+        return Ok(dir.clone());
+    }
+
+    if let Some(dir) = std::env::var_os("BBCPR_STATE_DIR").filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(dir));
+    }
+
     let home_dir = dirs::home_dir()
-        .ok_or_else(|| BbcpError::IoError("Could not determine home directory".into()))?;
+        .ok_or_else(|| BbcprError::Config("Could not determine home directory; set BBCPR_STATE_DIR".into()))?;
     
     Ok(home_dir.join(".bbcpr").join("transfers"))
 }
 
-pub fn cleanup_old_transfers(max_age_days: u64) -> Result<usize, BbcpError> {
+/// Path of the state file for a transfer, either `<state dir>/<id>.json` or
+/// the sidecar `.<name>.bbcpr-state.json` next to the destination
+pub fn state_file_path(transfer_id: &str, destination: &str, sidecar: bool) -> Result<PathBuf, BbcprError> {
+    if !sidecar {
+        return Ok(get_state_directory()?.join(format!("{}.json", transfer_id)));
+    }
+
+    let destination = Path::new(destination);
+    let name = destination.file_name()
+        .ok_or_else(|| BbcprError::Config(format!("Destination {:?} has no file name", destination)))?;
+    let parent = destination.parent().unwrap_or_else(|| Path::new(""));
+
+    Ok(parent.join(format!(".{}.bbcpr-state.json", name.to_string_lossy())))
+}
+
+pub fn cleanup_old_transfers(max_age_days: u64) -> Result<usize, BbcprError> {
     let transfers = TransferState::list_all_transfers()?;
     let current_time = SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -220,6 +279,11 @@ pub fn cleanup_old_transfers(max_age_days: u64) -> Result<usize, BbcpError> {
     let mut cleaned_count = 0;
 
     for transfer in transfers {
+        // Never remove the state of a transfer that is still running
+        if crate::transfer::lock::TransferLock::holder(&transfer.transfer_id)?.is_some() {
+            continue;
+        }
+
         if current_time.saturating_sub(transfer.timestamp) > max_age_seconds {
             transfer.delete_from_disk()?;
             cleaned_count += 1;
@@ -271,6 +335,17 @@ mod tests {
         assert!(state.chunk_states[&0].completed);
     }
 
+    #[test]
+    fn test_sidecar_state_file_path() {
+        let state = TransferState::new("/source/file.txt", "/dest/file.txt", 1000, 4, None)
+            .with_sidecar(true);
+
+        assert_eq!(
+            state.state_file().unwrap(),
+            PathBuf::from("/dest/.file.txt.bbcpr-state.json")
+        );
+    }
+
     #[test]
     fn test_transfer_id_generation() {
         let id1 = generate_transfer_id("/a", "/b");
@@ -27,7 +27,7 @@ impl TransferStream {
     
     pub async fn transfer<C: Connection + 'static>(
         &self,
-        connection: Arc<Mutex<C>>,
+        _connection: Arc<Mutex<C>>,
         progress_tx: mpsc::Sender<TransferMessage>,
     ) -> Result<()> {
         debug!(
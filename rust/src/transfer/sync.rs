@@ -0,0 +1,143 @@
+// Incremental sync: deciding which destinations already hold the source (-O, --sync)
+
+use anyhow::{Context, Result};
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::UNIX_EPOCH;
+use tracing::debug;
+
+use crate::checksum::md5::MD5Checksum;
+use crate::checksum::Checksum;
+
+/// When an existing destination is left alone. Destinations that are
+/// missing are always copied; destinations that differ are replaced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Skip any destination that exists (-O)
+    Existing,
+    /// Skip when size and modification time (to the second) match
+    SizeAndTime,
+    /// Skip when size and MD5 checksum match
+    Checksum,
+    /// Never skip; always replace the destination
+    Always,
+}
+
+impl FromStr for SyncPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "existing" => Ok(Self::Existing),
+            "size-mtime" => Ok(Self::SizeAndTime),
+            "checksum" => Ok(Self::Checksum),
+            "always" => Ok(Self::Always),
+            _ => anyhow::bail!(
+                "Invalid sync mode {:?}: use existing, size-mtime, checksum or always", value
+            ),
+        }
+    }
+}
+
+/// Why `destination` does not need to be copied from `source` under
+/// `policy`, or `None` if it does
+pub async fn skip_reason(policy: SyncPolicy, source: &Path, destination: &Path) -> Result<Option<&'static str>> {
+    let destination_metadata = match tokio::fs::metadata(destination).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", destination)),
+    };
+
+    if policy == SyncPolicy::Existing {
+        return Ok(Some("destination exists"));
+    }
+    if policy == SyncPolicy::Always || !destination_metadata.is_file() {
+        return Ok(None);
+    }
+
+    let source_metadata = tokio::fs::metadata(source).await
+        .with_context(|| format!("Failed to read {:?}", source))?;
+    if source_metadata.len() != destination_metadata.len() {
+        return Ok(None);
+    }
+
+    Ok(match policy {
+        SyncPolicy::SizeAndTime => {
+            (mtime_seconds(&source_metadata) == mtime_seconds(&destination_metadata))
+                .then_some("size and modification time match")
+        }
+        SyncPolicy::Checksum => {
+            let (source_sum, destination_sum) = tokio::try_join!(
+                file_checksum(source),
+                file_checksum(destination)
+            )?;
+            (source_sum == destination_sum).then_some("checksum matches")
+        }
+        SyncPolicy::Existing | SyncPolicy::Always => None,
+    })
+}
+
+fn mtime_seconds(metadata: &std::fs::Metadata) -> Option<u64> {
+    metadata.modified().ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs())
+}
+
+/// MD5 of a whole file, read off the async runtime
+pub async fn file_checksum(path: &Path) -> Result<Vec<u8>> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+        let mut file = std::fs::File::open(&path)
+            .with_context(|| format!("Failed to open {:?}", path))?;
+        let mut checksum = MD5Checksum::new();
+        let mut buffer = vec![0u8; 1024 * 1024];
+        loop {
+            let n = file.read(&mut buffer)
+                .with_context(|| format!("Failed to read {:?}", path))?;
+            if n == 0 {
+                break;
+            }
+            checksum.update(&buffer[..n]);
+        }
+        debug!("Computed {} of {:?}", checksum.name(), path);
+        Ok(checksum.finalize())
+    })
+    .await
+    .context("Checksum task panicked")?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_policies() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let same = dir.path().join("same");
+        let changed = dir.path().join("changed");
+        let missing = dir.path().join("missing");
+        std::fs::write(&source, b"hello world").unwrap();
+        std::fs::write(&same, b"hello world").unwrap();
+        std::fs::write(&changed, b"hello WORLD").unwrap();
+
+        let skip = |policy, destination| skip_reason(policy, &source, destination);
+
+        assert!(skip(SyncPolicy::Existing, &changed).await.unwrap().is_some());
+        assert!(skip(SyncPolicy::Existing, &missing).await.unwrap().is_none());
+        assert!(skip(SyncPolicy::Checksum, &same).await.unwrap().is_some());
+        assert!(skip(SyncPolicy::Checksum, &changed).await.unwrap().is_none());
+        assert!(skip(SyncPolicy::Always, &same).await.unwrap().is_none());
+
+        let mtime = std::fs::metadata(&source).unwrap().modified().unwrap();
+        std::fs::File::options().write(true).open(&same).unwrap().set_modified(mtime).unwrap();
+        assert!(skip(SyncPolicy::SizeAndTime, &same).await.unwrap().is_some());
+    }
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!("size-mtime".parse::<SyncPolicy>().unwrap(), SyncPolicy::SizeAndTime);
+        assert!("newer".parse::<SyncPolicy>().is_err());
+    }
+}
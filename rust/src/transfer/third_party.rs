@@ -0,0 +1,704 @@
+// Third-party transfers: `user@hostA:/f user@hostB:/g`
+//
+// The local machine is only the controller. It starts an agent on each
+// host over SSH (see `agent`) and follows the receiving agent's progress
+// reports. The data either flows directly between the hosts or, when
+// they can't reach each other, is relayed through the controller. When
+// the source host can't reach the receiver's data port, the streams are
+// tunnelled through the controller's SSH connections instead, which
+// only need the SSH port open.
+
+use anyhow::{Context, Result};
+use std::str::FromStr;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::network::endpoint::Endpoint;
+use crate::network::protocol::{self, MessageType};
+use crate::network::ssh::{RemoteChild, RemoteStdin, RemoteStdout, SshOptions};
+use crate::network::{Connection, RemoteShell};
+use crate::transfer::agent::{self, AgentRole};
+use crate::transfer::engine::TransferMessage;
+use crate::transfer::space;
+
+/// How data travels between the two hosts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RelayMode {
+    /// The source host connects straight to the destination host
+    #[default]
+    Direct,
+    /// Data passes through the controller
+    Relay,
+    /// Each stream is carried over SSH, as when direct mode finds the
+    /// data port blocked
+    Tunnel,
+}
+
+impl FromStr for RelayMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "direct" => Ok(RelayMode::Direct),
+            "relay" => Ok(RelayMode::Relay),
+            "tunnel" => Ok(RelayMode::Tunnel),
+            other => anyhow::bail!("Invalid third-party mode {:?}: expected direct, relay or tunnel", other),
+        }
+    }
+}
+
+/// How tunnelled streams share SSH connections
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TunnelMode {
+    /// One session per host, with a channel per stream
+    #[default]
+    Channels,
+    /// A session per stream, for servers that throttle each connection
+    Sessions,
+}
+
+impl TunnelMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TunnelMode::Channels => "channels",
+            TunnelMode::Sessions => "sessions",
+        }
+    }
+}
+
+impl FromStr for TunnelMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "channels" => Ok(TunnelMode::Channels),
+            "sessions" => Ok(TunnelMode::Sessions),
+            other => anyhow::bail!("Invalid tunnel mode {:?}: expected channels or sessions", other),
+        }
+    }
+}
+
+/// Copies a file from one remote host to another
+pub struct ThirdPartyTransfer {
+    source: Endpoint,
+    destination: Endpoint,
+    mode: RelayMode,
+    tunnel: TunnelMode,
+    streams: usize,
+    ssh: SshOptions,
+    remote_program: String,
+    port_range: Option<String>,
+    force: bool,
+    partial_suffix: Option<String>,
+    space_check: bool,
+    preserve: bool,
+    /// Permission bits forced onto the copy
+    file_mode: Option<u32>,
+    xattrs: bool,
+    /// Opens every stream to the listening agent
+    token: String,
+}
+
+impl ThirdPartyTransfer {
+    /// Both ends must be `Endpoint::Ssh`
+    pub fn new(source: Endpoint, destination: Endpoint) -> Result<Self> {
+        for end in [&source, &destination] {
+            if !matches!(end, Endpoint::Ssh { .. }) {
+                anyhow::bail!("Third-party transfers need two SSH endpoints, not {}", end);
+            }
+        }
+        Ok(Self {
+            source,
+            destination,
+            mode: RelayMode::default(),
+            tunnel: TunnelMode::default(),
+            streams: 1,
+            ssh: SshOptions::default(),
+            remote_program: "bbcpr".to_string(),
+            port_range: None,
+            force: false,
+            partial_suffix: Some("bbcpr-partial".to_string()),
+            space_check: true,
+            preserve: false,
+            file_mode: None,
+            xattrs: false,
+            token: agent::new_token()?,
+        })
+    }
+
+    pub fn with_mode(mut self, mode: RelayMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// How streams are tunnelled when the data port can't be reached
+    pub fn with_tunnel(mut self, tunnel: TunnelMode) -> Self {
+        self.tunnel = tunnel;
+        self
+    }
+
+    /// Parallel data streams in direct and tunnel modes
+    pub fn with_streams(mut self, streams: usize) -> Self {
+        self.streams = streams.max(1);
+        self
+    }
+
+    /// Backend, identity and credentials used to log in to both hosts
+    pub fn with_ssh_options(mut self, ssh: SshOptions) -> Self {
+        self.ssh = ssh;
+        self
+    }
+
+    /// Name or path of bbcpr on the remote hosts
+    pub fn with_remote_program(mut self, program: String) -> Self {
+        self.remote_program = program;
+        self
+    }
+
+    /// Ports the receiving agent may listen on in direct mode (-Z)
+    pub fn with_port_range(mut self, range: Option<String>) -> Self {
+        self.port_range = range;
+        self
+    }
+
+    pub fn with_force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Suffix of the receiving agent's partial file, or `None` to write in place
+    pub fn with_partial_suffix(mut self, suffix: Option<String>) -> Self {
+        self.partial_suffix = suffix;
+        self
+    }
+
+    /// Check free space on the destination host before starting (disabled by -F)
+    pub fn with_space_check(mut self, space_check: bool) -> Self {
+        self.space_check = space_check;
+        self
+    }
+
+    /// Give the copy the source's attributes (-p)
+    pub fn with_preserve(mut self, preserve: bool) -> Self {
+        self.preserve = preserve;
+        self
+    }
+
+    /// Permission bits forced onto the copy (-m)
+    pub fn with_file_mode(mut self, mode: Option<u32>) -> Self {
+        self.file_mode = mode;
+        self
+    }
+
+    /// Carry extended attributes and ACLs to the copy (-X)
+    pub fn with_xattrs(mut self, xattrs: bool) -> Self {
+        self.xattrs = xattrs;
+        self
+    }
+
+    /// Run the transfer, forwarding the receiver's progress to `progress_tx`
+    /// and returning the number of bytes written
+    pub async fn run(&self, progress_tx: &mpsc::Sender<TransferMessage>) -> Result<u64> {
+        info!("Third-party transfer from {} to {} ({:?})", self.source, self.destination, self.mode);
+
+        let source_host = self.connect(&self.source).await?;
+        let destination_host = self.connect(&self.destination).await?;
+        if self.space_check {
+            self.check_space(source_host.as_ref(), destination_host.as_ref()).await?;
+        }
+
+        match self.mode {
+            RelayMode::Direct | RelayMode::Tunnel => {
+                self.run_direct(source_host.as_ref(), destination_host.as_ref(), progress_tx).await
+            }
+            RelayMode::Relay => self.run_relay(source_host.as_ref(), destination_host.as_ref(), progress_tx).await,
+        }
+    }
+
+    async fn connect(&self, remote: &Endpoint) -> Result<Box<dyn RemoteShell>> {
+        let Endpoint::Ssh { user, host, port, .. } = remote else {
+            anyhow::bail!("{} is not reached over SSH", remote);
+        };
+        let mut connection = self.ssh.shell(host, user.as_deref(), *port)?;
+        connection.connect().await
+            .with_context(|| format!("Failed to connect to {}", host))?;
+        Ok(connection)
+    }
+
+    /// Arguments that run an agent for `role` on `path`. The agent's data
+    /// end is "-", as in a pipe transfer. A sender given `part` sends only
+    /// that part, to its stdout.
+    fn agent_args(&self, role: AgentRole, path: &str, peer: Option<String>, part: Option<usize>) -> Vec<String> {
+        let mut args = vec!["--agent".to_string(), role.as_str().to_string()];
+        if role == AgentRole::Listen || peer.is_some() {
+            args.extend(["--streams".to_string(), self.streams.to_string()]);
+        }
+        if let Some(peer) = &peer {
+            args.extend(["--agent-peer".to_string(), peer.clone()]);
+        }
+        if let Some(index) = part {
+            args.extend(["--agent-part".to_string(), format!("{}/{}", index, self.streams)]);
+        }
+        if role == AgentRole::Listen || peer.is_some() || part.is_some() {
+            args.extend(["--agent-token".to_string(), self.token.clone()]);
+        }
+        // Tunnelled streams arrive from the destination host itself
+        if role == AgentRole::Listen && self.mode == RelayMode::Tunnel {
+            args.push("--agent-loopback".to_string());
+        }
+        if role == AgentRole::Send && self.xattrs {
+            args.push("--xattrs".to_string());
+        }
+        if matches!(role, AgentRole::Receive | AgentRole::Listen) {
+            if self.force {
+                args.push("--force".to_string());
+            }
+            if self.preserve {
+                args.push("--preserve".to_string());
+            }
+            if let Some(mode) = self.file_mode {
+                args.extend(["--mode".to_string(), format!("{:o}", mode)]);
+            }
+            match &self.partial_suffix {
+                Some(suffix) => args.extend(["--partial-suffix".to_string(), suffix.clone()]),
+                None => args.push("--inplace".to_string()),
+            }
+            if let Some(range) = &self.port_range {
+                args.extend(["--port-range".to_string(), range.clone()]);
+            }
+        }
+
+        match role {
+            AgentRole::Send => args.extend([path.to_string(), "-".to_string()]),
+            AgentRole::Receive | AgentRole::Listen | AgentRole::Query => args.extend(["-".to_string(), path.to_string()]),
+        }
+        args
+    }
+
+    /// Ask query agents for the size of the source and the free space at
+    /// the destination, and fail unless it fits
+    async fn check_space(&self, source_host: &dyn RemoteShell, destination_host: &dyn RemoteShell) -> Result<()> {
+        let args = self.agent_args(AgentRole::Query, "-", None, None);
+
+        let mut source = source_host.spawn(&self.remote_program, &args).await?;
+        let mut queries = source.stdin().take().context("Query agent has no stdin")?;
+        let mut replies = source.stdout().take().context("Query agent has no stdout")?;
+        let info = agent::query_file_info(&mut replies, &mut queries, &self.source.path()).await?;
+        drop((queries, replies));
+        reap(source, "source query").await?;
+
+        let mut destination = destination_host.spawn(&self.remote_program, &args).await?;
+        let mut queries = destination.stdin().take().context("Query agent has no stdin")?;
+        let mut replies = destination.stdout().take().context("Query agent has no stdout")?;
+        space::check_remote_space(&mut replies, &mut queries, &self.destination.path(), info.size).await?;
+        drop((queries, replies));
+        reap(destination, "destination query").await
+    }
+
+    /// The destination listens; the source connects to it, or the streams
+    /// are tunnelled to it
+    async fn run_direct(
+        &self,
+        source_host: &dyn RemoteShell,
+        destination_host: &dyn RemoteShell,
+        progress_tx: &mpsc::Sender<TransferMessage>,
+    ) -> Result<u64> {
+        let args = self.agent_args(AgentRole::Listen, &self.destination.path(), None, None);
+        let mut receiver = destination_host.spawn(&self.remote_program, &args).await?;
+        let mut reports = receiver.stdout().take().context("Receiving agent has no stdout")?;
+
+        let handshake = protocol::read_message(&mut reports).await?
+            .context("Receiving agent exited before announcing its port")?;
+        let port = match handshake.message_type {
+            MessageType::Handshake if handshake.data.len() == 2 => u16::from_be_bytes([handshake.data[0], handshake.data[1]]),
+            MessageType::Error => anyhow::bail!("Receiving agent failed: {}", String::from_utf8_lossy(&handshake.data)),
+            other => anyhow::bail!("Expected the receiving agent's port, got {:?}", other),
+        };
+
+        let received = match self.mode {
+            RelayMode::Tunnel => {
+                info!("Tunnelling {} streams through SSH over {}", self.streams, self.tunnel.as_str());
+                self.run_tunnel(source_host, destination_host, port, &mut reports, progress_tx).await?
+            }
+            _ => self.run_sender(source_host, destination_host, port, &mut reports, progress_tx).await?,
+        };
+        drop(reports);
+        reap(receiver, "receiving").await?;
+        Ok(received)
+    }
+
+    /// Start a sender that connects to the receiver's `port`, falling back
+    /// to a tunnel when it can't reach it
+    async fn run_sender(
+        &self,
+        source_host: &dyn RemoteShell,
+        destination_host: &dyn RemoteShell,
+        port: u16,
+        reports: &mut RemoteStdout,
+        progress_tx: &mpsc::Sender<TransferMessage>,
+    ) -> Result<u64> {
+        let peer = match destination_host.host() {
+            host if host.contains(':') => format!("[{}]:{}", host, port),
+            host => format!("{}:{}", host, port),
+        };
+        debug!("Receiving agent listening on {}", peer);
+
+        let args = self.agent_args(AgentRole::Send, &self.source.path(), Some(peer.clone()), None);
+        let mut sender = source_host.spawn(&self.remote_program, &args).await?;
+        let mut status = sender.stdout().take().context("Sending agent has no stdout")?;
+
+        match protocol::read_message(&mut status).await? {
+            Some(message) if message.message_type == MessageType::Handshake => {}
+            Some(message) if message.message_type == MessageType::Error => {
+                warn!(
+                    "{} can't reach {} ({}); tunnelling {} streams through SSH over {}",
+                    source_host.host(), peer, String::from_utf8_lossy(&message.data), self.streams, self.tunnel.as_str()
+                );
+                drop(status);
+                // It has already failed, and said why
+                let _ = sender.wait().await;
+                return self.run_tunnel(source_host, destination_host, port, reports, progress_tx).await;
+            }
+            Some(message) => anyhow::bail!("Unexpected {:?} record from the sending agent", message.message_type),
+            None => {
+                drop(status);
+                reap(sender, "sending").await?;
+                anyhow::bail!("The sending agent exited without connecting");
+            }
+        }
+        drop(status);
+
+        // A sender that fails fails fast, before the receiver gives up
+        // waiting for it
+        let ((), received) = tokio::try_join!(
+            reap(sender, "sending"),
+            follow_reports(reports, progress_tx),
+        )?;
+        Ok(received)
+    }
+
+    /// Carry each stream over SSH: a sender per part on the source host,
+    /// whose output is forwarded to the receiver's `port` from the
+    /// destination host. Slower than a direct connection, but needs only
+    /// the SSH port.
+    async fn run_tunnel(
+        &self,
+        source_host: &dyn RemoteShell,
+        destination_host: &dyn RemoteShell,
+        port: u16,
+        reports: &mut RemoteStdout,
+        progress_tx: &mpsc::Sender<TransferMessage>,
+    ) -> Result<u64> {
+        // The first stream uses the existing sessions
+        let mut sessions = Vec::new();
+        if self.tunnel == TunnelMode::Sessions {
+            for _ in 1..self.streams {
+                sessions.push((self.connect(&self.source).await?, self.connect(&self.destination).await?));
+            }
+        }
+
+        let mut senders = Vec::with_capacity(self.streams);
+        let mut copies = Vec::with_capacity(self.streams);
+        for index in 0..self.streams {
+            let (source, destination) = match index.checked_sub(1).and_then(|i| sessions.get(i)) {
+                Some((source, destination)) => (source.as_ref(), destination.as_ref()),
+                None => (source_host, destination_host),
+            };
+            let args = self.agent_args(AgentRole::Send, &self.source.path(), None, Some(index));
+            let mut sender = source.spawn(&self.remote_program, &args).await?;
+            let data = sender.stdout().take().context("Sending agent has no stdout")?;
+            let socket = destination.forward(port).await?;
+            senders.push(sender);
+            copies.push(tunnel_stream(data, socket.writer));
+        }
+
+        let tunnelled = futures::future::try_join_all(copies);
+        let (tunnelled, received) = tokio::join!(tunnelled, follow_reports(reports, progress_tx));
+
+        // The receiver's report explains a broken tunnel best
+        let received = received?;
+        let tunnelled: u64 = tunnelled?.into_iter().sum();
+        debug!("Tunnelled {} bytes", tunnelled);
+        for sender in senders {
+            reap(sender, "sending").await?;
+        }
+        Ok(received)
+    }
+
+    /// Both agents use their stdio; the controller copies between them
+    async fn run_relay(
+        &self,
+        source_host: &dyn RemoteShell,
+        destination_host: &dyn RemoteShell,
+        progress_tx: &mpsc::Sender<TransferMessage>,
+    ) -> Result<u64> {
+        let args = self.agent_args(AgentRole::Receive, &self.destination.path(), None, None);
+        let mut receiver = destination_host.spawn(&self.remote_program, &args).await?;
+        let args = self.agent_args(AgentRole::Send, &self.source.path(), None, None);
+        let mut sender = source_host.spawn(&self.remote_program, &args).await?;
+
+        let mut data = sender.stdout().take().context("Sending agent has no stdout")?;
+        let mut sink = receiver.stdin().take().context("Receiving agent has no stdin")?;
+        let mut reports = receiver.stdout().take().context("Receiving agent has no stdout")?;
+
+        let relay = async {
+            let relayed = tokio::io::copy(&mut data, &mut sink).await
+                .context("Failed to relay data between the agents")?;
+            sink.shutdown().await?;
+            debug!("Relayed {} bytes", relayed);
+            Ok::<_, anyhow::Error>(())
+        };
+        let (relayed, received) = tokio::join!(relay, follow_reports(&mut reports, progress_tx));
+        drop((data, sink, reports));
+
+        // The receiver's report explains a broken relay best
+        let received = received?;
+        relayed?;
+        reap(sender, "sending").await?;
+        reap(receiver, "receiving").await?;
+        Ok(received)
+    }
+}
+
+/// Copy one sender's part into its forwarded connection
+async fn tunnel_stream(mut data: RemoteStdout, mut socket: RemoteStdin) -> Result<u64> {
+    let copied = tokio::io::copy(&mut data, &mut socket).await
+        .context("Failed to tunnel data to the receiving agent")?;
+    socket.shutdown().await?;
+    Ok(copied)
+}
+
+/// Forward the receiving agent's progress until it reports the outcome
+pub(crate) async fn follow_reports<R>(reports: &mut R, progress_tx: &mpsc::Sender<TransferMessage>) -> Result<u64>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    let mut received = 0;
+    loop {
+        let message = protocol::read_message(reports).await?
+            .context("Receiving agent exited without reporting the outcome")?;
+        match message.message_type {
+            MessageType::Progress => {
+                // Agents report running totals
+                let (bytes, total) = protocol::progress_values(&message)?;
+                let _ = progress_tx.send(TransferMessage::Progress {
+                    bytes_transferred: bytes.saturating_sub(received),
+                    total_bytes: total,
+                }).await;
+                received = bytes;
+            }
+            MessageType::Complete => return Ok(received),
+            MessageType::Error => {
+                anyhow::bail!("Receiving agent failed: {}", String::from_utf8_lossy(&message.data));
+            }
+            other => anyhow::bail!("Unexpected {:?} record from the receiving agent", other),
+        }
+    }
+}
+
+/// Wait for an agent and fail if it did
+pub(crate) async fn reap(child: RemoteChild, role: &str) -> Result<()> {
+    let status = child.wait().await
+        .with_context(|| format!("Failed to wait for the {} agent", role))?;
+    if !status.success() {
+        anyhow::bail!("The {} agent failed: {}", role, status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use std::path::{Path, PathBuf};
+
+    use crate::error::BbcprError;
+    use crate::network::ssh::RemoteSocket;
+    use crate::transfer::agent::Agent;
+
+    /// Runs agents in-process, as if this host were reached over SSH
+    pub(crate) struct LocalShell;
+
+    #[async_trait]
+    impl Connection for LocalShell {
+        async fn connect(&mut self) -> crate::error::Result<()> {
+            Ok(())
+        }
+        async fn send(&mut self, _data: &[u8]) -> crate::error::Result<usize> {
+            Err(BbcprError::Unsupported("send".to_string()))
+        }
+        async fn receive(&mut self, _buf: &mut [u8]) -> crate::error::Result<usize> {
+            Err(BbcprError::Unsupported("receive".to_string()))
+        }
+        async fn close(&mut self) -> crate::error::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl RemoteShell for LocalShell {
+        fn host(&self) -> &str {
+            "127.0.0.1"
+        }
+
+        async fn spawn(&self, _program: &str, args: &[String]) -> crate::error::Result<RemoteChild> {
+            let agent = agent_from_args(args);
+            let (stdin, input) = tokio::io::duplex(64 * 1024);
+            let (output, stdout) = tokio::io::duplex(64 * 1024);
+            let pump = tokio::spawn(async move {
+                Ok(agent.run_with(input, output).await.map_or(1, |()| 0))
+            });
+            Ok(RemoteChild::native(Box::new(stdin), Box::new(stdout), pump))
+        }
+
+        async fn forward(&self, port: u16) -> crate::error::Result<RemoteSocket> {
+            let (reader, writer) = tokio::net::TcpStream::connect(("127.0.0.1", port)).await?.into_split();
+            Ok(RemoteSocket { reader: Box::new(reader), writer: Box::new(writer) })
+        }
+    }
+
+    /// The agent that `bbcpr` would run for `args`
+    fn agent_from_args(args: &[String]) -> Agent {
+        let role: AgentRole = args[1].parse().unwrap();
+        let (mut peer, mut streams, mut part, mut force) = (None, 1, None, false);
+        let (mut token, mut loopback, mut suffix) = (None, false, None);
+        let (mut preserve, mut mode, mut xattrs) = (false, None, false);
+        let mut paths = Vec::new();
+        let mut rest = args[2..].iter();
+        while let Some(arg) = rest.next() {
+            match arg.as_str() {
+                "--streams" => streams = rest.next().unwrap().parse().unwrap(),
+                "--agent-peer" => peer = rest.next().cloned(),
+                "--agent-part" => part = Some(agent::parse_part(rest.next().unwrap()).unwrap()),
+                "--agent-token" => token = rest.next().cloned(),
+                "--agent-loopback" => loopback = true,
+                "--partial-suffix" => suffix = rest.next().cloned(),
+                "--inplace" => suffix = None,
+                "--force" => force = true,
+                "--preserve" => preserve = true,
+                "--mode" => mode = Some(u32::from_str_radix(rest.next().unwrap(), 8).unwrap()),
+                "--xattrs" => xattrs = true,
+                path => paths.push(path.to_string()),
+            }
+        }
+        let path = if role == AgentRole::Send { &paths[0] } else { &paths[1] };
+
+        Agent::new(role, PathBuf::from(path))
+            .with_peer(peer)
+            .with_streams(streams)
+            .with_part(part)
+            .with_token(token)
+            .with_loopback(loopback)
+            .with_partial_suffix(suffix)
+            .with_force(force)
+            .with_preserve(preserve)
+            .with_mode(mode)
+            .with_xattrs(xattrs)
+    }
+
+    /// Copy a file between two local shells, returning the bytes the
+    /// receiver reported and the progress forwarded for them
+    async fn copy_between_shells(dir: &Path, transfer: impl FnOnce(Endpoint, Endpoint) -> ThirdPartyTransfer) -> (u64, u64) {
+        let source = dir.join("source.dat");
+        let data: Vec<u8> = (0..6_000_000u32).map(|i| (i % 249) as u8).collect();
+        std::fs::write(&source, &data).unwrap();
+        let destination = dir.join("copy.dat");
+        let transfer = transfer(
+            Endpoint::parse(&format!("node1:{}", source.display())).unwrap(),
+            Endpoint::parse(&format!("node2:{}", destination.display())).unwrap(),
+        );
+
+        let (progress_tx, mut progress_rx) = mpsc::channel(1024);
+        let progress = tokio::spawn(async move {
+            let mut total = 0;
+            while let Some(message) = progress_rx.recv().await {
+                if let TransferMessage::Progress { bytes_transferred, .. } = message {
+                    total += bytes_transferred;
+                }
+            }
+            total
+        });
+
+        let received = match transfer.mode {
+            RelayMode::Relay => transfer.run_relay(&LocalShell, &LocalShell, &progress_tx).await,
+            _ => transfer.run_direct(&LocalShell, &LocalShell, &progress_tx).await,
+        }
+        .unwrap();
+        drop(progress_tx);
+
+        assert_eq!(std::fs::read(&destination).unwrap(), data);
+        (received, progress.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_relay_through_controller() {
+        let dir = tempfile::tempdir().unwrap();
+        let (received, progress) = copy_between_shells(dir.path(), |source, destination| {
+            ThirdPartyTransfer::new(source, destination).unwrap().with_mode(RelayMode::Relay)
+        }).await;
+
+        assert_eq!(received, 6_000_000);
+        assert_eq!(progress, 6_000_000);
+    }
+
+    #[tokio::test]
+    async fn test_direct_between_hosts() {
+        let dir = tempfile::tempdir().unwrap();
+        let (received, progress) = copy_between_shells(dir.path(), |source, destination| {
+            ThirdPartyTransfer::new(source, destination).unwrap().with_streams(3)
+        }).await;
+
+        assert_eq!(received, 6_000_000);
+        assert_eq!(progress, 6_000_000);
+    }
+
+    #[test]
+    fn test_needs_ssh_endpoints() {
+        let remote = Endpoint::parse("alice@node1:/data/f").unwrap();
+        let local = Endpoint::parse("/data/f").unwrap();
+
+        assert!(ThirdPartyTransfer::new(remote.clone(), remote.clone()).is_ok());
+        assert!(ThirdPartyTransfer::new(remote.clone(), local.clone()).is_err());
+        assert!(ThirdPartyTransfer::new(local, remote).is_err());
+    }
+
+    #[test]
+    fn test_tunnel_agent_args() {
+        let remote = Endpoint::parse("alice@node1:/data/f").unwrap();
+        let transfer = ThirdPartyTransfer::new(remote.clone(), remote).unwrap()
+            .with_mode(RelayMode::Tunnel)
+            .with_streams(4)
+            .with_tunnel("sessions".parse().unwrap())
+            .with_preserve(true)
+            .with_file_mode(Some(0o640))
+            .with_xattrs(true);
+        let token = transfer.token.clone();
+        assert_eq!(transfer.tunnel, TunnelMode::Sessions);
+        assert_eq!("tunnel".parse::<RelayMode>().unwrap(), RelayMode::Tunnel);
+        assert!("ssh".parse::<TunnelMode>().is_err());
+
+        let listen = transfer.agent_args(AgentRole::Listen, "/data/g", None, None);
+        assert_eq!(listen, [
+            "--agent", "listen", "--streams", "4", "--agent-token", &token, "--agent-loopback",
+            "--preserve", "--mode", "640", "--partial-suffix", "bbcpr-partial", "-", "/data/g",
+        ]);
+        let part = transfer.agent_args(AgentRole::Send, "/data/f", None, Some(2));
+        assert_eq!(part, ["--agent", "send", "--agent-part", "2/4", "--agent-token", &token, "--xattrs", "/data/f", "-"]);
+        assert_eq!(token.len(), 32);
+        assert_ne!(token, ThirdPartyTransfer::new(transfer.source.clone(), transfer.destination.clone()).unwrap().token);
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_over_channels() {
+        let dir = tempfile::tempdir().unwrap();
+        let (received, progress) = copy_between_shells(dir.path(), |source, destination| {
+            ThirdPartyTransfer::new(source, destination).unwrap()
+                .with_mode(RelayMode::Tunnel)
+                .with_streams(2)
+        }).await;
+
+        assert_eq!(received, 6_000_000);
+        assert_eq!(progress, 6_000_000);
+    }
+}
@@ -0,0 +1,552 @@
+// Recursive directory transfers (-r)
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
+use tracing::{debug, info, warn};
+
+use crate::network::Connection;
+use crate::platform;
+use crate::transfer::batch::{BatchReceiver, BatchSender, BatchStats};
+use crate::transfer::engine::{TransferEngine, TransferMessage};
+use crate::transfer::walker::{FileEntry, HardLinkEntry, SpecialEntry, SymlinkEntry, TreeWalker};
+use crate::transfer::sync::{self, SyncPolicy};
+use crate::transfer::{space, TransferOptions};
+
+/// Files get one stream per this many bytes, up to the configured stream count
+const MIN_BYTES_PER_STREAM: u64 = 8 * 1024 * 1024;
+
+/// What `TreeTransfer::create_links` did
+#[derive(Default)]
+struct LinkReport {
+    symlinks: usize,
+    hard_links: usize,
+    specials: usize,
+    /// Device nodes left out for lack of privileges
+    skipped_devices: usize,
+    failures: Vec<(PathBuf, String)>,
+}
+
+impl LinkReport {
+    /// Count 1 for an entry that was created, recording the failure otherwise
+    fn record(&mut self, target: &Path, result: Result<bool>) -> usize {
+        match result {
+            Ok(created) => created as usize,
+            Err(e) => {
+                warn!("Failed to create {:?}: {:#}", target, e);
+                self.failures.push((target.to_path_buf(), format!("{:#}", e)));
+                0
+            }
+        }
+    }
+}
+
+pub struct TreeTransfer {
+    options: TransferOptions,
+    source_root: PathBuf,
+    destination_root: PathBuf,
+}
+
+impl TreeTransfer {
+    /// Copy the tree at `source` to `destination`. As with `cp -r`, an
+    /// existing destination directory receives the tree under the source's
+    /// name; otherwise the destination becomes the copy of the source.
+    pub fn new(source: PathBuf, destination: PathBuf, options: TransferOptions) -> Self {
+        let destination_root = match source.file_name() {
+            Some(name) if destination.is_dir() => destination.join(name),
+            _ => destination,
+        };
+
+        Self {
+            options,
+            source_root: source,
+            destination_root,
+        }
+    }
+
+    pub fn destination_root(&self) -> &Path {
+        &self.destination_root
+    }
+
+    pub async fn transfer<C: Connection + 'static>(
+        &self,
+        mut connection: C,
+        progress_tx: mpsc::Sender<TransferMessage>,
+    ) -> Result<()> {
+        info!("Starting recursive transfer from {:?} to {:?}", self.source_root, self.destination_root);
+
+        connection.connect().await
+            .context("Failed to establish connection")?;
+
+        match self.copy(&progress_tx).await {
+            Ok(()) => {
+                let _ = progress_tx.send(TransferMessage::Complete).await;
+                info!("Recursive transfer completed successfully");
+                Ok(())
+            }
+            Err(e) => {
+                let _ = progress_tx.send(TransferMessage::Error(format!("{:#}", e))).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Walk the source, recreate its directories on the target, then copy
+    /// every regular file, several at a time
+    pub async fn copy(&self, progress_tx: &mpsc::Sender<TransferMessage>) -> Result<()> {
+        let mut tree = TreeWalker::new(self.source_root.clone())
+            .with_filter(self.options.filter.clone())
+            .with_symlinks(self.options.symlinks)
+            .with_hard_links(self.options.hard_links)
+            .with_specials(self.options.specials)
+            .walk()
+            .await?;
+        info!(
+            "Found {} directories and {} files ({} bytes) under {:?}",
+            tree.directories.len(), tree.files.len(), tree.total_size(), self.source_root
+        );
+        let mut skipped = tree.skipped;
+
+        if let Some(policy) = self.options.sync {
+            let total = tree.files.len();
+            tree.files = self.out_of_date(policy, std::mem::take(&mut tree.files)).await?;
+            info!("{} of {} files are up to date and will be skipped", total - tree.files.len(), total);
+        }
+
+        if self.options.space_check {
+            space::check_local_space(&self.destination_root, tree.total_size())?;
+        }
+
+        // All directories exist before any file data lands
+        tokio::fs::create_dir_all(&self.destination_root).await
+            .with_context(|| format!("Failed to create {:?}", self.destination_root))?;
+        for directory in &tree.directories {
+            let target = self.destination_root.join(directory);
+            tokio::fs::create_dir_all(&target).await
+                .with_context(|| format!("Failed to create {:?}", target))?;
+        }
+
+        let mut failures = tree.errors.clone();
+        for (path, error) in &tree.errors {
+            warn!("Skipping unreadable {:?}: {}", self.source_root.join(path), error);
+        }
+
+        // Small files share batch streams; the rest get their own engine
+        let (small_files, large_files): (Vec<_>, Vec<_>) = tree.files.into_iter()
+            .partition(|file| self.options.batch_threshold > 0 && file.size <= self.options.batch_threshold);
+
+        let mut batches = JoinSet::new();
+        if !small_files.is_empty() {
+            info!(
+                "Batching {} files of up to {} bytes",
+                small_files.len(), self.options.batch_threshold
+            );
+            let batch_count = (self.options.streams.max(1) as usize).min(small_files.len());
+            let mut groups: Vec<Vec<FileEntry>> = vec![Vec::new(); batch_count];
+            for (i, file) in small_files.into_iter().enumerate() {
+                groups[i % batch_count].push(file);
+            }
+            for group in groups {
+                batches.spawn(self.copy_batch(group, progress_tx.clone()));
+            }
+        }
+
+        let semaphore = Arc::new(Semaphore::new(self.options.concurrent_files.max(1)));
+        let mut tasks = JoinSet::new();
+
+        for file in large_files {
+            let engine = self.file_engine(&file);
+            let semaphore = semaphore.clone();
+            let progress_tx = progress_tx.clone();
+
+            tasks.spawn(async move {
+                let _permit = semaphore.acquire_owned().await;
+                let result = engine.copy(&progress_tx).await;
+                (file.relative_path, result)
+            });
+        }
+
+        while let Some(result) = tasks.join_next().await {
+            let (path, result) = result.context("File transfer task panicked")?;
+            match result {
+                Ok(()) => debug!("Copied {:?}", path),
+                Err(e) => {
+                    warn!("Failed to copy {:?}: {:#}", self.source_root.join(&path), e);
+                    failures.push((path, format!("{:#}", e)));
+                }
+            }
+        }
+
+        while let Some(result) = batches.join_next().await {
+            let stats = result.context("Batch task panicked")?;
+            failures.extend(stats.failures.into_iter().map(|(path, e)| (PathBuf::from(path), e)));
+        }
+
+        // Links and special files go in once the files they refer to exist
+        let links = self.create_links(
+            std::mem::take(&mut tree.symlinks),
+            std::mem::take(&mut tree.hard_links),
+            std::mem::take(&mut tree.specials),
+        ).await?;
+        info!(
+            "Recreated {} symlinks, {} hard links and {} special files",
+            links.symlinks, links.hard_links, links.specials
+        );
+        skipped.specials += links.skipped_devices;
+        failures.extend(links.failures);
+
+        if skipped.total() > 0 {
+            warn!(
+                "Skipped {} symlinks, {} special files, {} sockets and {} symlink loops under {:?}",
+                skipped.symlinks, skipped.specials, skipped.sockets, skipped.loops, self.source_root
+            );
+        }
+
+        // Copying files changed the directories' timestamps, so their
+        // attributes go on last, children before parents
+        if self.options.preserve || self.options.xattrs || self.options.target_mode.directory.is_some() {
+            failures.extend(self.preserve_directories(&tree.directories).await?);
+        }
+
+        if !failures.is_empty() {
+            anyhow::bail!("{} entries under {:?} failed to copy", failures.len(), self.source_root);
+        }
+        Ok(())
+    }
+
+    /// Recreate symlinks, hard links and special files on the target
+    async fn create_links(
+        &self,
+        symlinks: Vec<SymlinkEntry>,
+        hard_links: Vec<HardLinkEntry>,
+        specials: Vec<SpecialEntry>,
+    ) -> Result<LinkReport> {
+        let root = self.destination_root.clone();
+        let replace = self.options.replaces_existing();
+        let keep_existing = self.options.sync == Some(SyncPolicy::Existing);
+
+        tokio::task::spawn_blocking(move || {
+            let mut report = LinkReport::default();
+
+            for link in symlinks {
+                let target = root.join(&link.relative_path);
+                let result = make_way(&target, replace, keep_existing, || {
+                    std::fs::read_link(&target).is_ok_and(|existing| existing == link.target)
+                })
+                .and_then(|go| {
+                    if go {
+                        platform::create_symlink(&link.target, &target)?;
+                    }
+                    Ok(go)
+                });
+                report.symlinks += report.record(&target, result);
+            }
+
+            for link in hard_links {
+                let (target, original) = (root.join(&link.relative_path), root.join(&link.link_to));
+                let result = make_way(&target, replace, keep_existing, || same_file(&target, &original))
+                    .and_then(|go| {
+                        if go {
+                            std::fs::hard_link(&original, &target)?;
+                        }
+                        Ok(go)
+                    });
+                report.hard_links += report.record(&target, result);
+            }
+
+            for special in specials {
+                let target = root.join(&special.relative_path);
+                if platform::is_device(special.mode) && !platform::is_privileged() {
+                    debug!("Skipping device node {:?}: not privileged", target);
+                    report.skipped_devices += 1;
+                    continue;
+                }
+                let result = make_way(&target, replace, keep_existing, || false).and_then(|go| {
+                    if go {
+                        platform::create_special(&target, special.mode, special.rdev)?;
+                    }
+                    Ok(go)
+                });
+                report.specials += report.record(&target, result);
+            }
+
+            report
+        })
+        .await
+        .context("Link creation task panicked")
+    }
+
+    /// Apply the source attributes (-p), directory mode (-m) and extended
+    /// attributes (-X) of every directory, deepest first, then the root
+    async fn preserve_directories(&self, directories: &[PathBuf]) -> Result<Vec<(PathBuf, String)>> {
+        let source_root = self.source_root.clone();
+        let destination_root = self.destination_root.clone();
+        let (preserve, xattrs) = (self.options.preserve, self.options.xattrs);
+        let mode = self.options.target_mode.directory;
+        let mut directories = directories.to_vec();
+        directories.reverse();
+        directories.push(PathBuf::new());
+
+        tokio::task::spawn_blocking(move || {
+            let mut failures = Vec::new();
+            for directory in directories {
+                let result = platform::copy_attributes(
+                    &source_root.join(&directory),
+                    &destination_root.join(&directory),
+                    preserve,
+                    mode,
+                    xattrs,
+                );
+                if let Err(e) = result {
+                    warn!("Failed to preserve attributes of {:?}: {}", destination_root.join(&directory), e);
+                    failures.push((directory, e.to_string()));
+                }
+            }
+            failures
+        })
+        .await
+        .context("Attribute task panicked")
+    }
+
+    /// The files whose destination is missing or changed under `policy`,
+    /// checking up to `concurrent_files` at a time
+    async fn out_of_date(&self, policy: SyncPolicy, files: Vec<FileEntry>) -> Result<Vec<FileEntry>> {
+        let semaphore = Arc::new(Semaphore::new(self.options.concurrent_files.max(1)));
+        let mut tasks = JoinSet::new();
+
+        for file in files {
+            let source = self.source_root.join(&file.relative_path);
+            let destination = self.destination_root.join(&file.relative_path);
+            let semaphore = semaphore.clone();
+
+            tasks.spawn(async move {
+                let _permit = semaphore.acquire_owned().await;
+                let reason = sync::skip_reason(policy, &source, &destination).await;
+                (file, destination, reason)
+            });
+        }
+
+        let mut changed = Vec::new();
+        while let Some(result) = tasks.join_next().await {
+            let (file, destination, reason) = result.context("Sync check task panicked")?;
+            match reason? {
+                Some(reason) => debug!("Skipping {:?}: {}", destination, reason),
+                None => changed.push(file),
+            }
+        }
+
+        changed.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+        Ok(changed)
+    }
+
+    /// Send a group of small files through one batch stream. Files that
+    /// fail are reported in the stats; if the stream itself breaks, every
+    /// file of the group is.
+    fn copy_batch(
+        &self,
+        files: Vec<FileEntry>,
+        progress_tx: mpsc::Sender<TransferMessage>,
+    ) -> impl std::future::Future<Output = BatchStats> + Send + 'static {
+        let (writer, reader) = tokio::io::duplex(self.options.buffer_size.max(64 * 1024));
+        let source_root = self.source_root.clone();
+        let chunk_size = self.options.buffer_size;
+        let xattrs = self.options.xattrs;
+        let sparse = self.options.sparse;
+        let zero_holes = self.options.zero_holes;
+        let receiver = BatchReceiver::new(self.destination_root.clone())
+            .with_partial_suffix(self.options.partial_suffix.clone())
+            .with_keep_partial(self.options.keep_partial)
+            .with_force(self.options.replaces_existing())
+            .with_preserve(self.options.preserve)
+            .with_mode(self.options.target_mode)
+            .with_sparse(sparse || zero_holes);
+        let paths: Vec<PathBuf> = files.iter().map(|file| file.relative_path.clone()).collect();
+
+        async move {
+            let sender = tokio::spawn(async move {
+                let mut sender = BatchSender::new(writer, source_root, chunk_size)
+                    .with_xattrs(xattrs)
+                    .with_sparse(sparse)
+                    .with_zero_holes(zero_holes);
+                for file in files {
+                    let bytes = sender.send_file(&file.relative_path).await?;
+                    let _ = progress_tx.send(TransferMessage::Progress {
+                        bytes_transferred: bytes,
+                        total_bytes: file.size,
+                    }).await;
+                }
+                sender.finish().await
+            });
+
+            let received = receiver.receive(reader).await;
+            // A sender error explains an incomplete batch better than the receiver's
+            let result = match sender.await.context("Batch sender panicked") {
+                Ok(Ok(skipped)) => received.map(|mut stats| {
+                    stats.failures.extend(skipped);
+                    stats
+                }),
+                Ok(Err(e)) | Err(e) => Err(e),
+            };
+
+            result.unwrap_or_else(|e| {
+                warn!("Batch transfer failed: {:#}", e);
+                BatchStats {
+                    failures: paths.into_iter()
+                        .map(|path| (path.to_string_lossy().into_owned(), format!("{:#}", e)))
+                        .collect(),
+                    ..BatchStats::default()
+                }
+            })
+        }
+    }
+
+    /// Engine for one file. Small files use fewer streams so that many of
+    /// them can run concurrently; large files are still split.
+    fn file_engine(&self, file: &FileEntry) -> TransferEngine {
+        let mut options = self.options.clone();
+        options.streams = (file.size / MIN_BYTES_PER_STREAM).clamp(1, self.options.streams.max(1) as u64) as u32;
+        // Space and sync policy were checked for the whole tree
+        options.space_check = false;
+        if matches!(options.sync, Some(SyncPolicy::SizeAndTime | SyncPolicy::Checksum)) {
+            options.sync = Some(SyncPolicy::Always);
+        }
+
+        TransferEngine::new(
+            self.source_root.join(&file.relative_path),
+            self.destination_root.join(&file.relative_path),
+            options,
+        )
+    }
+}
+
+/// Clear the way for a new entry at `target`. Returns false when an entry
+/// that is `unchanged` (or any entry, with `keep_existing`) should be left alone.
+fn make_way(target: &Path, replace: bool, keep_existing: bool, unchanged: impl FnOnce() -> bool) -> Result<bool> {
+    let metadata = match std::fs::symlink_metadata(target) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e.into()),
+    };
+
+    if keep_existing || unchanged() {
+        return Ok(false);
+    }
+    if metadata.is_dir() {
+        anyhow::bail!("{:?} is a directory", target);
+    }
+    if !replace {
+        anyhow::bail!("{:?} already exists; use -f/--force to replace it", target);
+    }
+
+    std::fs::remove_file(target)?;
+    Ok(true)
+}
+
+/// Whether two paths are names of the same file
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (std::fs::symlink_metadata(a), std::fs::symlink_metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> TransferOptions {
+        TransferOptions {
+            sidecar_state: true,
+            ..TransferOptions::default()
+        }
+    }
+
+    fn make_tree(root: &Path) -> PathBuf {
+        let source = root.join("src");
+        std::fs::create_dir_all(source.join("a/b")).unwrap();
+        std::fs::write(source.join("top.txt"), b"top").unwrap();
+        std::fs::write(source.join("a/b/large.dat"), vec![3u8; 200_000]).unwrap();
+        source
+    }
+
+    async fn copy(transfer: &TreeTransfer) -> Result<()> {
+        let (tx, mut rx) = mpsc::channel(100);
+        tokio::spawn(async move { while rx.recv().await.is_some() {} });
+        transfer.copy(&tx).await
+    }
+
+    #[tokio::test]
+    async fn test_existing_directory_receives_tree_under_its_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = make_tree(dir.path());
+        let destination = dir.path().join("dst");
+
+        // Copied onto a new path first, then into it as an existing directory
+        let transfer = TreeTransfer::new(source.clone(), destination.clone(), options());
+        assert_eq!(transfer.destination_root(), destination);
+        copy(&transfer).await.unwrap();
+        let transfer = TreeTransfer::new(source, destination.clone(), options());
+        assert_eq!(transfer.destination_root(), destination.join("src"));
+        copy(&transfer).await.unwrap();
+
+        for root in [destination.clone(), destination.join("src")] {
+            assert_eq!(std::fs::read(root.join("top.txt")).unwrap(), b"top");
+            assert_eq!(std::fs::read(root.join("a/b/large.dat")).unwrap(), vec![3u8; 200_000]);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_preserve_applies_directory_attributes() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::time::{Duration, SystemTime};
+
+        let dir = tempfile::tempdir().unwrap();
+        let source = make_tree(dir.path());
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_200_000_000);
+        for directory in [source.join("a/b"), source.join("a")] {
+            std::fs::set_permissions(&directory, std::fs::Permissions::from_mode(0o750)).unwrap();
+            std::fs::File::open(&directory).unwrap().set_modified(modified).unwrap();
+        }
+        let destination = dir.path().join("dst");
+
+        let transfer = TreeTransfer::new(source, destination.clone(), TransferOptions { preserve: true, ..options() });
+        copy(&transfer).await.unwrap();
+
+        for directory in [destination.join("a/b"), destination.join("a")] {
+            let metadata = std::fs::metadata(&directory).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o7777, 0o750);
+            assert_eq!(metadata.modified().unwrap(), modified);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_target_mode_applies_to_files_and_directories() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let source = make_tree(dir.path());
+        let destination = dir.path().join("dst");
+        let target_mode = "0600%0700".parse().unwrap();
+
+        let transfer = TreeTransfer::new(source, destination.clone(), TransferOptions { target_mode, ..options() });
+        copy(&transfer).await.unwrap();
+
+        let mode = |path: PathBuf| std::fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        // top.txt goes through a batch, large.dat through its own engine
+        assert_eq!(mode(destination.join("top.txt")), 0o600);
+        assert_eq!(mode(destination.join("a/b/large.dat")), 0o600);
+        assert_eq!(mode(destination.join("a/b")), 0o700);
+        assert_eq!(mode(destination.clone()), 0o700);
+    }
+}
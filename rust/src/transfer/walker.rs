@@ -0,0 +1,576 @@
+// Parallel directory tree walker for recursive transfers
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::{debug, info, warn};
+
+use crate::transfer::filter::{Filter, IgnoreStack};
+
+/// Default number of directories read concurrently
+pub const DEFAULT_WALK_CONCURRENCY: usize = 16;
+
+/// What to do with symbolic links found in the tree
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Recreate the link itself on the target
+    #[default]
+    Copy,
+    /// Copy whatever the link points to
+    Follow,
+    Skip,
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "copy" => Ok(Self::Copy),
+            "follow" => Ok(Self::Follow),
+            "skip" => Ok(Self::Skip),
+            _ => anyhow::bail!("Invalid symlink mode {:?}: use copy, follow or skip", value),
+        }
+    }
+}
+
+/// What to do with FIFOs and device nodes found in the tree
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SpecialPolicy {
+    #[default]
+    Skip,
+    /// Recreate FIFOs, and device nodes when privileged
+    Recreate,
+}
+
+impl FromStr for SpecialPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "skip" => Ok(Self::Skip),
+            "recreate" => Ok(Self::Recreate),
+            _ => anyhow::bail!("Invalid special file mode {:?}: use skip or recreate", value),
+        }
+    }
+}
+
+/// A regular file found under the source root
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    pub relative_path: PathBuf,
+    pub size: u64,
+    /// Device and inode of a file with several links, when hard links are preserved
+    pub inode: Option<(u64, u64)>,
+}
+
+/// A symbolic link to recreate as is
+#[derive(Debug, Clone)]
+pub struct SymlinkEntry {
+    pub relative_path: PathBuf,
+    pub target: PathBuf,
+}
+
+/// A further name for a file that is copied under `link_to`
+#[derive(Debug, Clone)]
+pub struct HardLinkEntry {
+    pub relative_path: PathBuf,
+    pub link_to: PathBuf,
+}
+
+/// A FIFO or device node to recreate
+#[derive(Debug, Clone)]
+pub struct SpecialEntry {
+    pub relative_path: PathBuf,
+    /// File type and permission bits, as in `st_mode`
+    pub mode: u32,
+    pub rdev: u64,
+}
+
+/// Entries left out by the symlink and special file policies
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SkipSummary {
+    pub symlinks: usize,
+    pub specials: usize,
+    pub sockets: usize,
+    /// Followed symlinks leading back into a directory above them
+    pub loops: usize,
+}
+
+impl SkipSummary {
+    pub fn total(&self) -> usize {
+        self.symlinks + self.specials + self.sockets + self.loops
+    }
+
+    fn add(&mut self, other: SkipSummary) {
+        self.symlinks += other.symlinks;
+        self.specials += other.specials;
+        self.sockets += other.sockets;
+        self.loops += other.loops;
+    }
+}
+
+/// Everything found under a source root, with paths relative to it
+#[derive(Debug, Default)]
+pub struct FileTree {
+    /// Directories in creation order (parents before children)
+    pub directories: Vec<PathBuf>,
+    pub files: Vec<FileEntry>,
+    pub symlinks: Vec<SymlinkEntry>,
+    pub hard_links: Vec<HardLinkEntry>,
+    pub specials: Vec<SpecialEntry>,
+    pub skipped: SkipSummary,
+    /// Entries that could not be read
+    pub errors: Vec<(PathBuf, String)>,
+}
+
+impl FileTree {
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|file| file.size).sum()
+    }
+}
+
+#[derive(Default)]
+struct DirListing {
+    /// Subdirectories with the ignore files that apply inside them and
+    /// their ancestors
+    directories: Vec<(PathBuf, IgnoreStack, Ancestors)>,
+    files: Vec<FileEntry>,
+    symlinks: Vec<SymlinkEntry>,
+    specials: Vec<SpecialEntry>,
+    skipped: SkipSummary,
+    errors: Vec<(PathBuf, String)>,
+}
+
+/// Settings shared by every directory read of one walk
+struct WalkSettings {
+    root: PathBuf,
+    filter: Option<Arc<Filter>>,
+    symlinks: SymlinkPolicy,
+    hard_links: bool,
+    specials: SpecialPolicy,
+}
+
+/// Device and inode of a directory and of every directory above it up to
+/// the root, to stop followed symlink loops
+type Ancestors = Vec<(u64, u64)>;
+
+pub struct TreeWalker {
+    root: PathBuf,
+    concurrency: usize,
+    filter: Option<Arc<Filter>>,
+    symlinks: SymlinkPolicy,
+    hard_links: bool,
+    specials: SpecialPolicy,
+}
+
+impl TreeWalker {
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            concurrency: DEFAULT_WALK_CONCURRENCY,
+            filter: None,
+            symlinks: SymlinkPolicy::default(),
+            hard_links: false,
+            specials: SpecialPolicy::default(),
+        }
+    }
+
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Leave out entries rejected by `filter`; excluded directories are not descended into
+    pub fn with_filter(mut self, filter: Option<Arc<Filter>>) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    pub fn with_symlinks(mut self, policy: SymlinkPolicy) -> Self {
+        self.symlinks = policy;
+        self
+    }
+
+    /// Report files sharing an inode once, with the other names as hard links
+    pub fn with_hard_links(mut self, hard_links: bool) -> Self {
+        self.hard_links = hard_links;
+        self
+    }
+
+    pub fn with_specials(mut self, policy: SpecialPolicy) -> Self {
+        self.specials = policy;
+        self
+    }
+
+    /// Walk the tree, reading up to `concurrency` directories at a time
+    pub async fn walk(&self) -> Result<FileTree> {
+        let root_metadata = tokio::fs::metadata(&self.root).await
+            .with_context(|| format!("Failed to read source directory {:?}", self.root))?;
+        if !root_metadata.is_dir() {
+            anyhow::bail!("Source {:?} is not a directory", self.root);
+        }
+
+        let settings = Arc::new(WalkSettings {
+            root: self.root.clone(),
+            filter: self.filter.clone(),
+            symlinks: self.symlinks,
+            hard_links: self.hard_links,
+            specials: self.specials,
+        });
+        let semaphore = Arc::new(Semaphore::new(self.concurrency));
+        let mut tasks = JoinSet::new();
+        let mut tree = FileTree::default();
+
+        let root_ignores = match &self.filter {
+            Some(filter) => filter.enter_directory(&self.root, &IgnoreStack::default()),
+            None => IgnoreStack::default(),
+        };
+        let root_ancestors = file_id(&root_metadata).into_iter().collect();
+        spawn_read(&mut tasks, &semaphore, &settings, PathBuf::new(), root_ignores, root_ancestors);
+
+        while let Some(result) = tasks.join_next().await {
+            let listing = result.context("Directory walker task panicked")?;
+
+            for (directory, ignores, ancestors) in listing.directories {
+                spawn_read(&mut tasks, &semaphore, &settings, directory.clone(), ignores, ancestors);
+                tree.directories.push(directory);
+            }
+            tree.files.extend(listing.files);
+            tree.symlinks.extend(listing.symlinks);
+            tree.specials.extend(listing.specials);
+            tree.skipped.add(listing.skipped);
+            tree.errors.extend(listing.errors);
+        }
+
+        // Path ordering is component-wise, so parents sort before children
+        tree.directories.sort();
+        tree.files.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+        tree.symlinks.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+        tree.specials.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+
+        if self.hard_links {
+            split_hard_links(&mut tree);
+        }
+
+        debug!(
+            "Walked {:?}: {} directories, {} files, {} bytes, {} symlinks, {} hard links, {} special files",
+            self.root, tree.directories.len(), tree.files.len(), tree.total_size(),
+            tree.symlinks.len(), tree.hard_links.len(), tree.specials.len()
+        );
+        Ok(tree)
+    }
+}
+
+fn spawn_read(
+    tasks: &mut JoinSet<DirListing>,
+    semaphore: &Arc<Semaphore>,
+    settings: &Arc<WalkSettings>,
+    relative: PathBuf,
+    ignores: IgnoreStack,
+    ancestors: Ancestors,
+) {
+    let settings = settings.clone();
+    let semaphore = semaphore.clone();
+
+    tasks.spawn(async move {
+        let _permit = semaphore.acquire_owned().await;
+        read_directory(&settings, relative, &ignores, &ancestors).await
+    });
+}
+
+/// Keep the first name of every multiply-linked file as the file to copy
+/// and turn the other names into hard links to it
+fn split_hard_links(tree: &mut FileTree) {
+    let mut first_names: HashMap<(u64, u64), PathBuf> = HashMap::new();
+    let mut files = Vec::with_capacity(tree.files.len());
+
+    for file in std::mem::take(&mut tree.files) {
+        let Some(inode) = file.inode else {
+            files.push(file);
+            continue;
+        };
+
+        match first_names.get(&inode) {
+            Some(first) => tree.hard_links.push(HardLinkEntry {
+                relative_path: file.relative_path,
+                link_to: first.clone(),
+            }),
+            None => {
+                first_names.insert(inode, file.relative_path.clone());
+                files.push(file);
+            }
+        }
+    }
+
+    tree.files = files;
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(unix)]
+fn link_count(metadata: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink()
+}
+
+#[cfg(not(unix))]
+fn link_count(_metadata: &std::fs::Metadata) -> u64 {
+    1
+}
+
+async fn read_directory(settings: &WalkSettings, relative: PathBuf, ignores: &IgnoreStack, ancestors: &Ancestors) -> DirListing {
+    let root = &settings.root;
+    let filter = settings.filter.as_deref();
+    let mut listing = DirListing::default();
+
+    let mut entries = match tokio::fs::read_dir(root.join(&relative)).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Failed to read directory {:?}: {}", root.join(&relative), e);
+            listing.errors.push((relative, e.to_string()));
+            return listing;
+        }
+    };
+
+    loop {
+        let entry = match entries.next_entry().await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => break,
+            Err(e) => {
+                listing.errors.push((relative.clone(), e.to_string()));
+                break;
+            }
+        };
+
+        let path = relative.join(entry.file_name());
+        // DirEntry metadata does not follow symlinks
+        let mut metadata = match entry.metadata().await {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                listing.errors.push((path, e.to_string()));
+                continue;
+            }
+        };
+
+        if metadata.is_symlink() {
+            match settings.symlinks {
+                SymlinkPolicy::Skip => {
+                    debug!("Skipping symlink {:?}", entry.path());
+                    listing.skipped.symlinks += 1;
+                    continue;
+                }
+                SymlinkPolicy::Copy => {}
+                SymlinkPolicy::Follow => match tokio::fs::metadata(entry.path()).await {
+                    Ok(target) => metadata = target,
+                    Err(e) => {
+                        warn!("Cannot follow symlink {:?}: {}", entry.path(), e);
+                        listing.errors.push((path, e.to_string()));
+                        continue;
+                    }
+                },
+            }
+        }
+
+        if let Some(reason) = filter.and_then(|f| f.skip_reason(&path, &entry.path(), &metadata, ignores)) {
+            info!("Skipping {:?}: {}", root.join(&path), reason);
+            continue;
+        }
+
+        let file_type = metadata.file_type();
+        if file_type.is_symlink() {
+            match tokio::fs::read_link(entry.path()).await {
+                Ok(target) => listing.symlinks.push(SymlinkEntry { relative_path: path, target }),
+                Err(e) => listing.errors.push((path, e.to_string())),
+            }
+        } else if file_type.is_dir() {
+            // Only a followed symlink can lead back into the tree. A
+            // directory reached twice by other routes is copied twice.
+            let id = file_id(&metadata);
+            if settings.symlinks == SymlinkPolicy::Follow && id.is_some_and(|id| ancestors.contains(&id)) {
+                warn!("Skipping {:?}: symlink loop back to a directory above it", entry.path());
+                listing.skipped.loops += 1;
+                continue;
+            }
+
+            let ignores = match filter {
+                Some(filter) => filter.enter_directory(&entry.path(), ignores),
+                None => ignores.clone(),
+            };
+            let mut chain = ancestors.clone();
+            chain.extend(id);
+            listing.directories.push((path, ignores, chain));
+        } else if file_type.is_file() {
+            let inode = if settings.hard_links && link_count(&metadata) > 1 {
+                file_id(&metadata)
+            } else {
+                None
+            };
+            listing.files.push(FileEntry {
+                relative_path: path,
+                size: metadata.len(),
+                inode,
+            });
+        } else {
+            classify_special(settings, &mut listing, path, &metadata, &entry.path());
+        }
+    }
+
+    listing
+}
+
+/// Record a FIFO, device node or socket according to the special file policy
+#[cfg(unix)]
+fn classify_special(
+    settings: &WalkSettings,
+    listing: &mut DirListing,
+    path: PathBuf,
+    metadata: &std::fs::Metadata,
+    full_path: &Path,
+) {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    if metadata.file_type().is_socket() {
+        debug!("Skipping socket {:?}", full_path);
+        listing.skipped.sockets += 1;
+    } else if settings.specials == SpecialPolicy::Recreate {
+        listing.specials.push(SpecialEntry {
+            relative_path: path,
+            mode: metadata.mode(),
+            rdev: metadata.rdev(),
+        });
+    } else {
+        debug!("Skipping special file {:?}", full_path);
+        listing.skipped.specials += 1;
+    }
+}
+
+#[cfg(not(unix))]
+fn classify_special(
+    _settings: &WalkSettings,
+    listing: &mut DirListing,
+    _path: PathBuf,
+    _metadata: &std::fs::Metadata,
+    full_path: &Path,
+) {
+    debug!("Skipping {:?}: not a regular file or directory", full_path);
+    listing.skipped.specials += 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_walk_orders_directories_before_children() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("a/b/c")).unwrap();
+        std::fs::create_dir_all(dir.path().join("d")).unwrap();
+        std::fs::write(dir.path().join("top.txt"), b"hello").unwrap();
+        std::fs::write(dir.path().join("a/b/c/deep.txt"), b"hello world").unwrap();
+
+        let tree = TreeWalker::new(dir.path().to_path_buf())
+            .with_concurrency(2)
+            .walk()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            tree.directories,
+            vec![PathBuf::from("a"), PathBuf::from("a/b"), PathBuf::from("a/b/c"), PathBuf::from("d")]
+        );
+        assert_eq!(tree.files.len(), 2);
+        assert_eq!(tree.total_size(), 16);
+        assert!(tree.errors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_walk_prunes_excluded_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("target/debug")).unwrap();
+        std::fs::write(dir.path().join("target/debug/app"), b"binary").unwrap();
+        std::fs::write(dir.path().join("main.rs"), b"fn main() {}").unwrap();
+
+        let filter = Filter::new().exclude("target/").unwrap();
+        let tree = TreeWalker::new(dir.path().to_path_buf())
+            .with_filter(Some(Arc::new(filter)))
+            .walk()
+            .await
+            .unwrap();
+
+        assert!(tree.directories.is_empty());
+        assert_eq!(tree.files.len(), 1);
+        assert_eq!(tree.files[0].relative_path, PathBuf::from("main.rs"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_walk_links_and_specials() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("data"), b"shared").unwrap();
+        std::fs::hard_link(dir.path().join("data"), dir.path().join("sub/alias")).unwrap();
+        std::os::unix::fs::symlink("../data", dir.path().join("sub/link")).unwrap();
+        std::os::unix::fs::symlink("..", dir.path().join("sub/up")).unwrap();
+        std::os::unix::net::UnixListener::bind(dir.path().join("socket")).unwrap();
+
+        let tree = TreeWalker::new(dir.path().to_path_buf())
+            .with_hard_links(true)
+            .walk()
+            .await
+            .unwrap();
+
+        assert_eq!(tree.files.len(), 1);
+        assert_eq!(tree.hard_links.len(), 1);
+        assert_eq!(tree.hard_links[0].link_to, PathBuf::from("data"));
+        assert_eq!(tree.symlinks.len(), 2);
+        assert_eq!(tree.symlinks[0].target, PathBuf::from("../data"));
+        assert_eq!(tree.skipped.sockets, 1);
+
+        let followed = TreeWalker::new(dir.path().to_path_buf())
+            .with_symlinks(SymlinkPolicy::Follow)
+            .walk()
+            .await
+            .unwrap();
+
+        // sub/link is copied as a file; sub/up leads back to the root
+        assert_eq!(followed.files.len(), 3);
+        assert_eq!(followed.skipped.loops, 1);
+        assert!(followed.symlinks.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_followed_links_to_one_directory_are_not_loops() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = dir.path().join("shared");
+        std::fs::create_dir(&shared).unwrap();
+        std::fs::write(shared.join("file"), b"data").unwrap();
+        for name in ["a", "b", "c"] {
+            std::fs::create_dir(dir.path().join(name)).unwrap();
+            std::os::unix::fs::symlink(&shared, dir.path().join(name).join("link")).unwrap();
+        }
+
+        let tree = TreeWalker::new(dir.path().to_path_buf())
+            .with_symlinks(SymlinkPolicy::Follow)
+            .walk()
+            .await
+            .unwrap();
+
+        assert_eq!(tree.files.len(), 4);
+        assert_eq!(tree.skipped.loops, 0);
+    }
+}
@@ -0,0 +1,136 @@
+// End-to-end tests that run the bbcpr binary
+
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn bbcpr(args: &[&str], state_dir: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_bbcpr"))
+        .args(args)
+        .env("BBCPR_STATE_DIR", state_dir)
+        .output()
+        .expect("failed to run bbcpr")
+}
+
+fn assert_success(output: &Output) {
+    assert!(
+        output.status.success(),
+        "bbcpr failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn test_copy_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source.dat");
+    let data: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(&source, &data).unwrap();
+    let destination = dir.path().join("copy.dat");
+
+    let output = bbcpr(
+        &["-s", "3", source.to_str().unwrap(), destination.to_str().unwrap()],
+        &dir.path().join("state"),
+    );
+
+    assert_success(&output);
+    assert_eq!(fs::read(&destination).unwrap(), data);
+    assert!(!dir.path().join(".copy.dat.bbcpr-partial").exists());
+}
+
+#[test]
+fn test_copy_tree() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("src");
+    fs::create_dir_all(source.join("sub/deeper")).unwrap();
+    fs::write(source.join("top.txt"), b"top").unwrap();
+    fs::write(source.join("sub/middle.txt"), b"middle").unwrap();
+    fs::write(source.join("sub/deeper/large.dat"), vec![7u8; 1_000_000]).unwrap();
+    fs::write(source.join("sub/deeper/empty"), b"").unwrap();
+    let destination = dir.path().join("dst");
+
+    let output = bbcpr(
+        &["-r", source.to_str().unwrap(), destination.to_str().unwrap()],
+        &dir.path().join("state"),
+    );
+
+    assert_success(&output);
+    assert_eq!(fs::read(destination.join("top.txt")).unwrap(), b"top");
+    assert_eq!(fs::read(destination.join("sub/middle.txt")).unwrap(), b"middle");
+    assert_eq!(fs::read(destination.join("sub/deeper/large.dat")).unwrap(), vec![7u8; 1_000_000]);
+    assert_eq!(fs::read(destination.join("sub/deeper/empty")).unwrap(), b"");
+}
+
+#[test]
+fn test_directory_needs_recursive() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("src");
+    fs::create_dir(&source).unwrap();
+
+    let output = bbcpr(
+        &[source.to_str().unwrap(), dir.path().join("dst").to_str().unwrap()],
+        &dir.path().join("state"),
+    );
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("use -r"));
+}
+
+#[test]
+fn test_copy_file_list() {
+    let dir = tempfile::tempdir().unwrap();
+    let destination = dir.path().join("dst");
+    fs::create_dir(&destination).unwrap();
+    fs::write(dir.path().join("a.txt"), b"a").unwrap();
+    fs::write(dir.path().join("b.txt"), b"b").unwrap();
+    let renamed = dir.path().join("renamed.txt");
+    let list = dir.path().join("list");
+    fs::write(
+        &list,
+        format!("{}\n{} {}\n", dir.path().join("a.txt").display(), dir.path().join("b.txt").display(), renamed.display()),
+    )
+    .unwrap();
+
+    let output = bbcpr(
+        &["-I", list.to_str().unwrap(), destination.to_str().unwrap()],
+        &dir.path().join("state"),
+    );
+
+    assert_success(&output);
+    assert_eq!(fs::read(destination.join("a.txt")).unwrap(), b"a");
+    assert_eq!(fs::read(&renamed).unwrap(), b"b");
+}
+
+#[test]
+fn test_pipe_stdin_to_stdout() {
+    let dir = tempfile::tempdir().unwrap();
+    let data: Vec<u8> = (0..2_000_000u32).map(|i| (i % 241) as u8).collect();
+    let source = dir.path().join("source.dat");
+    fs::write(&source, &data).unwrap();
+
+    // Only the data may reach stdout
+    let output = bbcpr(&["-s", "4", source.to_str().unwrap(), "-"], &dir.path().join("state"));
+    assert_success(&output);
+    assert_eq!(output.stdout, data);
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_bbcpr"))
+        .args(["-", dir.path().join("copy.dat").to_str().unwrap()])
+        .env("BBCPR_STATE_DIR", dir.path().join("state"))
+        .stdin(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    std::io::Write::write_all(&mut child.stdin.take().unwrap(), &data).unwrap();
+    assert!(child.wait().unwrap().success());
+    assert_eq!(fs::read(dir.path().join("copy.dat")).unwrap(), data);
+}
+
+#[test]
+fn test_null_source_to_null_sink() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let output = bbcpr(&["-s", "4", "null:8M", "null:"], &dir.path().join("state"));
+
+    assert_success(&output);
+    assert!(output.stdout.is_empty());
+}